@@ -1,4 +1,6 @@
-use clap::Parser;
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
 
 /// A simple program to manage a remote repository that
 /// is encrypted before being pushed to the cloud.
@@ -10,32 +12,78 @@ pub(crate) enum Args {
         
         #[arg(short, long, default_value=".")]
         /// The target directory.
-        target: String
+        target: String,
+        #[arg(long)]
+        /// The memory used to derive the password key, in MiB.
+        kdf_memory_mib: Option<u32>,
+        #[arg(long)]
+        /// The number of Argon2id passes used to derive the password key.
+        kdf_iterations: Option<u32>,
+        #[arg(long)]
+        /// The number of lanes used to derive the password key.
+        kdf_parallelism: Option<u32>,
+        #[arg(short, long)]
+        /// A keyfile that is needed alongside (or instead of) the password,
+        /// a new one is created if it does not exist.
+        keyfile: Option<PathBuf>,
+        #[arg(long)]
+        /// Repairs an interrupted operation first instead of refusing to run.
+        auto_repair: bool
     },
     /// Seals a repository, encrypting it.
     Seal {
         #[arg(short, long, default_value=".")]
         /// The target directory.
-        target: String
+        target: String,
+        #[arg(short, long)]
+        /// A keyfile that is needed alongside (or instead of) the password.
+        keyfile: Option<PathBuf>,
+        #[arg(short, long)]
+        /// An identity file to unlock the vault with instead of a password.
+        identity: Option<PathBuf>,
+        #[arg(long)]
+        /// Repairs an interrupted operation first instead of refusing to run.
+        auto_repair: bool
     },
     /// Unseals a repository, decrypting it.
     Unseal {
         #[arg(short, long, default_value=".")]
         /// The target directory.
-        target: String
+        target: String,
+        #[arg(short, long)]
+        /// A keyfile that is needed alongside (or instead of) the password.
+        keyfile: Option<PathBuf>,
+        #[arg(short, long)]
+        /// An identity file to unlock the vault with instead of a password.
+        identity: Option<PathBuf>,
+        #[arg(long)]
+        /// Repairs an interrupted operation first instead of refusing to run.
+        auto_repair: bool
     },
     /// Syncs local changes with the cloud.
     Sync {
         #[arg(short, long, default_value=".")]
         /// The target directory.
-        target: String
+        target: String,
+        #[arg(short, long)]
+        /// A keyfile that is needed alongside (or instead of) the password.
+        keyfile: Option<PathBuf>,
+        #[arg(short, long)]
+        /// An identity file to unlock the vault with instead of a password.
+        identity: Option<PathBuf>
     },
     /// Links the repository with a remote branch.
     Link {
         #[arg(short, long, default_value=".")]
         /// The target directory.
         target: String,
-        url: String
+        url: String,
+        #[arg(short, long)]
+        /// A keyfile that is needed alongside (or instead of) the password.
+        keyfile: Option<PathBuf>,
+        #[arg(short, long)]
+        /// An identity file to unlock the vault with instead of a password.
+        identity: Option<PathBuf>
     },
     /// Pulls a remote vault down to local.
     Pull {
@@ -52,7 +100,263 @@ pub(crate) enum Args {
         #[arg(short, long, default_value=".")]
         /// The target directory.
         target: String,
+        #[arg(short, long)]
+        /// A keyfile that is needed alongside (or instead of) the password.
+        keyfile: Option<PathBuf>,
+        #[arg(short, long)]
+        /// An identity file to unlock the vault with instead of a password.
+        identity: Option<PathBuf>,
+        #[arg(long)]
+        /// Repairs an interrupted operation first instead of refusing to run.
+        auto_repair: bool
+    },
+    /// Rewrites the vault binaries of a sealed vault into
+    /// the newest format.
+    Migrate {
+        #[arg(short, long, default_value=".")]
+        /// The target directory.
+        target: String,
+        #[arg(short, long)]
+        /// A keyfile that is needed alongside (or instead of) the password.
+        keyfile: Option<PathBuf>,
+        #[arg(short, long)]
+        /// An identity file to unlock the vault with instead of a password.
+        identity: Option<PathBuf>
+    },
+    /// Changes the vault password, this works whether the
+    /// vault is sealed or unsealed.
+    Passwd {
+        #[arg(short, long, default_value=".")]
+        /// The target directory.
+        target: String,
+        #[arg(short, long)]
+        /// A keyfile that is needed alongside (or instead of) the password.
+        keyfile: Option<PathBuf>,
+        #[arg(long)]
+        /// The keyfile to wrap the new password with, by default the
+        /// keyfile that unlocked the vault is kept.
+        new_keyfile: Option<PathBuf>
+    },
+    /// Replaces the master key and re-encrypts the vault with it,
+    /// use this if a device that held the unsealed vault is compromised.
+    RotateMaster {
+        #[arg(short, long, default_value=".")]
+        /// The target directory.
+        target: String,
+        #[arg(short, long)]
+        /// A keyfile that is needed alongside (or instead of) the password.
+        keyfile: Option<PathBuf>,
+        #[arg(short, long)]
+        /// An identity file to unlock the vault with instead of a password.
+        identity: Option<PathBuf>
+    },
+    /// Manages the key slots, each slot holds a separate
+    /// password that can unlock the vault.
+    Key {
+        #[arg(short, long, default_value=".")]
+        /// The target directory.
+        target: String,
+        #[command(subcommand)]
+        action: KeyAction
+    },
+    /// Manages the recovery phrase, a paper backup that can
+    /// unlock the vault if every password is forgotten.
+    Recovery {
+        #[arg(short, long, default_value=".")]
+        /// The target directory.
+        target: String,
+        #[command(subcommand)]
+        action: RecoveryAction
+    },
+    /// Manages the public keys that the vault is shared with, each one
+    /// can unlock the vault with its identity file instead of a password.
+    Recipient {
+        #[arg(short, long, default_value=".")]
+        /// The target directory.
+        target: String,
+        #[command(subcommand)]
+        action: RecipientAction
+    },
+    /// Manages the devices that are trusted to sign the vault, once any
+    /// are trusted only their vaults can be unsealed.
+    Signer {
+        #[arg(short, long, default_value=".")]
+        /// The target directory.
+        target: String,
+        #[command(subcommand)]
+        action: SignerAction
+    },
+    /// Decrypts a vault binary into a directory with nothing but the
+    /// password, for when the rest of the repository has been lost.
+    Recover {
+        /// The vault binary to decrypt.
+        vault: PathBuf,
+        /// The directory to extract into.
+        output: PathBuf,
+        #[arg(long)]
+        /// The folder holding the objects of a vault that uses the object
+        /// layout, by default the `.nov/objects` next to the vault binary.
+        objects: Option<PathBuf>,
+        #[arg(short, long)]
+        /// A keyfile that is needed alongside (or instead of) the password.
+        keyfile: Option<PathBuf>,
+        #[arg(short, long)]
+        /// An identity file to unlock the vault with instead of a password.
+        identity: Option<PathBuf>
+    },
+    /// Checks the vault binary against its parity and repairs the
+    /// damage that it can. The objects of the object layout have no
+    /// parity and are not checked.
+    Scrub {
+        #[arg(short, long, default_value=".")]
+        /// The target directory.
+        target: String,
+        #[arg(long)]
+        /// Only report the damage without repairing it.
+        dry_run: bool
+    },
+    /// Finishes or rolls back an operation that was interrupted,
+    /// asking before anything is deleted.
+    Repair {
+        #[arg(short, long, default_value=".")]
+        /// The target directory.
+        target: String,
+        #[arg(long)]
+        /// Only print what the repair would do.
+        dry_run: bool,
+        #[arg(short, long)]
+        /// A keyfile that is needed alongside (or instead of) the password.
+        keyfile: Option<PathBuf>,
+        #[arg(short, long)]
+        /// An identity file to unlock the vault with instead of a password.
+        identity: Option<PathBuf>
+    },
+    /// Finds the key derivation parameters that take about the target
+    /// time on this machine, and uses them for the vault if there is one.
+    KdfBench {
+        #[arg(short, long, default_value=".")]
+        /// The target directory.
+        target: String,
+        #[arg(long, default_value_t = 1000)]
+        /// How long unlocking should take, in milliseconds.
+        target_ms: u64,
+        #[arg(long, default_value_t = 64)]
+        /// The memory used to derive the password key, in MiB.
+        memory_mib: u32
     }
 }
 
+#[derive(Subcommand, Debug)]
+pub(crate) enum KeyAction {
+    /// Adds a new password to the vault.
+    Add {
+        /// The label of the new key slot.
+        label: String,
+        #[arg(short, long)]
+        /// A keyfile that is needed alongside (or instead of) the password.
+        keyfile: Option<PathBuf>,
+        #[arg(short, long)]
+        /// An identity file to unlock the vault with instead of a password.
+        identity: Option<PathBuf>,
+        #[arg(long)]
+        /// A keyfile that the new password will need alongside it.
+        new_keyfile: Option<PathBuf>
+    },
+    /// Removes a password from the vault.
+    Remove {
+        /// The label of the key slot to remove.
+        label: String,
+        #[arg(short, long)]
+        /// A keyfile that is needed alongside (or instead of) the password.
+        keyfile: Option<PathBuf>,
+        #[arg(short, long)]
+        /// An identity file to unlock the vault with instead of a password.
+        identity: Option<PathBuf>
+    },
+    /// Lists the labels of every key slot.
+    List
+}
+
+#[derive(Subcommand, Debug)]
+pub(crate) enum RecoveryAction {
+    /// Generates a new recovery phrase for the vault.
+    Export {
+        #[arg(short, long)]
+        /// A keyfile that is needed alongside (or instead of) the password.
+        keyfile: Option<PathBuf>,
+        #[arg(short, long)]
+        /// An identity file to unlock the vault with instead of a password.
+        identity: Option<PathBuf>
+    },
+    /// Unlocks the vault with the recovery phrase and sets a new password.
+    Unlock {
+        #[arg(short, long, default_value="default")]
+        /// The key slot that the new password is set on.
+        label: String
+    }
+}
 
+#[derive(Subcommand, Debug)]
+pub(crate) enum RecipientAction {
+    /// Shares the vault with a public key.
+    Add {
+        /// The public key, starting with 'novo1'.
+        recipient: String,
+        #[arg(short, long)]
+        /// A keyfile that is needed alongside (or instead of) the password.
+        keyfile: Option<PathBuf>,
+        #[arg(short, long)]
+        /// An identity file to unlock the vault with instead of a password.
+        identity: Option<PathBuf>
+    },
+    /// Stops sharing the vault with a public key.
+    Remove {
+        /// The public key, starting with 'novo1'.
+        recipient: String,
+        #[arg(short, long)]
+        /// A keyfile that is needed alongside (or instead of) the password.
+        keyfile: Option<PathBuf>,
+        #[arg(short, long)]
+        /// An identity file to unlock the vault with instead of a password.
+        identity: Option<PathBuf>
+    },
+    /// Lists the public keys that the vault is shared with.
+    List,
+    /// Generates a new identity file.
+    Keygen {
+        /// Where the identity file is written.
+        output: PathBuf
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub(crate) enum SignerAction {
+    /// Trusts a device to sign the vault.
+    Trust {
+        /// Who the device belongs to.
+        label: String,
+        /// The public key, starting with 'novosig1'. This device is trusted if it is left out.
+        signer: Option<String>,
+        #[arg(short, long)]
+        /// A keyfile that is needed alongside (or instead of) the password.
+        keyfile: Option<PathBuf>,
+        #[arg(short, long)]
+        /// An identity file to unlock the vault with instead of a password.
+        identity: Option<PathBuf>
+    },
+    /// Stops trusting a device.
+    Untrust {
+        /// The label of the device.
+        label: String,
+        #[arg(short, long)]
+        /// A keyfile that is needed alongside (or instead of) the password.
+        keyfile: Option<PathBuf>,
+        #[arg(short, long)]
+        /// An identity file to unlock the vault with instead of a password.
+        identity: Option<PathBuf>
+    },
+    /// Lists the trusted devices and who signed the sealed vault.
+    List,
+    /// Prints the public key of this device.
+    Show
+}
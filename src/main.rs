@@ -1,12 +1,15 @@
 
-use std::{process::exit, time::Duration};
 
 use clap::Parser;
 
 use crate::{
-    cli::Args, printing::SteppedComputationHandle, sys::{
-        common::{link, open, pull, seal_full, sync, unseal},
-        init::run_init, lib::sync::load_tigris_params,
+    cli::{Args, KeyAction, RecipientAction, RecoveryAction, SignerAction}, sys::{
+        common::{
+            kdf_bench, key_add, key_list, key_remove, link, migrate, open, passwd, pull, recipient_add, recipient_keygen,
+            recipient_list, recipient_remove, recover, recovery_export, recovery_unlock, repair, rotate_master, scrub, seal_full, signer_list, signer_show,
+            signer_trust, signer_untrust, sync, unseal
+        },
+        init::run_init, mk::KdfParams,
     }
 };
 use anyhow::Result;
@@ -19,13 +22,49 @@ mod sys;
 fn run_subcommand() -> Result<()> {
     let args = Args::parse();
     match args {
-        Args::Init { target } => run_init(target),
-        Args::Seal { target } => seal_full(target),
-        Args::Unseal { target } => unseal(target),
-        Args::Sync { target } => sync(target),
-        Args::Link { target, url } => link(target, &url),
+        Args::Init { target, kdf_memory_mib, kdf_iterations, kdf_parallelism, keyfile, auto_repair } => {
+            let defaults = KdfParams::default();
+            let params = KdfParams::new(
+                kdf_memory_mib.map_or(defaults.memory_kib, |m| m.saturating_mul(1024)),
+                kdf_iterations.unwrap_or(defaults.iterations),
+                kdf_parallelism.unwrap_or(defaults.parallelism)
+            )?;
+            run_init(target, params, keyfile.as_deref(), auto_repair)
+        }
+        Args::Seal { target, keyfile, identity, auto_repair } => seal_full(target, keyfile.as_deref(), identity.as_deref(), auto_repair),
+        Args::Unseal { target, keyfile, identity, auto_repair } => unseal(target, keyfile.as_deref(), identity.as_deref(), auto_repair),
+        Args::Sync { target, keyfile, identity } => sync(target, keyfile.as_deref(), identity.as_deref()),
+        Args::Link { target, url, keyfile, identity } => link(target, &url, keyfile.as_deref(), identity.as_deref()),
         Args::Pull { target, url } => pull(target, &url),
-        Args::Open { target } => open(target)
+        Args::Open { target, keyfile, identity, auto_repair } => open(target, keyfile.as_deref(), identity.as_deref(), auto_repair),
+        Args::Migrate { target, keyfile, identity } => migrate(target, keyfile.as_deref(), identity.as_deref()),
+        Args::Passwd { target, keyfile, new_keyfile } => passwd(target, keyfile.as_deref(), new_keyfile.as_deref()),
+        Args::RotateMaster { target, keyfile, identity } => rotate_master(target, keyfile.as_deref(), identity.as_deref()),
+        Args::Key { target, action } => match action {
+            KeyAction::Add { label, keyfile, identity, new_keyfile } => key_add(target, &label, keyfile.as_deref(), new_keyfile.as_deref(), identity.as_deref()),
+            KeyAction::Remove { label, keyfile, identity } => key_remove(target, &label, keyfile.as_deref(), identity.as_deref()),
+            KeyAction::List => key_list(target)
+        },
+        Args::Recovery { target, action } => match action {
+            RecoveryAction::Export { keyfile, identity } => recovery_export(target, keyfile.as_deref(), identity.as_deref()),
+            RecoveryAction::Unlock { label } => recovery_unlock(target, &label)
+        },
+        Args::Recipient { target, action } => match action {
+            RecipientAction::Add { recipient, keyfile, identity } => recipient_add(target, &recipient, keyfile.as_deref(), identity.as_deref()),
+            RecipientAction::Remove { recipient, keyfile, identity } => recipient_remove(target, &recipient, keyfile.as_deref(), identity.as_deref()),
+            RecipientAction::List => recipient_list(target),
+            RecipientAction::Keygen { output } => recipient_keygen(&output)
+        },
+        Args::Signer { target, action } => match action {
+            SignerAction::Trust { label, signer, keyfile, identity } => signer_trust(target, &label, signer.as_deref(), keyfile.as_deref(), identity.as_deref()),
+            SignerAction::Untrust { label, keyfile, identity } => signer_untrust(target, &label, keyfile.as_deref(), identity.as_deref()),
+            SignerAction::List => signer_list(target),
+            SignerAction::Show => signer_show(target)
+        },
+        Args::Recover { vault, output, objects, keyfile, identity } => recover(&vault, &output, objects.as_deref(), keyfile.as_deref(), identity.as_deref()),
+        Args::Repair { target, dry_run, keyfile, identity } => repair(target, dry_run, keyfile.as_deref(), identity.as_deref()),
+        Args::Scrub { target, dry_run } => scrub(target, dry_run),
+        Args::KdfBench { target, target_ms, memory_mib } => kdf_bench(target, target_ms, memory_mib)
     }
 }
 
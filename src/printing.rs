@@ -1,6 +1,5 @@
-use std::{collections::VecDeque, io::{Write, stdout}, sync::{Arc, LazyLock, Mutex}};
+use std::{io::stdout, sync::{Arc, LazyLock, Mutex}};
 
-use anyhow::Result;
 use colorize::AnsiColor;
 use crossterm::{ExecutableCommand, cursor::{MoveDown, MoveUp}, terminal::{Clear, ClearType}};
 
@@ -37,7 +36,7 @@ struct CompLine {
     size: usize,
 }
 
-static CURRENT_COMP: LazyLock<Mutex<Option<Arc<Mutex<SteppedComputation>>>>> = LazyLock::new(|| Mutex::default());
+static CURRENT_COMP: LazyLock<Mutex<Option<Arc<Mutex<SteppedComputation>>>>> = LazyLock::new(Mutex::default);
 
 struct SteppedComputation {
     comp_name: String,
@@ -138,7 +137,7 @@ impl SteppedComputationHandle {
     {
         match self {
             Self::Active(real) => real.lock().unwrap().start_next(name, done, functor),
-            Self::Shell { previous, anchor, depth, size, index, finished } => {
+            Self::Shell { previous, anchor: _, depth, size, index, finished: _ } => {
                 let mut lock = previous.lock().unwrap();
                 *index += 1;
                 lock.add_feed_with_depth(name.to_string(), *index, *depth, *size);
@@ -160,7 +159,7 @@ impl SteppedComputationHandle {
                 *outer = None;
 
             },
-            Self::Shell { previous, anchor, depth, size, index, finished } => {
+            Self::Shell { previous, anchor, depth: _, size: _, index: _, finished } => {
                 if *finished {
                     return;
                 }
@@ -223,7 +222,7 @@ impl SteppedComputation {
             text: feed,
             in_progress: true,
             depth,
-            size: size
+            size
         });
         self.render_printout();
     }
@@ -276,7 +275,7 @@ impl SteppedComputation {
                         weight_chain.push(1.0 / lin.size as f32);
 
                         
-                        let result = vec![ lin.index as f32 ].iter().chain(weight_chain.iter()).fold(1., |a,b| a * b );
+                        let result = [lin.index as f32].iter().chain(weight_chain.iter()).fold(1., |a,b| a * b );
                         // println!("depth: {i}, Weights: {:?}, Indx: {}, size: {}, result: {result}", weight_chain, lin.index, lin.size);
                         
                         major += result * 100.0;
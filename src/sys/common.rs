@@ -5,19 +5,29 @@ use crossterm::{
 };
 use std::{
     env,
-    io::{Write, stdout},
+    io::{Seek, SeekFrom, Write, stdout},
     path::Path,
+    time::{Duration, Instant},
 };
 
 use crate::{
     console_log, printing::SteppedComputationHandle, sys::{
-        lib::{path::{Normal, RootPath}, sync::{init_remote, load_tigris_params, pull_remote, push_remote}},
-        mk::{CachedPassword, WrappedKey},
+        lib::{path::{Normal, RootPath}, sync::{init_remote, pull_remote, push_remote}},
+        mk::{CachedPassword, KeySlots, MasterVaultKey, UserVaultKey, WrappedKey, calibrate_kdf, check_slot_label},
+        recipient::{Identity, Recipient},
+        signature::{DeviceKey, Signer, TrustedSigners, VaultSignature, sign_vault},
+        recovery::{RECOVERY_SLOT, RecoveryPhrase},
         procedure::{
-            actions::{Context, VaultState},
-            sequence::{Playable, SEAL_FULL, UNSEAL_FULL},
+            actions::{Context, RepairMode, VaultState, expand_decrypted_bin},
+            sequence::{Playable, ROTATE_MASTER, SEAL_FULL, UNSEAL_FULL},
         },
-        statefile::StateFileHandle,
+        statefile::{StateFileHandle, decode_key_block},
+        objects::{Manifest, ObjectStore},
+        parity::{check_parity, refresh_parity, repair_parity},
+        shards::{exists_sharded, open_sharded, with_joined},
+        generation::warn_if_rolled_back,
+        lock::VaultLock,
+        writer::{VaultReader, read_embedded_keys, read_header, reencrypt, replace_embedded_keys},
     }
 };
 
@@ -38,7 +48,7 @@ pub fn make_git_repo(root: impl AsRef<Path>) -> Result<()> {
     Ok(())
 }
 
-pub fn seal_full(root: impl AsRef<Path>) -> Result<()> {
+pub fn seal_full(root: impl AsRef<Path>, keyfile: Option<&Path>, identity: Option<&Path>, auto_repair: bool) -> Result<()> {
     let path = root.as_ref();
 
     if let Ok(mut sfh) = StateFileHandle::new(path)
@@ -48,26 +58,46 @@ pub fn seal_full(root: impl AsRef<Path>) -> Result<()> {
         return Ok(());
     }
 
-    let mut usr_input = get_password_with_prompt(false)?;
+    let identity = identity.map(Identity::from_file).transpose()?;
+    let mut usr_input = match identity {
+        Some(_) => CachedPassword::from_string(String::new()),
+        None => with_keyfile(get_password_with_prompt(false)?, keyfile)?
+    };
 
     let root = RootPath::new(path);
     let mut ctx = Context::new(&root, &mut usr_input)?;
+    if let Some(identity) = identity {
+        ctx.set_identity(identity);
+    }
+    if auto_repair {
+        ctx.set_repair_mode(RepairMode::Automatic);
+    }
     SEAL_FULL.play(&root, &mut ctx)?;
 
     Ok(())
 }
 
-pub fn unseal(root: impl AsRef<Path>) -> Result<()> {
+pub fn unseal(root: impl AsRef<Path>, keyfile: Option<&Path>, identity: Option<&Path>, auto_repair: bool) -> Result<()> {
     if let Ok(mut sfh) = StateFileHandle::new(root.as_ref()) && let Ok(VaultState::Unsealed) = sfh.get_state() {
             console_log!(Info, "The vault is already unsealed.");
             return Ok(());
         
     }
 
-    let mut password = prompt_password(false)?;
+    let identity = identity.map(Identity::from_file).transpose()?;
+    let mut password = match identity {
+        Some(_) => CachedPassword::from_string(String::new()),
+        None => with_keyfile(prompt_password(false)?, keyfile)?
+    };
 
     let root = RootPath::new(root.as_ref());
     let mut ctx = Context::new(&root, &mut password)?;
+    if let Some(identity) = identity {
+        ctx.set_identity(identity);
+    }
+    if auto_repair {
+        ctx.set_repair_mode(RepairMode::Automatic);
+    }
 
     
     UNSEAL_FULL.play(&root, &mut ctx)?;
@@ -80,16 +110,19 @@ pub fn unseal(root: impl AsRef<Path>) -> Result<()> {
 
 /// Performs a sync, which basically detects which mode
 /// we are currently in.
-pub fn sync(root: impl AsRef<Path>) -> Result<()> {
+pub fn sync(root: impl AsRef<Path>, keyfile: Option<&Path>, identity: Option<&Path>) -> Result<()> {
 
 
     require_seal(
         &RootPath::new(root.as_ref()),
+        keyfile,
+        identity,
         |sf| match sf.get_remote() {
             Some(_) => Ok(()),
             None => Err(anyhow!("The remote URL is not set. Please run link first.")),
         },
         || {
+            warn_if_rolled_back(&RootPath::new(root.as_ref()));
             push_remote(root.as_ref())?;
             Ok(())
         },
@@ -125,12 +158,21 @@ fn unseal_verbose(root: impl AsRef<Path>, context: &mut Context<'_>) -> Result<(
 }
 
 /// This is a way
-pub fn open(root: impl AsRef<Path>) -> Result<()> {
-    let wrapped = StateFileHandle::new(root.as_ref())?.get_wrapped_key()?;
-    let mut password = fetch_password(&wrapped)?;
+pub fn open(root: impl AsRef<Path>, keyfile: Option<&Path>, identity: Option<&Path>, auto_repair: bool) -> Result<()> {
+    let identity = identity.map(Identity::from_file).transpose()?;
+    let mut password = match identity {
+        Some(_) => CachedPassword::from_string(String::new()),
+        None => fetch_password(&StateFileHandle::new(root.as_ref())?.get_key_slots()?, keyfile)?
+    };
 
     
     let mut context = Context::new(&RootPath::new(root.as_ref()), &mut password)?;
+    if let Some(identity) = identity {
+        context.set_identity(identity);
+    }
+    if auto_repair {
+        context.set_repair_mode(RepairMode::Automatic);
+    }
 
     // println!("A");
     unseal_verbose(root.as_ref(), &mut context)?;
@@ -171,6 +213,7 @@ fn open_internal(root: &RootPath<Normal>, password: &mut Context) -> Result<()>
                 if key.code == KeyCode::Char('s') {
                     console_log!(Info, "Synchronizing repository with remote...");
 
+                    let identity = password.identity().cloned();
                     require_seal_with_retrieval(
                         root,
                         |sf| match sf.get_remote() {
@@ -180,6 +223,7 @@ fn open_internal(root: &RootPath<Normal>, password: &mut Context) -> Result<()>
                             }
                         },
                         |_| Ok(password.password().clone()),
+                        identity,
                         || {
                             push_remote(root.path())?;
                             Ok(())
@@ -202,12 +246,15 @@ fn open_internal(root: &RootPath<Normal>, password: &mut Context) -> Result<()>
 
 /// This is a guard that makes sure things happen in the correct order
 /// and is used for a few operations that require the seal-unseal pattern.
-fn require_seal<PF, F>(root: &RootPath<Normal>, pf_functor: PF, functor: F) -> Result<()>
+fn require_seal<PF, F>(root: &RootPath<Normal>, keyfile: Option<&Path>, identity: Option<&Path>, pf_functor: PF, functor: F) -> Result<()>
 where
     PF: FnMut(&StateFileHandle) -> Result<()>,
     F: FnMut() -> Result<()>,
 {
-    require_seal_with_retrieval(root, pf_functor, fetch_password, functor)
+    match identity.map(Identity::from_file).transpose()? {
+        Some(identity) => require_seal_with_retrieval(root, pf_functor, |_| Ok(CachedPassword::from_string(String::new())), Some(identity), functor),
+        None => require_seal_with_retrieval(root, pf_functor, |slots| fetch_password(slots, keyfile), None, functor),
+    }
 }
 
 /// This is a guard that makes sure things happen in the correct order
@@ -216,27 +263,31 @@ fn require_seal_with_retrieval<PF, KR, F>(
     root: &RootPath<Normal>,
     mut pf_functor: PF,
     mut kr_functor: KR,
+    identity: Option<Identity>,
     mut functor: F,
 ) -> Result<()>
 where
     PF: FnMut(&StateFileHandle) -> Result<()>,
-    KR: FnMut(&WrappedKey) -> Result<CachedPassword>,
+    KR: FnMut(&KeySlots) -> Result<CachedPassword>,
     F: FnMut() -> Result<()>,
 {
     // let wrapped = state_file.get_mk()?;
 
     // println!("hello");
     let state_file_handle = StateFileHandle::new(root.path())?;
-    let wrapped = state_file_handle.get_wrapped_key()?;
+    let slots = state_file_handle.get_key_slots()?;
 
-    let mut password = kr_functor(&wrapped)?;
+    let mut password = kr_functor(&slots)?;
     drop(state_file_handle);
 
     // println!("hello 2");
 
     let mut context = Context::new(root, &mut password)?;
+    if let Some(identity) = identity {
+        context.set_identity(identity);
+    }
 
-    pf_functor(&context.state_file())?;
+    pf_functor(context.state_file())?;
     context.state_file_mut().reload()?;
 
     // println!("hello 3");
@@ -270,13 +321,15 @@ where
 }
 
 
-pub fn link(root: impl AsRef<Path>, url: &str) -> Result<()> {
+pub fn link(root: impl AsRef<Path>, url: &str, keyfile: Option<&Path>, identity: Option<&Path>) -> Result<()> {
     let path = root.as_ref();
 
     // TODO: Check to see if the repository is well-formed.
 
     require_seal(
         &RootPath::new(path),
+        keyfile,
+        identity,
         |_| Ok(()),
         || {
            init_remote(path, url)?;
@@ -303,12 +356,723 @@ pub fn pull(root: impl AsRef<Path>, url: &str) -> Result<()> {
     //     ));
     // }
 
-    pull_remote(root.as_ref(), &url)?;
+    pull_remote(root.as_ref(), url)?;
     // git_clone(root.as_ref(), &url)?;
 
     Ok(())
 }
 
+/// Changes the vault password by wrapping the existing master
+/// key under a new password, the vault binaries are not touched.
+/// Unless another one is given, the keyfile is kept as a factor.
+pub fn passwd(root: impl AsRef<Path>, keyfile: Option<&Path>, new_keyfile: Option<&Path>) -> Result<()> {
+    let root = RootPath::new(root.as_ref());
+
+    if !root.metadata_folder().exists() {
+        return Err(anyhow!("There is no repository in that directory."));
+    }
+
+    let (_lock, mut handle) = open_at_rest(&root, "changing the password")?;
+
+    let mut slots = handle.get_key_slots()?;
+    let mut old = with_keyfile(prompt_password(false)?, keyfile)?;
+
+    // Check the old password before asking for a new one.
+    slots.unlock(&mut old)?;
+
+    let mut new = with_keyfile(prompt_new_password()?, new_keyfile.or(keyfile))?;
+    let label = slots.change_password(&mut old, &mut new, handle.get_kdf_params()?)?.to_string();
+    handle.set_key_slots(&slots);
+    handle.writeback()?;
+    refresh_embedded_keys(&root, &mut handle)?;
+
+    console_log!(Info, "Succesfully changed the password of key slot '{label}'.");
+    Ok(())
+}
+
+/// Keeps the key slots embedded in the vault binaries in step with the
+/// state file, these only exist while the vault is sealed.
+fn refresh_embedded_keys(root: &RootPath<Normal>, handle: &mut StateFileHandle) -> Result<()> {
+    if handle.get_state()? != VaultState::Sealed {
+        return Ok(());
+    }
+    let key_block = handle.key_block()?;
+    with_joined(root, || {
+        for binary in [root.vault_binary(), root.secure_local_zip()] {
+            if binary.exists() {
+                replace_embedded_keys(&binary, &key_block)?;
+            }
+        }
+        refresh_parity(&root.vault_binary(), &root.vault_parity())
+    })
+}
+
+/// Decrypts a vault binary into a directory using only the key slots
+/// embedded in it, for when the `.nov` folder has been lost.
+pub fn recover(vault: &Path, output: &Path, objects: Option<&Path>, keyfile: Option<&Path>, identity: Option<&Path>) -> Result<()> {
+    // Unless told otherwise, the objects are looked for where a seal leaves them.
+    let objects = match objects {
+        Some(objects) => objects.to_path_buf(),
+        None => RootPath::new(vault.parent().unwrap_or(Path::new("."))).objects_folder(),
+    };
+
+    // A split vault is read through a joined copy, its shards are left as they are.
+    let joined = match vault.exists() {
+        true => None,
+        false => {
+            let mut joined = tempfile::NamedTempFile::new()?;
+            std::io::copy(&mut open_sharded(vault)?, joined.as_file_mut())?;
+            Some(joined)
+        }
+    };
+    let vault = joined.as_ref().map_or(vault, |joined| joined.path());
+
+    let key_block = read_embedded_keys(vault)?.ok_or_else(|| {
+        anyhow!("This vault binary was written before key slots were embedded, it can only be opened with its .nov folder.")
+    })?;
+    let (slots, recipients) = decode_key_block(&key_block)?;
+
+    if output.exists() && std::fs::read_dir(output)?.next().is_some() {
+        return Err(anyhow!("The output directory is not empty."));
+    }
+
+    let master = match identity {
+        Some(identity) => recipients.unlock(&Identity::from_file(identity)?)?,
+        None => slots.unlock(&mut with_keyfile(prompt_password(false)?, keyfile)?)?.1,
+    };
+
+    let mut stepped = SteppedComputationHandle::start("Recovering", 2);
+    let mut reader = stepped.start_next("Verifying the vault", "Verified the vault", || -> Result<_> {
+        let mut reader = VaultReader::open(vault, master.key_bytes())?;
+        reader.verify()?;
+        reader.seek(SeekFrom::Start(0))?;
+        Ok(reader)
+    })?;
+    stepped.start_next("Extracting the vault", "Extracted the vault", || {
+        std::fs::create_dir_all(output)?;
+        match Manifest::read(&mut reader)? {
+            Some(manifest) => {
+                if !objects.is_dir() {
+                    return Err(anyhow!("This vault keeps its files as objects, but there is no folder of objects at {objects:?}. Pass it with --objects."));
+                }
+                let store = ObjectStore::new(objects, &master)?;
+                store.verify(&manifest)?;
+                store.expand(&manifest, output)
+            }
+            None => expand_decrypted_bin(output, &mut reader),
+        }
+    })?;
+    stepped.finish();
+
+    console_log!(Info, "Succesfully recovered the vault into {output:?}.");
+    Ok(())
+}
+
+/// Checks the vault binary against its parity and repairs
+/// whatever damage the parity can cover.
+pub fn scrub(root: impl AsRef<Path>, dry_run: bool) -> Result<()> {
+    let root = RootPath::new(root.as_ref());
+    if !root.metadata_folder().exists() {
+        return Err(anyhow!("There is no repository in that directory."));
+    }
+    // A seal or unseal would replace the binary while it is being repaired.
+    let _lock = VaultLock::acquire(&root)?;
+    if !exists_sharded(&root.vault_binary()) {
+        return Err(anyhow!("There is no vault binary to scrub, the vault is unsealed or there is no repository in that directory."));
+    }
+    if !exists_sharded(&root.vault_parity()) {
+        return Err(anyhow!("The vault binary has no parity, set parity in the novault.toml and seal the vault to write it."));
+    }
+    with_joined(&root, || scrub_joined(&root, dry_run))
+}
+
+fn scrub_joined(root: &RootPath<Normal>, dry_run: bool) -> Result<()> {
+    if root.objects_folder().exists() {
+        console_log!(Warn, "The objects in {:?} have no parity, so they are not checked. Only the manifest in the vault binary is.", root.objects_folder());
+    }
+
+    let report = check_parity(&root.vault_binary(), &root.vault_parity())?;
+    if report.is_clean() {
+        console_log!(Info, "All {} blocks of the vault binary are intact.", report.blocks);
+        return Ok(());
+    }
+
+    console_log!(
+        Warn,
+        "{} of the {} blocks of the vault binary are damaged, as are {} of its parity blocks.",
+        report.damaged.len(),
+        report.blocks,
+        report.damaged_parity
+    );
+    if report.resized {
+        console_log!(Warn, "The vault binary is not the size it was sealed at.");
+    }
+    if !report.is_recoverable() {
+        return Err(anyhow!("The damage is spread over {} places that the parity can not cover, the vault can not be repaired.", report.unrecoverable));
+    }
+    if dry_run {
+        console_log!(Info, "All of the damage can be repaired, scrub again without --dry-run to repair it.");
+        return Ok(());
+    }
+
+    let repaired = repair_parity(&root.vault_binary(), &root.vault_parity(), &report)?;
+    console_log!(Info, "Succesfully repaired {repaired} blocks of the vault binary.");
+    Ok(())
+}
+
+/// Unlocks the master key with the identity file if one is given, otherwise
+/// with a password and the keyfile. The password is given back so that it
+/// can be used again, it is left empty when the identity unlocked the vault.
+fn unlock_master(handle: &StateFileHandle, keyfile: Option<&Path>, identity: Option<&Path>) -> Result<(CachedPassword, MasterVaultKey)> {
+    match identity {
+        Some(identity) => {
+            let master = handle.get_recipients()?.unlock(&Identity::from_file(identity)?)?;
+            Ok((CachedPassword::from_string(String::new()), master))
+        }
+        None => {
+            let mut password = with_keyfile(prompt_password(false)?, keyfile)?;
+            let (_, master) = handle.get_key_slots()?.unlock(&mut password)?;
+            Ok((password, master))
+        }
+    }
+}
+
+/// Opens the state file of a vault that is either sealed or unsealed, these
+/// are the only states in which the keys may be changed. The lock is taken
+/// before the state is read and has to be held until it is written back.
+fn open_at_rest(root: &RootPath<Normal>, action: &str) -> Result<(VaultLock, StateFileHandle)> {
+    if !root.metadata_folder().exists() {
+        return Err(anyhow!("There is no repository in that directory."));
+    }
+
+    let lock = VaultLock::acquire(root)?;
+    let mut handle = StateFileHandle::new(root.path())?;
+    let state = handle.get_state()?;
+    if !matches!(state, VaultState::Sealed | VaultState::Unsealed) {
+        return Err(anyhow!("The vault was left in an incomplete state ({state:?}), please run 'novovault repair' before {action}."));
+    }
+    Ok((lock, handle))
+}
+
+/// Adds a new key slot, an existing password has to be
+/// provided to unlock the master key.
+pub fn key_add(root: impl AsRef<Path>, label: &str, keyfile: Option<&Path>, new_keyfile: Option<&Path>, identity: Option<&Path>) -> Result<()> {
+    let root = RootPath::new(root.as_ref());
+    let (_lock, mut handle) = open_at_rest(&root, "adding a key")?;
+
+    let mut slots = handle.get_key_slots()?;
+    let (_, master) = unlock_master(&handle, keyfile, identity)?;
+
+    let mut new = with_keyfile(prompt_new_password()?, new_keyfile)?;
+    slots.add(label, WrappedKey::init(&UserVaultKey::init_fresh(&mut new, handle.get_kdf_params()?)?, &master)?)?;
+    handle.set_key_slots(&slots);
+    handle.writeback()?;
+    refresh_embedded_keys(&root, &mut handle)?;
+
+    console_log!(Info, "Succesfully added key slot '{label}'.");
+    Ok(())
+}
+
+/// Removes a key slot, any password or identity that opens
+/// the vault may be used to do so.
+pub fn key_remove(root: impl AsRef<Path>, label: &str, keyfile: Option<&Path>, identity: Option<&Path>) -> Result<()> {
+    let root = RootPath::new(root.as_ref());
+    let (_lock, mut handle) = open_at_rest(&root, "removing a key")?;
+
+    let mut slots = handle.get_key_slots()?;
+    let (password, _) = unlock_master(&handle, keyfile, identity)?;
+
+    slots.remove(label)?;
+    handle.set_key_slots(&slots);
+    handle.writeback()?;
+    refresh_embedded_keys(&root, &mut handle)?;
+    drop(handle);
+
+    console_log!(Info, "Succesfully removed key slot '{label}'.");
+    console_log!(
+        Warn,
+        "The master key is unchanged, so anyone holding an older copy of the vault and the removed password can still decrypt it."
+    );
+
+    if prompt_confirm("Rotate the master key now?")? {
+        rotate_master_with(&root, password, keyfile, identity)?;
+    }
+    Ok(())
+}
+
+/// Wraps the master key to a public key, the holder of the matching
+/// identity can then unlock the vault without any password.
+pub fn recipient_add(root: impl AsRef<Path>, recipient: &str, keyfile: Option<&Path>, identity: Option<&Path>) -> Result<()> {
+    let root = RootPath::new(root.as_ref());
+    let recipient: Recipient = recipient.parse()?;
+    let (_lock, mut handle) = open_at_rest(&root, "adding a recipient")?;
+
+    let (_, master) = unlock_master(&handle, keyfile, identity)?;
+
+    let mut recipients = handle.get_recipients()?;
+    recipients.add(recipient, &master)?;
+    handle.set_recipients(&recipients);
+    handle.writeback()?;
+    refresh_embedded_keys(&root, &mut handle)?;
+
+    console_log!(Info, "The vault is now shared with {recipient}.");
+    Ok(())
+}
+
+/// Removes a recipient, like removing a key slot this needs the vault
+/// to be unlocked and does not change the master key unless it is
+/// rotated afterwards.
+pub fn recipient_remove(root: impl AsRef<Path>, recipient: &str, keyfile: Option<&Path>, identity: Option<&Path>) -> Result<()> {
+    let root = RootPath::new(root.as_ref());
+    let recipient: Recipient = recipient.parse()?;
+    let (_lock, mut handle) = open_at_rest(&root, "removing a recipient")?;
+    let (password, _) = unlock_master(&handle, keyfile, identity)?;
+
+    let mut recipients = handle.get_recipients()?;
+    recipients.remove(&recipient)?;
+    handle.set_recipients(&recipients);
+    handle.writeback()?;
+    refresh_embedded_keys(&root, &mut handle)?;
+    drop(handle);
+
+    console_log!(Info, "The vault is no longer shared with {recipient}.");
+    console_log!(
+        Warn,
+        "The master key is unchanged, so anyone holding an older copy of the vault and the removed identity can still decrypt it."
+    );
+
+    if prompt_confirm("Rotate the master key now?")? {
+        rotate_master_with(&root, password, keyfile, identity)?;
+    }
+    Ok(())
+}
+
+/// Lists the public keys that the vault is shared with.
+pub fn recipient_list(root: impl AsRef<Path>) -> Result<()> {
+    let root = RootPath::new(root.as_ref());
+    let (_lock, handle) = open_at_rest(&root, "listing the recipients")?;
+
+    let recipients = handle.get_recipients()?;
+    if recipients.is_empty() {
+        console_log!(Info, "The vault is not shared with any recipients.");
+    }
+    for (recipient, _) in recipients.iter() {
+        console_log!(Info, "{recipient}");
+    }
+    Ok(())
+}
+
+/// Generates a new identity file and prints its public key.
+pub fn recipient_keygen(output: &Path) -> Result<()> {
+    if output.exists() {
+        return Err(anyhow!("Refusing to overwrite the existing file at {output:?}."));
+    }
+    let identity = Identity::generate();
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(output)?.write_all(identity.to_file_contents()?.as_bytes())?;
+
+    console_log!(Info, "Wrote a new identity to {output:?}, its public key is:");
+    println!("{}", identity.recipient());
+    Ok(())
+}
+
+/// Trusts a device to sign the vault, once anyone is trusted only vaults
+/// signed by a trusted device can be unsealed. Without a public key this
+/// trusts the current device.
+pub fn signer_trust(root: impl AsRef<Path>, label: &str, signer: Option<&str>, keyfile: Option<&Path>, identity: Option<&Path>) -> Result<()> {
+    let root = RootPath::new(root.as_ref());
+    let (_lock, mut handle) = open_at_rest(&root, "trusting a signer")?;
+    let (_, master) = unlock_master(&handle, keyfile, identity)?;
+
+    let signer: Signer = match signer {
+        Some(signer) => signer.parse()?,
+        None => DeviceKey::load_or_generate(&root)?.signer(),
+    };
+    let mut signers = verified_signers_or_reset(&mut handle, &master)?;
+    if signers.is_empty() {
+        console_log!(Warn, "From now on only vaults signed by a trusted device can be unsealed.");
+    }
+    signers.add(label, signer)?;
+    handle.set_trusted_signers(&signers, &master)?;
+    handle.writeback()?;
+
+    console_log!(Info, "Vaults signed by {signer} are now trusted as '{label}'.");
+    Ok(())
+}
+
+/// Stops trusting a device, vaults that it already signed can no longer
+/// be unsealed until someone trusted seals them again.
+pub fn signer_untrust(root: impl AsRef<Path>, label: &str, keyfile: Option<&Path>, identity: Option<&Path>) -> Result<()> {
+    let root = RootPath::new(root.as_ref());
+    let (_lock, mut handle) = open_at_rest(&root, "removing a signer")?;
+    let (_, master) = unlock_master(&handle, keyfile, identity)?;
+
+    let mut signers = verified_signers_or_reset(&mut handle, &master)?;
+    signers.remove(label)?;
+    handle.set_trusted_signers(&signers, &master)?;
+    handle.writeback()?;
+
+    console_log!(Info, "Vaults signed by '{label}' are no longer trusted.");
+    if signers.is_empty() {
+        console_log!(Warn, "There are no trusted signers left, so signatures are no longer checked.");
+    }
+    Ok(())
+}
+
+/// The trusted signers, or none at all if they were tampered with. Nothing
+/// of a list that fails its check is kept, the master key has been given
+/// so the signers can be trusted again from scratch.
+fn verified_signers_or_reset(handle: &mut StateFileHandle, master: &MasterVaultKey) -> Result<TrustedSigners> {
+    handle.get_trusted_signers(master).or_else(|e| {
+        console_log!(Warn, "{e} The trusted signers are dropped and have to be trusted again.");
+        Ok(TrustedSigners::default())
+    })
+}
+
+/// Lists the trusted signers along with the device that signed the vault.
+pub fn signer_list(root: impl AsRef<Path>) -> Result<()> {
+    let root = RootPath::new(root.as_ref());
+    let (_lock, handle) = open_at_rest(&root, "listing the signers")?;
+
+    // Without the master key the list can not be checked, an unseal does that.
+    let signers = handle.get_unverified_trusted_signers()?;
+    if signers.is_empty() {
+        console_log!(Info, "There are no trusted signers, signatures are not checked.");
+    }
+    for (label, signer) in signers.iter() {
+        console_log!(Info, "{label}: {signer}");
+    }
+    if let Some(signature) = VaultSignature::read(&root.vault_signature())? {
+        let label = signers.find(&signature.signer).unwrap_or("an unknown device");
+        console_log!(Info, "The sealed vault was signed by {label} ({}).", signature.signer);
+    }
+    Ok(())
+}
+
+/// Prints the public key that this device signs the vault with.
+pub fn signer_show(root: impl AsRef<Path>) -> Result<()> {
+    let root = RootPath::new(root.as_ref());
+    if !root.metadata_folder().exists() {
+        return Err(anyhow!("There is no repository in that directory."));
+    }
+    println!("{}", DeviceKey::load_or_generate(&root)?.signer());
+    Ok(())
+}
+
+/// Replaces the master key, re-encrypting the vault binaries and
+/// rewrapping every key slot whose password is provided.
+pub fn rotate_master(root: impl AsRef<Path>, keyfile: Option<&Path>, identity: Option<&Path>) -> Result<()> {
+    let root = RootPath::new(root.as_ref());
+    let slots = open_at_rest(&root, "rotating the master key")?.1.get_key_slots()?;
+
+    let password = match identity {
+        Some(_) => CachedPassword::from_string(String::new()),
+        None => fetch_password(&slots, keyfile)?,
+    };
+    rotate_master_with(&root, password, keyfile, identity)
+}
+
+/// Rotates the master key after it was unlocked with the password, or with
+/// the identity if one is given.
+fn rotate_master_with(root: &RootPath<Normal>, mut password: CachedPassword, keyfile: Option<&Path>, identity: Option<&Path>) -> Result<()> {
+    let (_lock, mut handle) = open_at_rest(root, "rotating the master key")?;
+    let was_unsealed = handle.get_state()? == VaultState::Unsealed;
+    let identity = identity.map(Identity::from_file).transpose()?;
+
+    // Every slot needs its own password to be rewrapped, the ones
+    // that are left empty are dropped once that is confirmed. With an
+    // identity none of the slots were unlocked yet.
+    let slots = handle.get_key_slots()?;
+    let unlocked = match &identity {
+        Some(identity) => {
+            handle.get_recipients()?.unlock(identity)?;
+            None
+        }
+        None => Some(slots.unlock(&mut password)?.0),
+    };
+    let mut passwords = vec![];
+    for (index, slot) in slots.iter().enumerate() {
+        if Some(index) == unlocked {
+            passwords.push((slot.label.clone(), password.clone()));
+            continue;
+        }
+        let factors = slot.key.factors();
+        let other = if !factors.password && keyfile.is_some() {
+            // A slot that only needs the keyfile is carried over without asking.
+            CachedPassword::from_string(String::new())
+        } else if slot.label == RECOVERY_SLOT {
+            // The recovery slot is wrapped under the canonical form of its phrase.
+            prompt_recovery_phrase("Recovery phrase (leave empty to drop it): ")?
+                .map_or_else(|| CachedPassword::from_string(String::new()), |phrase| phrase.to_password())
+        } else {
+            read_password(&format!("Password for key slot '{}' (leave empty to drop it): ", slot.label))?
+        };
+        if other.is_empty() && factors.password {
+            if !prompt_confirm(&format!("Drop key slot '{}'? It will no longer unlock the vault.", slot.label))? {
+                return Err(anyhow!("The rotation was cancelled, nothing was changed."));
+            }
+            console_log!(Warn, "Key slot '{}' will be dropped.", slot.label);
+            continue;
+        }
+        let mut other = match factors.keyfile {
+            true => with_keyfile(other, keyfile)?,
+            false => other,
+        };
+        slot.key.get_master_key_with_no_rewrap(&mut other)
+            .map_err(|_| anyhow!("The password does not open key slot '{}', which needs {factors}.", slot.label))?;
+        passwords.push((slot.label.clone(), other));
+    }
+    drop(handle);
+
+    let mut context = Context::new(root, &mut password)?;
+    context.set_rotation_passwords(passwords);
+    if let Some(identity) = identity {
+        context.set_identity(identity);
+    }
+
+    // The rotation works on the sealed binaries.
+    if was_unsealed {
+        SEAL_FULL.play(root, &mut context)?;
+    }
+    ROTATE_MASTER.play(root, &mut context)?;
+    if was_unsealed {
+        unseal_verbose(root.path(), &mut context)?;
+    }
+
+    console_log!(Info, "Succesfully rotated the master key.");
+    Ok(())
+}
+
+/// Diagnoses an operation that was interrupted and finishes or undoes it,
+/// asking before anything is deleted.
+pub fn repair(root: impl AsRef<Path>, dry_run: bool, keyfile: Option<&Path>, identity: Option<&Path>) -> Result<()> {
+    let root = RootPath::new(root.as_ref());
+    if !root.metadata_folder().exists() {
+        return Err(anyhow!("There is no repository in that directory."));
+    }
+    if dry_run {
+        // A dry run neither locks the vault nor writes the state, not even a migration.
+        print_repair_plan(&root, &mut StateFileHandle::open_read_only(root.path())?)?;
+        return Ok(());
+    }
+
+    // The password is only asked for when the repair has to unlock the vault.
+    let identity = identity.map(Identity::from_file).transpose()?;
+    let mut password = CachedPassword::from_string(String::new());
+    let mut context = Context::new(&root, &mut password)?;
+    let Some(state) = print_repair_plan(&root, context.state_file_mut())? else {
+        return Ok(());
+    };
+
+    match identity {
+        Some(identity) => context.set_identity(identity),
+        None if state.repair_needs_password(context.state_file()) => {
+            *context.password() = with_keyfile(prompt_password(false)?, keyfile)?;
+        }
+        None => {}
+    }
+    context.set_repair_mode(RepairMode::Confirm);
+    let state = VaultState::recover(&root, &mut context)?;
+
+    console_log!(Info, "Succesfully repaired the vault, it is now {state:?}.");
+    if state == VaultState::Uninit {
+        console_log!(Info, "Run 'novovault init' to start the initialization over.");
+    }
+    Ok(())
+}
+
+/// Prints what a repair would do, giving back the state that needs it.
+fn print_repair_plan(root: &RootPath<Normal>, handle: &mut StateFileHandle) -> Result<Option<VaultState>> {
+    let state = handle.get_state()?;
+    if state.is_rest_state() {
+        console_log!(Info, "The vault is {state:?}, there is nothing to repair.");
+        return Ok(None);
+    }
+
+    console_log!(Warn, "An operation was interrupted, the vault was left in the {state:?} state.");
+    for step in state.repair_plan(root, handle)? {
+        console_log!(Info, "Planned: {step}");
+    }
+    Ok(Some(state))
+}
+
+/// Lists the labels of every key slot.
+pub fn key_list(root: impl AsRef<Path>) -> Result<()> {
+    let root = RootPath::new(root.as_ref());
+    let (_lock, handle) = open_at_rest(&root, "listing the keys")?;
+
+    for slot in handle.get_key_slots()?.iter() {
+        console_log!(Info, "{}", slot.label);
+    }
+    Ok(())
+}
+
+/// Generates a new recovery phrase and wraps the master key under it in
+/// its own slot, replacing any phrase that was exported before.
+pub fn recovery_export(root: impl AsRef<Path>, keyfile: Option<&Path>, identity: Option<&Path>) -> Result<()> {
+    let root = RootPath::new(root.as_ref());
+    let (_lock, mut handle) = open_at_rest(&root, "exporting a recovery phrase")?;
+
+    let mut slots = handle.get_key_slots()?;
+    if slots.iter().any(|s| s.label == RECOVERY_SLOT)
+        && !prompt_confirm("There is already a recovery phrase, the old one will stop working. Continue?")? {
+        return Ok(());
+    }
+
+    let (_, master) = unlock_master(&handle, keyfile, identity)?;
+
+    let phrase = RecoveryPhrase::generate();
+    slots.set_recovery(WrappedKey::init(&UserVaultKey::init_fresh(&mut phrase.to_password(), handle.get_kdf_params()?)?, &master)?);
+    handle.set_key_slots(&slots);
+    handle.writeback()?;
+    refresh_embedded_keys(&root, &mut handle)?;
+
+    console_log!(Info, "The recovery slot is embedded in every vault binary that is pushed, the phrase alone opens any copy of the vault.");
+    console_log!(Info, "Write this recovery phrase down and keep it somewhere safe, it will not be shown again:");
+    println!();
+    println!("    {}", phrase.display());
+    println!();
+    Ok(())
+}
+
+/// Unlocks the vault with the recovery phrase, sets a new password on the
+/// given slot and then unseals the vault with it.
+pub fn recovery_unlock(root: impl AsRef<Path>, label: &str) -> Result<()> {
+    let root = RootPath::new(root.as_ref());
+    check_slot_label(label)?;
+    let (_lock, mut handle) = open_at_rest(&root, "recovering it")?;
+
+    let phrase = prompt_recovery_phrase("Recovery phrase: ")?
+        .ok_or_else(|| anyhow!("No recovery phrase was given."))?;
+
+    let mut slots = handle.get_key_slots()?;
+    let (_, master) = slots.unlock(&mut phrase.to_password())
+        .map_err(|_| anyhow!("The recovery phrase does not unlock this vault."))?;
+
+    let mut password = prompt_new_password()?;
+    slots.set(label, WrappedKey::init(&UserVaultKey::init_fresh(&mut password, handle.get_kdf_params()?)?, &master)?)?;
+    handle.set_key_slots(&slots);
+    handle.writeback()?;
+    refresh_embedded_keys(&root, &mut handle)?;
+
+    console_log!(Info, "Succesfully set a new password on key slot '{label}'.");
+
+    if handle.get_state()? == VaultState::Sealed {
+        unseal_verbose(root.path(), &mut Context::new(&root, &mut password)?)?;
+    }
+    Ok(())
+}
+
+/// Calibrates the key derivation to take roughly the target time, if
+/// there is a vault at the root these become its parameters and every
+/// slot is rewrapped with them the next time it is unlocked.
+pub fn kdf_bench(root: impl AsRef<Path>, target_ms: u64, memory_mib: u32) -> Result<()> {
+    let root = RootPath::new(root.as_ref());
+
+    let target = Duration::from_millis(target_ms);
+    let params = calibrate_kdf(target, memory_mib.saturating_mul(1024), 1)?;
+
+    let start = Instant::now();
+    UserVaultKey::init_fresh(&mut CachedPassword::from_string("novovault benchmark".to_string()), params)?;
+    console_log!(Info, "Argon2id with {} MiB and {} passes took {} ms.", params.memory_kib / 1024, params.iterations, start.elapsed().as_millis());
+
+    if !root.metadata_folder().exists() {
+        console_log!(Info, "There is no repository in that directory, pass these to init with --kdf-memory-mib {} --kdf-iterations {}.", params.memory_kib / 1024, params.iterations);
+        return Ok(());
+    }
+
+    let (_lock, mut handle) = open_at_rest(&root, "changing the key derivation")?;
+    let previous = handle.get_kdf_params()?;
+    handle.set_kdf_params(params);
+    handle.writeback()?;
+
+    if params.at_least(&previous) {
+        console_log!(Info, "The vault will now use these parameters, each key slot is rewrapped the next time it unlocks the vault.");
+    } else {
+        console_log!(Warn, "These parameters are weaker than before, so they only apply to passwords that are set from now on.");
+    }
+    Ok(())
+}
+
+/// Rewrites any vault binaries that were written in an older
+/// format into the newest format.
+pub fn migrate(root: impl AsRef<Path>, keyfile: Option<&Path>, identity: Option<&Path>) -> Result<()> {
+    let root = RootPath::new(root.as_ref());
+    if !root.metadata_folder().exists() {
+        return Err(anyhow!("There is no repository in that directory."));
+    }
+    // Held from reading the state until the migrated binaries are written back.
+    let _lock = VaultLock::acquire(&root)?;
+    let mut handle = StateFileHandle::new(root.path())?;
+
+    match handle.get_state()? {
+        VaultState::Sealed => {}
+        VaultState::Unsealed => {
+            console_log!(Info, "The vault is unsealed, it will be written in the newest format when it is next sealed.");
+            return Ok(());
+        }
+        state => {
+            return Err(anyhow!("The vault was left in an incomplete state ({state:?}), please seal or unseal it before migrating."));
+        }
+    }
+
+    with_joined(&root, || migrate_joined(&root, &mut handle, keyfile, identity))
+}
+
+fn migrate_joined(root: &RootPath<Normal>, handle: &mut StateFileHandle, keyfile: Option<&Path>, identity: Option<&Path>) -> Result<()> {
+    let mut outdated = vec![];
+    for binary in [root.vault_binary(), root.secure_local_zip()] {
+        if binary.exists() && read_header(&binary)?.is_outdated() {
+            outdated.push(binary);
+        }
+    }
+
+    if outdated.is_empty() {
+        console_log!(Info, "The vault is already in the newest format.");
+        return Ok(());
+    }
+
+    let master = match identity {
+        Some(identity) => handle.get_recipients()?.unlock(&Identity::from_file(identity)?)?,
+        None => {
+            let slots = handle.get_key_slots()?;
+            slots.unlock(&mut fetch_password(&slots, keyfile)?)?.1
+        }
+    };
+
+    let key_block = handle.key_block()?;
+    let mut stepped = SteppedComputationHandle::start("Migrating", outdated.len());
+    for binary in outdated {
+        stepped.start_next(
+            &format!("Rewriting {binary:?}"),
+            &format!("Rewrote {binary:?}"),
+            || reencrypt(&binary, master.key_bytes(), master.key_bytes(), &key_block),
+        )?;
+    }
+    refresh_parity(&root.vault_binary(), &root.vault_parity())?;
+    if root.vault_binary().exists() {
+        sign_vault(root)?;
+    }
+    stepped.finish();
+
+    console_log!(Info, "Succesfully migrated the vault to the newest format.");
+    Ok(())
+}
+
+/// Asks a yes or no question, anything other than yes is a no.
+pub fn prompt_confirm(question: &str) -> Result<bool> {
+    print!("{} {question} [y/N] ", "PROMPT".magenta().bold());
+    stdout().flush()?;
+
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
 pub fn prompt_s3_access_key_and_pass() -> Result<(String, String)> {
     print!("{} ", "PROMPT".magenta().bold());
     stdout().flush()?;
@@ -324,19 +1088,47 @@ pub fn prompt_s3_access_key_and_pass() -> Result<(String, String)> {
 }
 
 fn get_password_with_prompt(confirm: bool) -> Result<CachedPassword> {
-    print!("{} ", "PROMPT".magenta().bold());
-    stdout().flush()?;
-
-    let scan = CachedPassword::from_string(rpassword::prompt_password(if !confirm {
+    read_password(if !confirm {
         "Enter vault password: "
     } else {
         "Confirm password: "
-    })?);
+    })
+}
+
+fn read_password(prompt: &str) -> Result<CachedPassword> {
+    print!("{} ", "PROMPT".magenta().bold());
+    stdout().flush()?;
+
+    let scan = CachedPassword::from_string(rpassword::prompt_password(prompt)?);
 
     stdout().lock().execute(MoveUp(1)).unwrap();
     Ok(scan)
 }
 
+/// Prompts for a recovery phrase, giving back nothing if it was left empty.
+fn prompt_recovery_phrase(prompt: &str) -> Result<Option<RecoveryPhrase>> {
+    print!("{} ", "PROMPT".magenta().bold());
+    stdout().flush()?;
+
+    let input = zeroize::Zeroizing::new(rpassword::prompt_password(prompt)?);
+    if input.trim().is_empty() {
+        return Ok(None);
+    }
+    RecoveryPhrase::parse(&input).map(Some)
+}
+
+/// Prompts for a new password along with a confirmation.
+pub fn prompt_new_password() -> Result<CachedPassword> {
+    let first = read_password("Enter new password: ")?;
+    let second = get_password_with_prompt(true)?;
+
+    if first == second {
+        Ok(first)
+    } else {
+        Err(anyhow!("Passowrds fail to match."))
+    }
+}
+
 /// Prompts for a password, optionally asking for
 /// password confirmation.
 ///
@@ -358,14 +1150,15 @@ pub fn prompt_password(confirm: bool) -> Result<CachedPassword> {
     }
 }
 
-pub fn fetch_password(wrapped: &WrappedKey) -> Result<CachedPassword> {
+pub fn fetch_password(slots: &KeySlots, keyfile: Option<&Path>) -> Result<CachedPassword> {
     match env::var("novpwd").map(CachedPassword::from_string) {
-        Ok(mut e) => {
+        Ok(e) => {
+            let mut e = with_keyfile(e, keyfile)?;
             console_log!(
                 Info,
                 "Found a password in the shell variables, trying the password."
             );
-            if wrapped.get_master_key(&mut e).is_ok() {
+            if slots.unlock(&mut e).is_ok() {
                 console_log!(Info, "Password succesfully verified.");
                 Ok(e)
             } else {
@@ -373,9 +1166,36 @@ pub fn fetch_password(wrapped: &WrappedKey) -> Result<CachedPassword> {
                     Error,
                     "The password could not be verified and thus will need to be entered manually."
                 );
-                prompt_password(false)
+                with_keyfile(prompt_password(false)?, keyfile)
             }
         }
-        Err(_) => prompt_password(false),
+        Err(_) => with_keyfile(prompt_password(false)?, keyfile),
     }
 }
+
+/// Attaches the keyfile, if one was given, as a second factor.
+pub fn with_keyfile(password: CachedPassword, keyfile: Option<&Path>) -> Result<CachedPassword> {
+    match keyfile {
+        Some(path) => password.with_keyfile(path),
+        None => Ok(password)
+    }
+}
+
+/// Fills a new keyfile with random bytes, an existing keyfile is left
+/// untouched so that it can be shared between vaults.
+pub fn create_keyfile(path: &Path) -> Result<()> {
+    if path.exists() {
+        return Ok(());
+    }
+    let mut contents = zeroize::Zeroizing::new([0u8; 64]);
+    rand::fill(&mut contents[..]);
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(&contents[..])?;
+
+    console_log!(Info, "Created a new keyfile at {path:?}, keep a copy of it somewhere safe.");
+    Ok(())
+}
@@ -0,0 +1,200 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{Read, Write},
+    path::Path,
+};
+
+use anyhow::{Result, anyhow};
+use flate2::{Compression, read::DeflateDecoder, write::DeflateEncoder};
+use zip::{CompressionMethod, write::FullFileOptions};
+
+use crate::sys::header::CompressionId;
+
+/// How much of a file is looked at to decide whether it compresses.
+const SAMPLE_SIZE: u64 = 64 * 1024;
+
+/// Samples smaller than this are compressed regardless, there is
+/// too little to go on and too little to lose.
+const MIN_SAMPLE_SIZE: usize = 4 * 1024;
+
+/// A sample has to shrink below this percentage of its size for the
+/// file to be compressed at all.
+const MAX_RATIO_PERCENT: usize = 95;
+
+/// Which codec each file is compressed with, as set in `novault.toml`.
+#[derive(Clone, Debug)]
+pub struct CompressionPolicy {
+    default: CompressionId,
+    /// The level of the default codec, the overrides use
+    /// the default level of their own codec.
+    level: Option<i32>,
+    /// Codecs by lowercase file extension, these skip the sampling.
+    overrides: HashMap<String, CompressionId>,
+}
+
+impl Default for CompressionPolicy {
+    fn default() -> Self {
+        Self {
+            default: CompressionId::Deflate,
+            level: None,
+            overrides: HashMap::new(),
+        }
+    }
+}
+
+impl CompressionPolicy {
+    pub fn new(
+        default: CompressionId,
+        level: Option<i32>,
+        overrides: HashMap<String, CompressionId>,
+    ) -> Result<Self> {
+        if let Some(level) = level {
+            check_level(default, level)?;
+        }
+        Ok(Self {
+            default,
+            level,
+            overrides: overrides
+                .into_iter()
+                .map(|(ext, codec)| (ext.trim_start_matches('.').to_lowercase(), codec))
+                .collect(),
+        })
+    }
+    /// The codec that the vault binary is marked with.
+    pub fn default_codec(&self) -> CompressionId {
+        self.default
+    }
+    /// Picks the codec for a file, an override for its extension always wins,
+    /// otherwise files that do not compress are stored as they are.
+    pub fn choose(&self, path: &Path) -> Result<CompressionId> {
+        let extension = path
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase());
+        if let Some(codec) = extension.and_then(|e| self.overrides.get(&e)) {
+            return Ok(*codec);
+        }
+        if self.default == CompressionId::Stored {
+            return Ok(CompressionId::Stored);
+        }
+
+        let mut sample = vec![];
+        File::open(path)?.take(SAMPLE_SIZE).read_to_end(&mut sample)?;
+        if is_incompressible(&sample)? {
+            Ok(CompressionId::Stored)
+        } else {
+            Ok(self.default)
+        }
+    }
+    /// The level of the default codec, none for the codec's own default.
+    pub fn default_level(&self) -> Option<i32> {
+        self.level
+    }
+    fn level(&self, codec: CompressionId) -> Option<i32> {
+        if codec == self.default { self.level } else { None }
+    }
+    /// The zip entry options for a file that uses this codec, the permissions
+    /// are only a fallback for paths that have none of their own.
+    pub fn zip_options(&self, codec: CompressionId) -> FullFileOptions<'static> {
+        let options = FullFileOptions::default().unix_permissions(0o755);
+        match codec {
+            CompressionId::Deflate => options
+                .compression_method(CompressionMethod::Deflated)
+                .compression_level(self.level(codec).map(i64::from)),
+            CompressionId::Zstd => options
+                .compression_method(CompressionMethod::Zstd)
+                .compression_level(self.level(codec).map(i64::from)),
+            CompressionId::Stored => options.compression_method(CompressionMethod::Stored),
+        }
+    }
+    pub fn compress(&self, codec: CompressionId, data: &[u8]) -> Result<Vec<u8>> {
+        match codec {
+            CompressionId::Deflate => {
+                let level = self.level(codec).map_or(Compression::default(), |l| Compression::new(l as u32));
+                let mut encoder = DeflateEncoder::new(vec![], level);
+                encoder.write_all(data)?;
+                Ok(encoder.finish()?)
+            }
+            CompressionId::Zstd => Ok(zstd::encode_all(data, self.level(codec).unwrap_or(0))?),
+            CompressionId::Stored => Ok(data.to_vec()),
+        }
+    }
+}
+
+fn check_level(codec: CompressionId, level: i32) -> Result<()> {
+    let range = match codec {
+        CompressionId::Deflate => 0..=9,
+        CompressionId::Zstd => -7..=22,
+        CompressionId::Stored => return Ok(()),
+    };
+    if !range.contains(&level) {
+        return Err(anyhow!(
+            "The compression level {level} is out of range for {codec:?}, it should be between {} and {}.",
+            range.start(),
+            range.end()
+        ));
+    }
+    Ok(())
+}
+
+pub fn decompress(codec: CompressionId, data: &[u8]) -> Result<Vec<u8>> {
+    let mut out = vec![];
+    match codec {
+        CompressionId::Deflate => {
+            DeflateDecoder::new(data).read_to_end(&mut out)?;
+        }
+        CompressionId::Zstd => out = zstd::decode_all(data)?,
+        CompressionId::Stored => out.extend_from_slice(data),
+    }
+    Ok(out)
+}
+
+/// Whether a quick compression of the sample barely shrinks it, which is
+/// the case for media and archives that are already compressed.
+fn is_incompressible(sample: &[u8]) -> Result<bool> {
+    if sample.len() < MIN_SAMPLE_SIZE {
+        return Ok(false);
+    }
+    let compressed = zstd::encode_all(sample, 1)?;
+    Ok(compressed.len() * 100 >= sample.len() * MAX_RATIO_PERCENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::sys::{compression::{CompressionPolicy, decompress}, header::CompressionId};
+
+    #[test]
+    pub fn check_compression_policy() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("compression");
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut noise = vec![0u8; 128 * 1024];
+        rand::fill(&mut noise[..]);
+        std::fs::write(dir.join("photo.JPG"), &noise).unwrap();
+        std::fs::write(dir.join("noise.bin"), &noise).unwrap();
+        std::fs::write(dir.join("notes.md"), "a note that repeats itself. ".repeat(4096)).unwrap();
+
+        let policy = CompressionPolicy::new(
+            CompressionId::Zstd,
+            Some(19),
+            HashMap::from([(".jpg".to_string(), CompressionId::Deflate)]),
+        )
+        .unwrap();
+
+        // Overrides skip the sampling, random data is otherwise stored.
+        assert_eq!(policy.choose(&dir.join("photo.JPG")).unwrap(), CompressionId::Deflate);
+        assert_eq!(policy.choose(&dir.join("noise.bin")).unwrap(), CompressionId::Stored);
+        assert_eq!(policy.choose(&dir.join("notes.md")).unwrap(), CompressionId::Zstd);
+
+        for codec in [CompressionId::Deflate, CompressionId::Zstd, CompressionId::Stored] {
+            let compressed = policy.compress(codec, b"hello hello hello hello").unwrap();
+            assert_eq!(decompress(codec, &compressed).unwrap(), b"hello hello hello hello");
+        }
+
+        // Deflate only goes up to nine.
+        assert!(CompressionPolicy::new(CompressionId::Deflate, Some(19), HashMap::new()).is_err());
+    }
+}
@@ -0,0 +1,459 @@
+/// Marks a point right after a state or filesystem operation, where an
+/// interruption has to be recoverable. Faults are only injected in tests.
+#[cfg(not(test))]
+pub fn checkpoint(_label: &str) -> anyhow::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+pub use injection::{arm, checkpoint, disarm};
+
+#[cfg(test)]
+mod injection {
+    use std::cell::Cell;
+
+    use anyhow::{Result, anyhow};
+
+    use crate::console_log;
+
+    /// How many checkpoints to let through before a fault is injected.
+    #[derive(Clone, Copy)]
+    struct FaultPlan {
+        remaining: usize,
+        /// Whether the process is killed outright, or the operation just fails.
+        abort: bool,
+    }
+
+    thread_local! {
+        /// Setting `NOVOVAULT_FAULT_AFTER` kills the test process after that many checkpoints.
+        static PLAN: Cell<Option<FaultPlan>> = Cell::new(
+            std::env::var("NOVOVAULT_FAULT_AFTER")
+                .ok()
+                .and_then(|after| after.parse().ok())
+                .map(|remaining| FaultPlan { remaining, abort: true }),
+        );
+    }
+
+    /// Nothing happens unless a fault is armed.
+    pub fn checkpoint(label: &str) -> Result<()> {
+        let Some(mut plan) = PLAN.get() else {
+            return Ok(());
+        };
+        if plan.remaining > 0 {
+            plan.remaining -= 1;
+            PLAN.set(Some(plan));
+            return Ok(());
+        }
+
+        PLAN.set(None);
+        if plan.abort {
+            console_log!(Error, "Injected a fault after {label}, aborting.");
+            std::process::abort();
+        }
+        Err(anyhow!("Injected a fault after {label}."))
+    }
+
+    /// Arms a fault after the given number of checkpoints. It fails the operation
+    /// instead of killing the process, nothing after the checkpoint gets to run.
+    pub fn arm(after: usize) {
+        PLAN.set(Some(FaultPlan { remaining: after, abort: false }));
+    }
+
+    /// Disarms the fault, giving back whether it never fired.
+    pub fn disarm() -> bool {
+        PLAN.take().is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, io::Write, path::{Path, PathBuf}, process::{Command, Stdio}};
+
+    use walkdir::WalkDir;
+
+    use crate::sys::{
+        common::repair,
+        fault::{arm, disarm},
+        init::init_vault,
+        lib::path::RootPath,
+        mk::{CachedPassword, DEFAULT_SLOT, KdfParams},
+        procedure::{
+            actions::{Context, RepairMode, VaultState},
+            sequence::{Playable, ROTATE_MASTER, SEAL_FULL, UNSEAL_FULL},
+        },
+        statefile::StateFileHandle,
+    };
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    enum Operation {
+        Init,
+        Seal,
+        Unseal,
+        Rotate,
+    }
+
+    impl Operation {
+        const ALL: [Self; 4] = [Self::Init, Self::Seal, Self::Unseal, Self::Rotate];
+
+        /// The state that the operation leaves the vault in.
+        fn rest_state(self) -> VaultState {
+            match self {
+                Self::Unseal => VaultState::Unsealed,
+                _ => VaultState::Sealed,
+            }
+        }
+    }
+
+    fn run(operation: Operation, root: &Path, auto_repair: bool) -> anyhow::Result<()> {
+        let root = RootPath::new(root);
+        let mut password = CachedPassword::from_string("hunter2".to_string());
+        if operation == Operation::Init {
+            return init_vault(&root, &mut password, KdfParams::new(256, 1, 1)?, auto_repair);
+        }
+        let mut ctx = Context::new(&root, &mut password)?;
+        if auto_repair {
+            ctx.set_repair_mode(RepairMode::Automatic);
+        }
+        match operation {
+            Operation::Seal => SEAL_FULL.play(&root, &mut ctx),
+            Operation::Rotate => {
+                ctx.set_rotation_passwords(vec![(DEFAULT_SLOT.to_string(), CachedPassword::from_string("hunter2".to_string()))]);
+                ROTATE_MASTER.play(&root, &mut ctx)
+            }
+            _ => UNSEAL_FULL.play(&root, &mut ctx),
+        }
+    }
+
+    fn state(root: &Path) -> VaultState {
+        StateFileHandle::new(root).unwrap().get_state().unwrap()
+    }
+
+    /// A vault with files going to each of the binaries.
+    fn write_plaintext(root: &Path) {
+        std::fs::create_dir_all(root.join("notes/deep")).unwrap();
+        std::fs::write(root.join(".gitignore"), "/.nov\n*.key\n").unwrap();
+        std::fs::write(
+            root.join("novault.toml"),
+            "[settings]\ndefault_policy = \"IgnoreAndEncrypt\"\ncompression = \"Stored\"\nparity = 4\n\n[rules]\nunsecured = [\"README.md\", \"*.local\"]\ndelete = []\n",
+        )
+        .unwrap();
+        std::fs::write(root.join("README.md"), "Left in the open.").unwrap();
+        std::fs::write(root.join("notes/a.txt"), "The first note.").unwrap();
+        std::fs::write(root.join("notes/deep/b.md"), "The second note.").unwrap();
+        std::fs::write(root.join("notes/deep/c.local"), "Left in the open next to a sealed note.").unwrap();
+        std::fs::write(root.join("device.key"), "Only ever kept locally.").unwrap();
+        std::fs::write(root.join("random.bin"), rand::random::<[u8; 32]>()).unwrap();
+    }
+
+    /// Every file of the plaintext by path, the repositories are left out.
+    fn plaintext(root: &Path) -> BTreeMap<PathBuf, Vec<u8>> {
+        WalkDir::new(root)
+            .into_iter()
+            .filter_entry(|e| e.file_name() != ".nov" && e.file_name() != ".git")
+            .map(Result::unwrap)
+            .filter(|e| e.file_type().is_file())
+            .map(|e| (e.path().strip_prefix(root).unwrap().to_path_buf(), std::fs::read(e.path()).unwrap()))
+            .collect()
+    }
+
+    fn copy_dir(from: &Path, to: &Path) {
+        for entry in WalkDir::new(from) {
+            let entry = entry.unwrap();
+            let target = to.join(entry.path().strip_prefix(from).unwrap());
+            if entry.file_type().is_dir() {
+                std::fs::create_dir_all(target).unwrap();
+            } else {
+                std::fs::copy(entry.path(), target).unwrap();
+            }
+        }
+    }
+
+    /// Kills each operation after every checkpoint in turn, then checks that
+    /// running it again finishes the job without anything being lost.
+    #[test]
+    pub fn check_crash_consistency() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("fault");
+        let unsealed = dir.join("unsealed");
+        let sealed = dir.join("sealed");
+
+        write_plaintext(&unsealed);
+        let expected = plaintext(&unsealed);
+        run(Operation::Init, &unsealed, false).unwrap();
+        run(Operation::Unseal, &unsealed, false).unwrap();
+        assert_eq!(plaintext(&unsealed), expected);
+        copy_dir(&unsealed, &sealed);
+        run(Operation::Seal, &sealed, false).unwrap();
+
+        for operation in Operation::ALL {
+            for after in 0.. {
+                let root = dir.join(format!("{operation:?}-{after}"));
+                match operation {
+                    Operation::Init => write_plaintext(&root),
+                    Operation::Seal => copy_dir(&unsealed, &root),
+                    Operation::Unseal | Operation::Rotate => copy_dir(&sealed, &root),
+                }
+                // The random file differs, so each fresh vault has its own plaintext.
+                let expected = match operation {
+                    Operation::Init => plaintext(&root),
+                    _ => expected.clone(),
+                };
+
+                arm(after);
+                let result = run(operation, &root, false);
+                if disarm() {
+                    // Every checkpoint was passed without a fault.
+                    result.unwrap();
+                    std::fs::remove_dir_all(&root).unwrap();
+                    break;
+                }
+                assert!(result.is_err(), "{operation:?} succeeded despite a fault after {after} checkpoints.");
+
+                // Without being asked to, nothing is repaired.
+                if !state(&root).is_rest_state() {
+                    let before = plaintext(&root);
+                    assert!(run(operation, &root, false).is_err(), "{operation:?} repaired a fault after {after} checkpoints unasked.");
+                    assert_eq!(plaintext(&root), before);
+                }
+                run(operation, &root, true).unwrap_or_else(|e| panic!("{operation:?} could not recover from a fault after {after} checkpoints: {e:?}"));
+                assert_eq!(state(&root), operation.rest_state());
+                if operation != Operation::Unseal {
+                    run(Operation::Unseal, &root, false).unwrap();
+                }
+                assert_eq!(plaintext(&root), expected, "{operation:?} lost data after a fault after {after} checkpoints.");
+                std::fs::remove_dir_all(&root).unwrap();
+            }
+        }
+    }
+
+    /// Runs each operation in a child process that really aborts after every
+    /// checkpoint in turn, so that no cleanup gets to run, then checks that
+    /// the vault left behind is repaired without anything being lost.
+    #[test]
+    pub fn check_abort_recovery() {
+        if let (Ok(root), Ok(operation)) = (std::env::var("NOVOVAULT_FAULT_ROOT"), std::env::var("NOVOVAULT_FAULT_OPERATION")) {
+            // This is the child, which the armed fault kills part way through.
+            let operation = Operation::ALL.into_iter().find(|o| format!("{o:?}") == operation).unwrap();
+            run(operation, Path::new(&root), false).unwrap();
+            return;
+        }
+
+        let tmp = tempfile::tempdir().unwrap();
+        let unsealed = tmp.path().join("unsealed");
+        let sealed = tmp.path().join("sealed");
+        write_plaintext(&unsealed);
+        run(Operation::Init, &unsealed, false).unwrap();
+        run(Operation::Unseal, &unsealed, false).unwrap();
+        let expected = plaintext(&unsealed);
+        copy_dir(&unsealed, &sealed);
+        run(Operation::Seal, &sealed, false).unwrap();
+
+        for operation in Operation::ALL {
+            for after in 0.. {
+                let root = tmp.path().join(format!("abort-{operation:?}-{after}"));
+                match operation {
+                    Operation::Init => write_plaintext(&root),
+                    Operation::Seal => copy_dir(&unsealed, &root),
+                    Operation::Unseal | Operation::Rotate => copy_dir(&sealed, &root),
+                }
+                let expected = match operation {
+                    Operation::Init => plaintext(&root),
+                    _ => expected.clone(),
+                };
+
+                let status = Command::new(std::env::current_exe().unwrap())
+                    .args(["--exact", "sys::fault::tests::check_abort_recovery", "--test-threads=1"])
+                    .env("NOVOVAULT_FAULT_AFTER", after.to_string())
+                    .env("NOVOVAULT_FAULT_ROOT", &root)
+                    .env("NOVOVAULT_FAULT_OPERATION", format!("{operation:?}"))
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null())
+                    .status()
+                    .unwrap();
+                if status.success() {
+                    // Every checkpoint was passed without the fault firing.
+                    assert_eq!(state(&root), operation.rest_state());
+                    break;
+                }
+                #[cfg(unix)]
+                assert_eq!(std::os::unix::process::ExitStatusExt::signal(&status), Some(libc::SIGABRT));
+
+                let recovered = {
+                    let paths = RootPath::new(&root);
+                    let mut password = CachedPassword::from_string("hunter2".to_string());
+                    let mut ctx = Context::new(&paths, &mut password).unwrap();
+                    ctx.set_repair_mode(RepairMode::Automatic);
+                    VaultState::recover(&paths, &mut ctx)
+                        .unwrap_or_else(|e| panic!("{operation:?} could not recover from an abort after {after} checkpoints: {e:?}"))
+                };
+                assert!(recovered.is_rest_state());
+                assert_eq!(state(&root), recovered);
+
+                match recovered {
+                    VaultState::Sealed => run(Operation::Unseal, &root, false).unwrap(),
+                    VaultState::Unsealed => {}
+                    // An initialization that was rolled back starts over.
+                    _ => {
+                        assert_eq!(operation, Operation::Init, "{operation:?} was rolled back to {recovered:?}.");
+                        run(Operation::Init, &root, false).unwrap();
+                        run(Operation::Unseal, &root, false).unwrap();
+                    }
+                }
+                assert_eq!(plaintext(&root), expected, "{operation:?} lost data after an abort after {after} checkpoints.");
+                std::fs::remove_dir_all(&root).unwrap();
+            }
+        }
+    }
+
+    /// Every file in the metadata folder by path.
+    fn metadata(root: &Path) -> BTreeMap<PathBuf, Vec<u8>> {
+        WalkDir::new(root.join(".nov"))
+            .into_iter()
+            .map(Result::unwrap)
+            .filter(|e| e.file_type().is_file())
+            .map(|e| (e.path().to_path_buf(), std::fs::read(e.path()).unwrap()))
+            .collect()
+    }
+
+    /// Interrupts an operation at the first checkpoint after which the
+    /// vault is in the given state and the condition holds.
+    fn interrupt(operation: Operation, from: &Path, root: &Path, at: VaultState, condition: impl Fn(&Path) -> bool) {
+        for after in 0.. {
+            match operation {
+                Operation::Init => write_plaintext(root),
+                _ => copy_dir(from, root),
+            }
+            arm(after);
+            let result = run(operation, root, false);
+            assert!(!disarm(), "{operation:?} finished without ever being in the {at:?} state.");
+            assert!(result.is_err());
+            if state(root) == at && condition(root) {
+                return;
+            }
+            std::fs::remove_dir_all(root).unwrap();
+        }
+    }
+
+    /// Plans a repair of the vault as it is on the disk.
+    fn repair_plan(root: &Path) -> Vec<String> {
+        let mut handle = StateFileHandle::open_read_only(root).unwrap();
+        handle.get_state().unwrap().repair_plan(&RootPath::new(root), &handle).unwrap()
+    }
+
+    /// Leaves a seal interrupted with the binaries partially written, then
+    /// checks that the plan names them, that a dry run leaves them and that
+    /// the repair only deletes them once that is confirmed.
+    #[test]
+    pub fn check_repair_confirm() {
+        if let Ok(root) = std::env::var("NOVOVAULT_REPAIR_ROOT") {
+            // This is the child, which answers the question from its stdin.
+            let root = RootPath::new(Path::new(&root));
+            let mut password = CachedPassword::from_string("hunter2".to_string());
+            let mut ctx = Context::new(&root, &mut password).unwrap();
+            ctx.set_repair_mode(RepairMode::Confirm);
+            SEAL_FULL.play(&root, &mut ctx).unwrap();
+            return;
+        }
+
+        let tmp = tempfile::tempdir().unwrap();
+        let unsealed = tmp.path().join("unsealed");
+        write_plaintext(&unsealed);
+        run(Operation::Init, &unsealed, false).unwrap();
+        run(Operation::Unseal, &unsealed, false).unwrap();
+        let expected = plaintext(&unsealed);
+
+        let root = tmp.path().join("interrupted");
+        interrupt(Operation::Seal, &unsealed, &root, VaultState::Encrypting, |root| {
+            RootPath::new(root).inprogress_vault().exists()
+        });
+        let paths = RootPath::new(&root);
+        let leftovers: Vec<_> = [paths.deletion_shards(), paths.secure_local_zip(), paths.inprogress_vault(), paths.inprogress_parity()]
+            .into_iter()
+            .filter(|path| path.exists())
+            .collect();
+        let mut planned: Vec<_> = leftovers
+            .iter()
+            .map(|path| format!("Delete {path:?}, which was partially written."))
+            .collect();
+        planned.push("Leave the vault unsealed.".to_string());
+        assert_eq!(repair_plan(&root), planned);
+
+        // A dry run writes nothing at all.
+        let before = metadata(&root);
+        repair(&root, true, None, None).unwrap();
+        assert_eq!(metadata(&root), before);
+
+        let confirm = |answer: &str| {
+            let mut child = Command::new(std::env::current_exe().unwrap())
+                .args(["--exact", "sys::fault::tests::check_repair_confirm", "--test-threads=1"])
+                .env("NOVOVAULT_REPAIR_ROOT", &root)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn()
+                .unwrap();
+            child.stdin.take().unwrap().write_all(answer.as_bytes()).unwrap();
+            child.wait().unwrap()
+        };
+
+        assert!(!confirm("n\n").success());
+        assert_eq!(state(&root), VaultState::Encrypting);
+        assert!(leftovers.iter().all(|path| path.exists()));
+        assert_eq!(repair_plan(&root), planned);
+
+        assert!(confirm("y\n").success());
+        assert_eq!(state(&root), VaultState::Sealed);
+        run(Operation::Unseal, &root, false).unwrap();
+        assert_eq!(plaintext(&root), expected);
+    }
+
+    /// Leaves an initialization interrupted and checks that starting it over
+    /// deletes everything in the metadata folder but the lock that is held.
+    #[test]
+    pub fn check_repair_keeps_lock() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("interrupted");
+        interrupt(Operation::Init, &root, &root, VaultState::InitFileSystem, |_| true);
+
+        let paths = RootPath::new(&root);
+        let files: Vec<_> = metadata(&root).into_keys().filter(|path| *path != paths.lock_file()).collect();
+        assert!(!files.is_empty());
+        let mut planned: Vec<_> = files
+            .iter()
+            .map(|path| format!("Delete {path:?}, the initialization then has to be run again."))
+            .collect();
+        planned.push("Leave the vault uninitialized.".to_string());
+        assert_eq!(repair_plan(&root), planned);
+
+        let mut password = CachedPassword::from_string("hunter2".to_string());
+        let mut ctx = Context::new(&paths, &mut password).unwrap();
+        ctx.set_repair_mode(RepairMode::Automatic);
+        assert_eq!(VaultState::recover(&paths, &mut ctx).unwrap(), VaultState::Uninit);
+        assert!(paths.lock_file().exists());
+        drop(ctx);
+
+        run(Operation::Init, &root, false).unwrap();
+        assert_eq!(state(&root), VaultState::Sealed);
+    }
+
+    /// Lowers the parameters in the synced state and checks that sealing
+    /// does not move the slot that unlocked the vault down to them.
+    #[test]
+    pub fn check_seal_keeps_slot_params() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("vault");
+        write_plaintext(&root);
+        let strong = KdfParams::new(1024, 2, 1).unwrap();
+        init_vault(&RootPath::new(&root), &mut CachedPassword::from_string("hunter2".to_string()), strong, false).unwrap();
+        run(Operation::Unseal, &root, false).unwrap();
+
+        let mut handle = StateFileHandle::new(&root).unwrap();
+        handle.set_kdf_params(KdfParams::new(256, 1, 1).unwrap());
+        handle.writeback().unwrap();
+        run(Operation::Seal, &root, false).unwrap();
+
+        let slots = StateFileHandle::new(&root).unwrap().get_key_slots().unwrap();
+        assert!(slots.iter().all(|slot| slot.key.params() == strong));
+        run(Operation::Unseal, &root, false).unwrap();
+    }
+}
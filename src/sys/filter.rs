@@ -1,9 +1,11 @@
 use std::{collections::HashMap, path::{Path, PathBuf}};
 
-use anyhow::Result;
+use anyhow::{Result, anyhow};
 use ignore::gitignore::{Gitignore, GitignoreBuilder};
 use serde::Deserialize;
 
+use crate::{console_log, sys::{compression::CompressionPolicy, header::CompressionId, parity::DATA_SHARDS, stream::Padding}};
+
 
 fn get_gitignore(root: impl AsRef<Path>) -> Result<Gitignore> {
     let path = root.as_ref();
@@ -31,6 +33,17 @@ pub enum FilterDecision {
     Unsecure
 }
 
+/// How the encrypted files are laid out on the disk.
+#[derive(Clone, Copy, Debug, Deserialize, Default, PartialEq, Eq)]
+pub enum VaultLayout {
+    /// Everything is zipped into the one vault binary.
+    #[default]
+    Archive,
+    /// Each file is its own encrypted object, and the vault
+    /// binary holds the manifest.
+    Objects
+}
+
 pub struct NovFilter {
     root: PathBuf,
     git_ignore: Gitignore,
@@ -39,6 +52,12 @@ pub struct NovFilter {
 
 struct TomlRules {
     default_policy: FilterDecision,
+    layout: VaultLayout,
+    compression: CompressionPolicy,
+    padding: Padding,
+    xattrs: bool,
+    parity: usize,
+    shard_size: Option<u64>,
     unsecured: Option<Gitignore>,
     delete: Option<Gitignore>
 }
@@ -46,13 +65,32 @@ struct TomlRules {
 #[derive(Deserialize, Debug)]
 struct TomlRulesSer {
     settings: SettingsSer,
-    rules: HashMap<String, Vec<String>>
+    rules: HashMap<String, Vec<String>>,
+    /// Codecs by file extension.
+    #[serde(default)]
+    compression: HashMap<String, CompressionId>
 }
 
 #[derive(Deserialize, Debug)]
 struct SettingsSer {
     #[serde(default)]
-    default_policy: FilterDecision
+    default_policy: FilterDecision,
+    #[serde(default)]
+    layout: VaultLayout,
+    compression: Option<CompressionId>,
+    compression_level: Option<i32>,
+    #[serde(default)]
+    padding: Padding,
+    /// Whether extended attributes are kept, this is only supported on Linux.
+    #[serde(default)]
+    xattrs: bool,
+    /// The parity blocks written for every 32 blocks of the vault binary.
+    /// Only the vault binary is covered, in the object layout that is just
+    /// the manifest and the objects have no parity.
+    #[serde(default)]
+    parity: usize,
+    /// The largest that a single file of the sealed vault may be, in MiB.
+    shard_size_mib: Option<u64>
 }
 
 fn read_rules(path: impl AsRef<Path>) -> Result<TomlRules> {
@@ -63,6 +101,12 @@ fn read_rules(path: impl AsRef<Path>) -> Result<TomlRules> {
         // TODO
         return Ok(TomlRules {
             default_policy: FilterDecision::default(),
+            layout: VaultLayout::default(),
+            compression: CompressionPolicy::default(),
+            padding: Padding::default(),
+            xattrs: false,
+            parity: 0,
+            shard_size: None,
             delete: None,
             unsecured: None
         })
@@ -92,9 +136,32 @@ fn read_rules(path: impl AsRef<Path>) -> Result<TomlRules> {
         delete = Some(gibuilder.build()?);
     }
 
+    if cfg.settings.shard_size_mib == Some(0) {
+        return Err(anyhow!("The shard size has to be at least one MiB."));
+    }
+    if cfg.settings.parity > DATA_SHARDS {
+        return Err(anyhow!("The parity can be at most {DATA_SHARDS} blocks, one for every block of the vault."));
+    }
+    if cfg.settings.parity > 0 && cfg.settings.layout == VaultLayout::Objects {
+        console_log!(
+            Warn,
+            "Parity only covers the vault binary, which in the Objects layout holds nothing but the manifest. The objects themselves are not protected, use the Archive layout for that."
+        );
+    }
+
     // std::process::exit(1);
     Ok(TomlRules {
         default_policy: cfg.settings.default_policy,
+        layout: cfg.settings.layout,
+        compression: CompressionPolicy::new(
+            cfg.settings.compression.unwrap_or(CompressionId::Deflate),
+            cfg.settings.compression_level,
+            cfg.compression
+        )?,
+        padding: cfg.settings.padding,
+        xattrs: cfg.settings.xattrs,
+        parity: cfg.settings.parity,
+        shard_size: cfg.settings.shard_size_mib.map(|m| m * 1024 * 1024),
         delete,
         unsecured
     })
@@ -113,6 +180,27 @@ impl NovFilter {
             rules: toml
         })
     }
+    pub fn layout(&self) -> VaultLayout {
+        self.rules.layout
+    }
+    pub fn compression(&self) -> &CompressionPolicy {
+        &self.rules.compression
+    }
+    pub fn padding(&self) -> Padding {
+        self.rules.padding
+    }
+    pub fn xattrs(&self) -> bool {
+        self.rules.xattrs
+    }
+    /// The parity blocks for every 32 blocks of the vault binary, none when zero.
+    /// The objects of the object layout are never covered.
+    pub fn parity(&self) -> usize {
+        self.rules.parity
+    }
+    /// The largest that a shard of the sealed vault may be, in bytes.
+    pub fn shard_size(&self) -> Option<u64> {
+        self.rules.shard_size
+    }
     pub fn check_decision(&self, path: impl AsRef<Path>) -> Result<FilterDecision> {
         let rel = path.as_ref().strip_prefix(&self.root)?;
 
@@ -0,0 +1,84 @@
+use std::path::Path;
+
+use anyhow::{Result, anyhow};
+
+use crate::{
+    console_log,
+    sys::{
+        lib::path::{Normal, RootPath},
+        shards::shard_path,
+        statefile::StateFileHandle,
+        writer::read_generation,
+    },
+};
+
+/// The generation that the next seal should be written with.
+pub fn next_generation(handle: &StateFileHandle) -> u64 {
+    handle.get_seen_generation() + 1
+}
+
+/// Remembers a generation as seen, the record only ever moves forwards.
+pub fn record_generation(handle: &mut StateFileHandle, generation: u64) {
+    if generation > handle.get_seen_generation() {
+        handle.set_seen_generation(generation);
+    }
+}
+
+/// Refuses a vault that is older than one this device has already seen,
+/// which is what a rolled back or replayed copy looks like.
+pub fn check_generation(handle: &StateFileHandle, generation: u64) -> Result<()> {
+    let seen = handle.get_seen_generation();
+    if generation < seen {
+        return Err(anyhow!(
+            "The vault is at generation {generation} but this device has already seen generation {seen}, it may have been rolled back to an older copy. Reset 'seen_generation' in {:?} to accept it anyway.",
+            handle.local_path()
+        ));
+    }
+    Ok(())
+}
+
+/// Reads the generation of the sealed vault, which may be split into shards.
+pub fn vault_generation(vault: &Path) -> Result<u64> {
+    if vault.exists() {
+        return read_generation(vault);
+    }
+    // The generation sits right after the key slots, well within the first shard.
+    read_generation(shard_path(vault, 0))
+}
+
+/// Warns when the sealed vault is older than one this device has seen. Nothing
+/// is decrypted, so this only catches copies that were rolled back as a whole.
+pub fn warn_if_rolled_back(root: &RootPath<Normal>) {
+    let checked = StateFileHandle::new(root.path()).and_then(|handle| {
+        check_generation(&handle, vault_generation(&root.vault_binary())?)
+    });
+    if let Err(e) = checked {
+        console_log!(Warn, "{e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sys::{generation::{check_generation, next_generation, record_generation}, statefile::StateFileHandle};
+
+    #[test]
+    pub fn check_generation_record() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("generation");
+        let mut handle = StateFileHandle::new(&dir).unwrap();
+
+        assert_eq!(handle.get_seen_generation(), 0);
+        assert_eq!(next_generation(&handle), 1);
+
+        record_generation(&mut handle, 4);
+        record_generation(&mut handle, 2);
+        handle.writeback().unwrap();
+        let handle = StateFileHandle::new(&dir).unwrap();
+        assert_eq!(handle.get_seen_generation(), 4);
+        assert_eq!(next_generation(&handle), 5);
+
+        check_generation(&handle, 4).unwrap();
+        check_generation(&handle, 7).unwrap();
+        assert!(check_generation(&handle, 3).is_err());
+    }
+}
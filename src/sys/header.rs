@@ -0,0 +1,150 @@
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
+
+/// The magic bytes at the top of every vault binary.
+pub const MAGIC: &[u8; 4] = b"NOVO";
+
+/// The size of the fixed part of the header.
+pub const HEADER_SIZE: usize = 8;
+
+/// The newest vault format, this is what is written on every seal.
+pub const CURRENT_VERSION: FormatVersion = FormatVersion::Generation;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FormatVersion {
+    /// The whole archive is encrypted in one go.
+    Legacy = 0,
+    /// The archive is encrypted in fixed-size chunks.
+    Stream = 1,
+    /// The archive is encrypted in fixed-size chunks, with the
+    /// header bound into every chunk as associated data.
+    AuthenticatedStream = 2,
+    /// As above, with a copy of the key slots between the header and
+    /// the nonce prefix so the binary can be opened on its own. The key
+    /// block is not authenticated, so every wrapped key in it, the recovery
+    /// slot included, can be read by anyone holding a copy of the binary.
+    EmbeddedKeys = 3,
+    /// As above, with the plaintext padded and ending in the
+    /// length of the contents.
+    Padded = 4,
+    /// As above, with the generation of the vault after the key slots,
+    /// bound into every chunk so an older vault can not pass for a newer one.
+    Generation = 5,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CipherId {
+    XChaCha20Poly1305 = 0,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KdfId {
+    Argon2id = 0,
+}
+
+/// The codec that the contents were compressed with, this is also
+/// how the codec is named in `novault.toml`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum CompressionId {
+    Deflate = 0,
+    Zstd = 1,
+    /// The contents are stored as they are.
+    #[serde(alias = "None")]
+    Stored = 2,
+}
+
+/// The fixed header of a vault binary, the four bytes after the
+/// magic describe how the rest of the file was produced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VaultHeader {
+    pub version: FormatVersion,
+    pub cipher: CipherId,
+    pub kdf: KdfId,
+    pub compression: CompressionId,
+}
+
+impl VaultHeader {
+    /// The header that new vaults are written with.
+    pub fn current() -> Self {
+        Self {
+            version: CURRENT_VERSION,
+            cipher: CipherId::XChaCha20Poly1305,
+            kdf: KdfId::Argon2id,
+            compression: CompressionId::Deflate,
+        }
+    }
+    pub fn with_compression(mut self, compression: CompressionId) -> Self {
+        self.compression = compression;
+        self
+    }
+    pub fn to_bytes(self) -> [u8; HEADER_SIZE] {
+        let mut bytes = [0u8; HEADER_SIZE];
+        bytes[..4].copy_from_slice(MAGIC);
+        bytes[4] = self.version as u8;
+        bytes[5] = self.cipher as u8;
+        bytes[6] = self.kdf as u8;
+        bytes[7] = self.compression as u8;
+        bytes
+    }
+    pub fn parse(bytes: &[u8; HEADER_SIZE]) -> Result<Self> {
+        if bytes[..4] != *MAGIC {
+            return Err(anyhow!("Could not find the magic header at the top of the vault binary."));
+        }
+        let version = match bytes[4] {
+            0 => FormatVersion::Legacy,
+            1 => FormatVersion::Stream,
+            2 => FormatVersion::AuthenticatedStream,
+            3 => FormatVersion::EmbeddedKeys,
+            4 => FormatVersion::Padded,
+            5 => FormatVersion::Generation,
+            v => return Err(anyhow!("Unknown vault format version ({v}), this vault was made by a newer version of novovault.")),
+        };
+        let cipher = match bytes[5] {
+            0 => CipherId::XChaCha20Poly1305,
+            v => return Err(anyhow!("Unknown vault cipher ({v}), this vault was made by a newer version of novovault.")),
+        };
+        let kdf = match bytes[6] {
+            0 => KdfId::Argon2id,
+            v => return Err(anyhow!("Unknown vault key derivation ({v}), this vault was made by a newer version of novovault.")),
+        };
+        let compression = match bytes[7] {
+            0 => CompressionId::Deflate,
+            1 => CompressionId::Zstd,
+            2 => CompressionId::Stored,
+            v => return Err(anyhow!("Unknown vault compression ({v}), this vault was made by a newer version of novovault.")),
+        };
+        Ok(Self {
+            version,
+            cipher,
+            kdf,
+            compression,
+        })
+    }
+    /// Whether this vault should be rewritten into the newest format.
+    pub fn is_outdated(&self) -> bool {
+        self.version < CURRENT_VERSION
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sys::header::{CompressionId, FormatVersion, VaultHeader};
+
+    #[test]
+    pub fn check_header_roundtrip() {
+        let header = VaultHeader::current();
+        assert_eq!(VaultHeader::parse(&header.to_bytes()).unwrap(), header);
+
+        let zstd = header.with_compression(CompressionId::Zstd);
+        assert_eq!(VaultHeader::parse(&zstd.to_bytes()).unwrap().compression, CompressionId::Zstd);
+
+        // The original format wrote four zero bytes.
+        let legacy = VaultHeader::parse(b"NOVO\0\0\0\0").unwrap();
+        assert_eq!(legacy.version, FormatVersion::Legacy);
+        assert!(legacy.is_outdated());
+
+        assert!(VaultHeader::parse(b"NOVO\x09\0\0\0").is_err());
+        assert!(VaultHeader::parse(b"ZIP!\0\0\0\0").is_err());
+        assert!(VaultHeader::parse(b"NOVO\x03\0\0\x09").is_err());
+    }
+}
@@ -3,28 +3,54 @@ use std::path::Path;
 use anyhow::{Result, anyhow};
 
 
-use crate::{console_log, sys::{common::prompt_password, lib::path::RootPath, procedure::{actions::Context, sequence::{INIT_FULL, Playable}}}};
+use crate::{console_log, sys::{common::{create_keyfile, prompt_password, with_keyfile}, lib::path::{Normal, RootPath}, mk::{CachedPassword, KdfParams}, procedure::{actions::{Context, RepairMode, VaultState}, sequence::{INIT_FULL, Playable}}, statefile::StateFileHandle}};
 
 
 
 
 
-pub fn run_init(root: impl AsRef<Path>) -> Result<()> {
-    let path = root.as_ref();
+pub fn run_init(root: impl AsRef<Path>, params: KdfParams, keyfile: Option<&Path>, auto_repair: bool) -> Result<()> {
+    let root = RootPath::new(root.as_ref());
 
-
-    if RootPath::new(path).metadata_folder().exists() {
+    if root.metadata_folder().exists() && !interrupted_init(&root)? {
         return Err(anyhow!("There is already a repository in that directory."));
     }
 
 
 
-    let mut password = prompt_password(true)?;
-
-    let root = RootPath::new(path);
+    if let Some(keyfile) = keyfile {
+        create_keyfile(keyfile)?;
+    }
+    let mut password = with_keyfile(prompt_password(true)?, keyfile)?;
 
-    INIT_FULL.play(&root, &mut Context::new(&root, &mut password)?)?;
+    init_vault(&root, &mut password, params, auto_repair)?;
 
     console_log!(Info, "Succesfully initialized a new NoVault");
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Whether an earlier initialization was interrupted, in which case it is
+/// finished instead of the directory being refused.
+fn interrupted_init(root: &RootPath<Normal>) -> Result<bool> {
+    let mut handle = StateFileHandle::new(root.path())?;
+    Ok(match handle.get_state()? {
+        VaultState::Uninit => true,
+        VaultState::Sealed | VaultState::Unsealed => false,
+        // The flag is only missing if we were stopped before the key was made.
+        _ => handle.get_init().unwrap_or(true),
+    })
+}
+
+/// Initializes a vault under the password, finishing the initialization
+/// instead if an earlier one was interrupted and repairs are allowed.
+pub fn init_vault(root: &RootPath<Normal>, password: &mut CachedPassword, params: KdfParams, auto_repair: bool) -> Result<()> {
+    let mut ctx = Context::new(root, password)?;
+    if auto_repair {
+        ctx.set_repair_mode(RepairMode::Automatic);
+    }
+    if VaultState::recover(root, &mut ctx)? == VaultState::Uninit {
+        ctx.state_file_mut().set_kdf_params(params);
+        INIT_FULL.play(root, &mut ctx)?;
+    }
+    Ok(())
+}
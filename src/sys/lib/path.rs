@@ -57,6 +57,18 @@ impl<T> RootPath<T> {
     pub fn vault_binary(&self) -> PathBuf {
         self.path().join("vault.bin")
     }
+    pub fn vault_parity(&self) -> PathBuf {
+        self.path().join("vault.par")
+    }
+    pub fn vault_signature(&self) -> PathBuf {
+        self.path().join("vault.sig")
+    }
+    pub fn inprogress_parity(&self) -> PathBuf {
+        self.metadata_folder().join("inpro.par")
+    }
+    pub fn objects_folder(&self) -> PathBuf {
+        self.metadata_folder().join("objects")
+    }
     pub fn wrap_folder(&self) -> PathBuf {
         self.metadata_folder().join("wrap")
     }
@@ -72,4 +84,13 @@ impl<T> RootPath<T> {
     pub fn s3_param_file(&self) -> PathBuf {
         self.metadata_folder().join(".s3auth")
     }
+    pub fn sealed_listing(&self) -> PathBuf {
+        self.metadata_folder().join(".sealed")
+    }
+    pub fn lock_file(&self) -> PathBuf {
+        self.metadata_folder().join(".lock")
+    }
+    pub fn device_key(&self) -> PathBuf {
+        self.metadata_folder().join(".device")
+    }
 }
\ No newline at end of file
@@ -19,6 +19,40 @@ pub fn t3_fetch(
     bucket_name: &str,
     file_path: &Path
 ) -> Result<Vec<u8>> {
+    let body = t3_get(s3_access_key, s3_secret_key, bucket_name, file_path)?;
+
+    // An error comes back as a body of its own, which must never be
+    // mistaken for the file.
+    if !body.status().is_success() {
+        return Err(anyhow!("Failed to fetch {file_path:?} ({}).", body.status()));
+    }
+    Ok(body.bytes()?.to_vec())
+}
+
+/// Fetches a file that may not exist, which is reported as nothing.
+pub fn t3_try_fetch(
+    s3_access_key: &str,
+    s3_secret_key: &str,
+    bucket_name: &str,
+    file_path: &Path
+) -> Result<Option<Vec<u8>>> {
+    let body = t3_get(s3_access_key, s3_secret_key, bucket_name, file_path)?;
+
+    if body.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    if !body.status().is_success() {
+        return Err(anyhow!("Failed to fetch {file_path:?} ({}).", body.status()));
+    }
+    Ok(Some(body.bytes()?.to_vec()))
+}
+
+fn t3_get(
+    s3_access_key: &str,
+    s3_secret_key: &str,
+    bucket_name: &str,
+    file_path: &Path
+) -> Result<reqwest::blocking::Response> {
 
 
     let path_str = file_path.to_string_lossy();
@@ -43,8 +77,8 @@ pub fn t3_fetch(
         &datetime,
         &headers,
         "us-east-1",
-        &s3_access_key,
-        &s3_secret_key,
+        s3_access_key,
+        s3_secret_key,
         "execute-api",
         ""
     );
@@ -57,7 +91,7 @@ pub fn t3_fetch(
         .headers(headers)
         .send()?;
 
-    Ok(body.bytes().unwrap().to_vec())
+    Ok(body)
 }
 
 
@@ -92,8 +126,8 @@ pub fn t3_delete(
         &datetime,
         &headers,
         "us-east-1",
-        &s3_access_key,
-        &s3_secret_key,
+        s3_access_key,
+        s3_secret_key,
         "execute-api",
         ""
     );
@@ -150,8 +184,8 @@ pub fn t3_put(
         &datetime,
         &headers,
         "us-east-1",
-        &s3_access_key,
-        &s3_secret_key,
+        s3_access_key,
+        s3_secret_key,
         "s3",
         ""
     );
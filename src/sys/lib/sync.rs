@@ -1,14 +1,15 @@
-use std::{io::Write, path::Path};
+use std::{collections::HashSet, io::Write, path::{Path, PathBuf}};
 
 use anyhow::{Result, anyhow};
 use colorize::AnsiColor;
+use walkdir::WalkDir;
 
 use crate::{
-    console_log, printing::SteppedComputationHandle, sys::{
+    console_log, sys::{
         common::prompt_s3_access_key_and_pass, lib::{
             path::{Normal, RootPath},
-            remote::t3::{get_snapshot_sig, t3_delete, t3_fetch, t3_put},
-        }, process::{add_remote_origin, git_add_all, git_add_commit_push, git_branch_main, git_clone, git_commit_all, git_push_origin}, statefile::{StateFileHandle, SyncMethod, read_hashmap, string_to_hashmap}
+            remote::t3::{get_snapshot_sig, t3_delete, t3_fetch, t3_put, t3_try_fetch},
+        }, process::{add_remote_origin, git_add_commit_push, git_branch_main, git_clone}, shards::{exists_sharded, read_sharded}, statefile::{StateFileHandle, SyncMethod, string_to_hashmap}
     }
 };
 
@@ -24,8 +25,8 @@ fn parse_link(url: &str) -> Result<String> {
 }
 
 pub fn init_remote(path: &Path, url: &str) -> Result<()> {
-    let (sync, url) = if url.starts_with("t3://") {
-        (SyncMethod::TigrisS3, url[5..].to_string())
+    let (sync, url) = if let Some(bucket) = url.strip_prefix("t3://") {
+        (SyncMethod::TigrisS3, bucket.to_string())
     } else if url.starts_with("git@") {
         (SyncMethod::Git, url.to_string())
     } else {
@@ -68,7 +69,7 @@ pub fn init_remote(path: &Path, url: &str) -> Result<()> {
                 &url,
                 path,
                 &path.join(".nov").join(".state"),
-                &rem_s3,
+                rem_s3,
             )?;
 
             print!(
@@ -86,11 +87,20 @@ pub fn init_remote(path: &Path, url: &str) -> Result<()> {
                 &url,
                 path,
                 &path.join("vault.bin"),
-                &rem_s3,
+                rem_s3,
             )?;
 
+            if exists_sharded(&path.join("vault.par")) {
+                transfer_file(&s3_access, &s3_secret, &url, path, &path.join("vault.par"), rem_s3)?;
+            }
+            if path.join("vault.sig").exists() {
+                transfer_file(&s3_access, &s3_secret, &url, path, &path.join("vault.sig"), rem_s3)?;
+            }
+
             print!("\r  {} Sent vault binary.           \n", "(2/3)".green());
 
+            push_objects(&s3_access, &s3_secret, &url, &RootPath::new(path), rem_s3, None)?;
+
             print!("  {} Setting remote lock...", "(3/3)".yellow());
             std::io::stdout().flush()?;
 
@@ -140,7 +150,8 @@ pub fn transfer_file(
     location: &Path,
     remote_root: &Path,
 ) -> Result<()> {
-    let bytes = std::fs::read(location)?;
+    // Split vaults go up whole, there is no limit on the size there.
+    let bytes = read_sharded(location)?;
 
     t3_put(
         s3_access,
@@ -185,9 +196,119 @@ pub fn load_tigris_params(path: &RootPath<Normal>) -> Result<(String, String)> {
     // t3_fetch(&acc, &sec,, file_path)
 }
 
+/// The objects of a vault that uses the object layout are stored once in
+/// the bucket and shared between snapshots, each snapshot lists the ones
+/// it needs in this index.
+const OBJECT_INDEX: &str = "objects.idx";
+
+fn object_key(id: &str) -> PathBuf {
+    Path::new(".nov").join("objects").join(&id[..2]).join(&id[2..])
+}
+
+fn parse_object_index(bytes: &[u8]) -> Result<HashSet<String>> {
+    let index = std::str::from_utf8(bytes)
+        .map_err(|_| anyhow!("The object index on the remote is corrupted."))?;
+    let ids = index.lines().filter(|l| !l.is_empty()).map(str::to_string).collect::<HashSet<_>>();
+    if ids.iter().any(|id| id.len() != 64 || !id.bytes().all(|b| b.is_ascii_hexdigit())) {
+        return Err(anyhow!("The object index on the remote is corrupted."));
+    }
+    Ok(ids)
+}
+
+/// The ids of every object in the local store.
+fn local_object_ids(path: &RootPath<Normal>) -> Result<HashSet<String>> {
+    let mut ids = HashSet::new();
+    if !path.objects_folder().exists() {
+        return Ok(ids);
+    }
+    for file in WalkDir::new(path.objects_folder()).min_depth(2).max_depth(2) {
+        let file = file?;
+        let fan = file.path().parent().and_then(|p| p.file_name()).unwrap_or_default();
+        ids.insert(format!("{}{}", fan.to_string_lossy(), file.file_name().to_string_lossy()));
+    }
+    Ok(ids)
+}
+
+/// Uploads the objects that the previous snapshot did not have along with
+/// the index of this snapshot. If the previous snapshot had an index, the
+/// objects that are no longer needed are returned, these should only be
+/// deleted once the lock points at the new snapshot.
+fn push_objects(
+    s3_access: &str,
+    s3_secret: &str,
+    bucket: &str,
+    path: &RootPath<Normal>,
+    snapshot: &Path,
+    previous: Option<&Path>,
+) -> Result<Option<HashSet<String>>> {
+    let ids = local_object_ids(path)?;
+    let uploaded = match previous {
+        Some(previous) => t3_try_fetch(s3_access, s3_secret, bucket, &previous.join(OBJECT_INDEX))?
+            .map(|index| parse_object_index(&index))
+            .transpose()?,
+        None => None,
+    };
+    if ids.is_empty() && uploaded.is_none() {
+        return Ok(None);
+    }
+    let uploaded = uploaded.unwrap_or_default();
+
+    let missing = ids.difference(&uploaded).collect::<Vec<_>>();
+    console_log!(Info, "Sending {} new objects, {} are already on the remote.", missing.len(), ids.len() - missing.len());
+    for id in missing {
+        transfer_file(s3_access, s3_secret, bucket, path.path(), &path.path().join(object_key(id)), Path::new(""))?;
+    }
+
+    let mut index = ids.iter().map(String::as_str).collect::<Vec<_>>();
+    index.sort();
+    t3_put(s3_access, s3_secret, bucket, &snapshot.join(OBJECT_INDEX), index.join("\n").into_bytes())?;
+
+    Ok(Some(uploaded.difference(&ids).cloned().collect()))
+}
+
+/// Deletes the objects that fell out of use along with the index of the old snapshot.
+fn delete_objects(
+    s3_access: &str,
+    s3_secret: &str,
+    bucket: &str,
+    previous: &Path,
+    stale: &HashSet<String>,
+) -> Result<()> {
+    for id in stale {
+        t3_delete(s3_access, s3_secret, bucket, &object_key(id))?;
+    }
+    t3_delete(s3_access, s3_secret, bucket, &previous.join(OBJECT_INDEX))?;
+    Ok(())
+}
+
+/// Fetches every object that the snapshot lists, snapshots of vaults that
+/// use the archive layout have no index.
+fn pull_objects(
+    access: &str,
+    secret: &str,
+    bucket: &str,
+    path: &RootPath<Normal>,
+    snapshot: &Path,
+) -> Result<()> {
+    let Some(index) = t3_try_fetch(access, secret, bucket, &snapshot.join(OBJECT_INDEX))? else {
+        return Ok(());
+    };
+    let ids = parse_object_index(&index)?;
+
+    console_log!(Info, "(TigrisT3) Pulling {} objects...", ids.len());
+    for id in ids {
+        let target = path.path().join(object_key(&id));
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(target, t3_fetch(access, secret, bucket, &object_key(&id))?)?;
+    }
+    Ok(())
+}
+
 pub fn pull_remote(path: &Path, url: &str) -> Result<()> {
-    let (sync, url) = if url.starts_with("t3://") {
-        (SyncMethod::TigrisS3, url[5..].to_string())
+    let (sync, url) = if let Some(bucket) = url.strip_prefix("t3://") {
+        (SyncMethod::TigrisS3, bucket.to_string())
     } else if url.starts_with("git@") {
         (SyncMethod::Git, url.to_string())
     } else {
@@ -237,6 +358,8 @@ pub fn pull_remote(path: &Path, url: &str) -> Result<()> {
             std::io::stdout().flush()?;
 
             let vault_bin = t3_fetch(&access, &secret, &url, &rem_root.join("vault.bin"))?;
+            let vault_par = t3_try_fetch(&access, &secret, &url, &rem_root.join("vault.par"))?;
+            let vault_sig = t3_try_fetch(&access, &secret, &url, &rem_root.join("vault.sig"))?;
 
             print!(
                 "\r  {} Pulled vault binary.               \n",
@@ -258,6 +381,21 @@ pub fn pull_remote(path: &Path, url: &str) -> Result<()> {
 
             std::fs::write(path.state_file(), state_file)?;
             std::fs::write(path.vault_binary(), vault_bin)?;
+            if let Some(vault_par) = vault_par {
+                std::fs::write(path.vault_parity(), vault_par)?;
+            }
+            if let Some(vault_sig) = vault_sig {
+                std::fs::write(path.vault_signature(), vault_sig)?;
+            }
+            pull_objects(&access, &secret, &url, &path, rem_root)?;
+
+            // Only the shared half of the state travels, this device starts
+            // out at the snapshot that it just pulled.
+            let mut handle = StateFileHandle::new(path.path())?;
+            handle.set_remote(&url);
+            handle.set_remote_storage(SyncMethod::TigrisS3);
+            handle.previous_tigris_commit_stamp(string);
+            handle.writeback()?;
 
             console_log!(Info, "(TigrisT3) Wrote artifacts to disk.");
         }
@@ -320,7 +458,7 @@ pub fn push_remote(path: &Path) -> Result<()> {
                 &bucket,
                 path.path(),
                 &path.state_file(),
-                &rem_s3,
+                rem_s3,
             )?;
 
             print!(
@@ -338,11 +476,27 @@ pub fn push_remote(path: &Path) -> Result<()> {
                 &bucket,
                 path.path(),
                 &path.vault_binary(),
-                &rem_s3,
+                rem_s3,
             )?;
 
+            if exists_sharded(&path.vault_parity()) {
+                transfer_file(&s3_access, &s3_secret, &bucket, path.path(), &path.vault_parity(), rem_s3)?;
+            }
+            if path.vault_signature().exists() {
+                transfer_file(&s3_access, &s3_secret, &bucket, path.path(), &path.vault_signature(), rem_s3)?;
+            }
+
             print!("\r  {} Sent vault binary.           \n", "(2/4)".green());
 
+            let stale = push_objects(
+                &s3_access,
+                &s3_secret,
+                &bucket,
+                &path,
+                rem_s3,
+                Some(Path::new(&last_commit)),
+            )?;
+
             print!("  {} Setting remote lock...", "(3/3)".yellow());
             std::io::stdout().flush()?;
 
@@ -359,12 +513,27 @@ pub fn push_remote(path: &Path) -> Result<()> {
                 &bucket,
                 &Path::new(&last_commit).join("vault.bin"),
             )?;
+            t3_delete(
+                &s3_access,
+                &s3_secret,
+                &bucket,
+                &Path::new(&last_commit).join("vault.par"),
+            )?;
+            t3_delete(
+                &s3_access,
+                &s3_secret,
+                &bucket,
+                &Path::new(&last_commit).join("vault.sig"),
+            )?;
             t3_delete(
                 &s3_access,
                 &s3_secret,
                 &bucket,
                 &Path::new(&last_commit).join(".nov").join(".state"),
             )?;
+            if let Some(stale) = stale {
+                delete_objects(&s3_access, &s3_secret, &bucket, Path::new(&last_commit), &stale)?;
+            }
 
             print!("\r  {} Deleted old folder.           \n", "(4/4)".green());
         
@@ -0,0 +1,185 @@
+use std::{
+    fs::{File, OpenOptions, TryLockError},
+    io::Write,
+    path::PathBuf,
+};
+
+use anyhow::{Result, anyhow};
+
+use crate::sys::{
+    lib::path::{Normal, RootPath},
+    statefile::string_to_hashmap,
+};
+
+/// An advisory lock on a vault, so that two processes never work on the same
+/// directory at once. It is released when this is dropped.
+///
+/// The lock itself is taken by the operating system on `.nov/.lock`, which lets
+/// go of it when its holder exits, so a stale lock never has to be taken over.
+pub struct VaultLock {
+    /// The open lockfile, none if this process already held the lock.
+    file: Option<File>,
+}
+
+/// Who is holding a lock, as written into the lockfile.
+struct LockHolder {
+    pid: u32,
+    command: String,
+    started: String,
+}
+
+impl VaultLock {
+    /// Takes the lock on a vault, failing if another process holds it.
+    pub fn acquire(root: &RootPath<Normal>) -> Result<Self> {
+        std::fs::create_dir_all(root.metadata_folder())?;
+        let path = root.lock_file();
+
+        // The file is never removed, a process could otherwise end up locking
+        // a file that has just been unlinked while another creates a new one.
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => return Self::held(path),
+            Err(TryLockError::Error(e)) => return Err(e.into()),
+        }
+
+        // The holder is only recorded so that others can be told who it is.
+        file.set_len(0)?;
+        file.write_all(LockHolder::current().to_file_contents().as_bytes())?;
+        Ok(Self { file: Some(file) })
+    }
+    /// Deals with a lock that is held, which is fine if it is held by us.
+    fn held(path: PathBuf) -> Result<Self> {
+        match LockHolder::read(&path) {
+            Ok(Some(holder)) if holder.pid == std::process::id() => Ok(Self { file: None }),
+            Ok(Some(holder)) => Err(anyhow!(
+                "The vault is busy, '{}' (PID {}) has been working on it since {}.",
+                holder.command,
+                holder.pid,
+                holder.started
+            )),
+            _ => Err(anyhow!("The vault is busy, another process holds the lock on it.")),
+        }
+    }
+}
+
+impl Drop for VaultLock {
+    fn drop(&mut self) {
+        // Closing the file releases the lock.
+        if let Some(file) = self.file.take() {
+            let _ = file.set_len(0);
+        }
+    }
+}
+
+impl LockHolder {
+    fn current() -> Self {
+        Self {
+            pid: std::process::id(),
+            command: std::env::args().collect::<Vec<_>>().join(" "),
+            started: chrono::Local::now().to_rfc3339(),
+        }
+    }
+    fn to_file_contents(&self) -> String {
+        format!("pid={}\ncommand={}\nstarted={}\n", self.pid, self.command, self.started)
+    }
+    /// Reads the holder of a lock, giving nothing back if it has not been
+    /// written yet.
+    fn read(path: &PathBuf) -> Result<Option<Self>> {
+        let contents = std::fs::read_to_string(path)?;
+        if contents.trim().is_empty() {
+            return Ok(None);
+        }
+        let fields = string_to_hashmap(&contents);
+        let pid = fields
+            .get("pid")
+            .and_then(|pid| pid.parse().ok())
+            .ok_or_else(|| anyhow!("The lockfile at {path:?} is corrupted."))?;
+        Ok(Some(Self {
+            pid,
+            command: fields.get("command").cloned().unwrap_or_default(),
+            started: fields.get("started").cloned().unwrap_or_default(),
+        }))
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{path::Path, process::{Command, Stdio}, time::Duration};
+
+    use crate::sys::{lib::path::{Normal, RootPath}, lock::VaultLock};
+
+    /// A lockfile as a process that has since exited left it behind.
+    fn write_stale_lock(root: &RootPath<Normal>) {
+        let mut child = Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        std::fs::create_dir_all(root.metadata_folder()).unwrap();
+        std::fs::write(root.lock_file(), format!("pid={}\ncommand=novovault seal\nstarted=then\n", child.id())).unwrap();
+    }
+
+    #[test]
+    pub fn check_vault_lock() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("lock");
+        let root = RootPath::new(&dir);
+
+        let lock = VaultLock::acquire(&root).unwrap();
+        assert!(std::fs::read_to_string(root.lock_file()).unwrap().contains(&format!("pid={}", std::process::id())));
+        // The same process can take it again without releasing it early.
+        drop(VaultLock::acquire(&root).unwrap());
+        assert!(std::fs::read_to_string(root.lock_file()).unwrap().contains(&format!("pid={}", std::process::id())));
+        drop(lock);
+        assert!(std::fs::read_to_string(root.lock_file()).unwrap().is_empty());
+
+        // A lock that was left behind is simply taken.
+        write_stale_lock(&root);
+        drop(VaultLock::acquire(&root).unwrap());
+    }
+
+    /// Lets several processes take over the same stale lock at once, and
+    /// checks that no two of them ever hold it together.
+    #[test]
+    pub fn check_racing_takeovers() {
+        if let Ok(dir) = std::env::var("NOVOVAULT_LOCK_ROOT") {
+            // This is one of the children racing for the lock.
+            let root = RootPath::new(Path::new(&dir));
+            match VaultLock::acquire(&root) {
+                Ok(lock) => {
+                    let held = Path::new(&dir).join("held");
+                    std::fs::File::create_new(&held).expect("Two processes held the lock at once.");
+                    std::fs::write(Path::new(&dir).join(format!("acquired.{}", std::process::id())), "").unwrap();
+                    std::thread::sleep(Duration::from_millis(200));
+                    std::fs::remove_file(held).unwrap();
+                    drop(lock);
+                }
+                Err(e) => assert!(e.to_string().contains("busy"), "{e}"),
+            }
+            return;
+        }
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("race");
+        let root = RootPath::new(&dir);
+        write_stale_lock(&root);
+
+        let children: Vec<_> = (0..8)
+            .map(|_| {
+                Command::new(std::env::current_exe().unwrap())
+                    .args(["--exact", "sys::lock::tests::check_racing_takeovers", "--test-threads=1"])
+                    .env("NOVOVAULT_LOCK_ROOT", &dir)
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null())
+                    .spawn()
+                    .unwrap()
+            })
+            .collect();
+        for mut child in children {
+            assert!(child.wait().unwrap().success());
+        }
+        let acquired = std::fs::read_dir(&dir)
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().file_name().to_string_lossy().starts_with("acquired."))
+            .count();
+        assert!(acquired >= 1);
+    }
+}
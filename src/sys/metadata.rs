@@ -0,0 +1,381 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
+
+use crate::console_log;
+
+/// The zip extra field that carries the metadata of an entry, "NV".
+pub const METADATA_FIELD: u16 = 0x564e;
+
+/// A single extra field can not be longer than this, the metadata
+/// shares the space with the fields that the zip writer adds itself.
+const MAX_FIELD_SIZE: usize = 60 * 1024;
+
+/// What is kept of a path besides its contents.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct FileMetadata {
+    /// The permission bits, these are not recorded for symlinks.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+    /// The modification time in seconds since the epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<i64>,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub mtime_nanos: u32,
+    /// Where the path points to if it is a symlink.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub symlink: Option<String>,
+    /// Hex encoded values of the extended attributes, by name.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub xattrs: BTreeMap<String, String>,
+}
+
+fn is_zero(value: &u32) -> bool {
+    *value == 0
+}
+
+impl FileMetadata {
+    /// Reads the metadata of a path without following it if it is a symlink.
+    pub fn read(path: &Path, xattrs: bool) -> Result<Self> {
+        let meta = std::fs::symlink_metadata(path)?;
+        let mut metadata = Self::default();
+
+        if meta.file_type().is_symlink() {
+            metadata.symlink = Some(std::fs::read_link(path)?.to_string_lossy().replace('\\', "/"));
+        } else {
+            metadata.mode = mode_of(&meta);
+        }
+
+        if let Ok(modified) = meta.modified() {
+            let (seconds, nanos) = match modified.duration_since(UNIX_EPOCH) {
+                Ok(since) => (since.as_secs() as i64, since.subsec_nanos()),
+                Err(e) => {
+                    let before = e.duration();
+                    let seconds = -(before.as_secs() as i64);
+                    match before.subsec_nanos() {
+                        0 => (seconds, 0),
+                        nanos => (seconds - 1, 1_000_000_000 - nanos),
+                    }
+                }
+            };
+            metadata.mtime = Some(seconds);
+            metadata.mtime_nanos = nanos;
+        }
+
+        if xattrs {
+            metadata.xattrs = xattr::list(path)?;
+        }
+        Ok(metadata)
+    }
+    pub fn is_symlink(&self) -> bool {
+        self.symlink.is_some()
+    }
+    fn modified(&self) -> Option<SystemTime> {
+        let seconds = self.mtime?;
+        let nanos = Duration::from_nanos(self.mtime_nanos.into());
+        if seconds >= 0 {
+            UNIX_EPOCH.checked_add(Duration::from_secs(seconds as u64) + nanos)
+        } else {
+            UNIX_EPOCH.checked_sub(Duration::from_secs(seconds.unsigned_abs()))?.checked_add(nanos)
+        }
+    }
+    /// The metadata as the contents of a zip extra field.
+    pub fn to_extra_field(&self) -> Result<Vec<u8>> {
+        let field = toml::to_string(self)?;
+        if field.len() <= MAX_FIELD_SIZE {
+            return Ok(field.into_bytes());
+        }
+
+        console_log!(Warn, "The extended attributes are too large to store in the archive and were dropped.");
+        let trimmed = Self {
+            xattrs: BTreeMap::new(),
+            ..self.clone()
+        };
+        Ok(toml::to_string(&trimmed)?.into_bytes())
+    }
+    /// Finds the metadata among the extra fields of a zip entry, archives
+    /// written before the metadata was kept have none.
+    pub fn from_extra_data(mut extra: &[u8]) -> Result<Option<Self>> {
+        while extra.len() >= 4 {
+            let id = u16::from_le_bytes([extra[0], extra[1]]);
+            let length = u16::from_le_bytes([extra[2], extra[3]]) as usize;
+            let Some(data) = extra.get(4..4 + length) else {
+                break;
+            };
+            if id == METADATA_FIELD {
+                let metadata = toml::from_str(&String::from_utf8_lossy(data))
+                    .map_err(|e| anyhow!("The metadata of an archive entry is corrupted: {e}"))?;
+                return Ok(Some(metadata));
+            }
+            extra = &extra[4 + length..];
+        }
+        Ok(None)
+    }
+    /// Restores the metadata onto a path that was just written out, the
+    /// permissions go last so that a read only file can still be touched.
+    pub fn apply(&self, path: &Path) -> Result<()> {
+        for (name, value) in &self.xattrs {
+            let value = hex::decode(value)
+                .map_err(|_| anyhow!("The extended attribute {name} of {path:?} is corrupted."))?;
+            if let Err(e) = xattr::set(path, name, &value) {
+                console_log!(Warn, "Failed to restore the extended attribute {name} (path={path:?}): {e}");
+            }
+        }
+
+        if self.is_symlink() {
+            return Ok(());
+        }
+
+        if let Some(modified) = self.modified() {
+            set_modified(path, modified).inspect_err(|e| {
+                console_log!(Error, "Failed to restore the modification time (path={path:?}): {e:?}");
+            })?;
+        }
+        if let Some(mode) = self.mode {
+            set_mode(path, mode).inspect_err(|e| {
+                console_log!(Error, "Failed to restore the permissions (path={path:?}): {e:?}");
+            })?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn mode_of(meta: &std::fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(meta.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn mode_of(_: &std::fs::Metadata) -> Option<u32> {
+    None
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn set_mode(_: &Path, _: u32) -> std::io::Result<()> {
+    Ok(())
+}
+
+fn set_modified(path: &Path, modified: SystemTime) -> std::io::Result<()> {
+    if path.is_dir() {
+        // Directories can only be opened for this on unix.
+        if cfg!(unix) {
+            File::open(path)?.set_modified(modified)?;
+        }
+        return Ok(());
+    }
+    File::options().write(true).open(path)?.set_modified(modified)
+}
+
+/// Creates a symlink, on windows these are skipped as creating
+/// them usually needs elevated rights.
+pub fn create_symlink(target: &str, path: &Path) -> Result<()> {
+    if std::fs::symlink_metadata(path).is_ok() {
+        std::fs::remove_file(path)?;
+    }
+
+    #[cfg(unix)]
+    std::os::unix::fs::symlink(target, path).inspect_err(|e| {
+        console_log!(Error, "Failed creating a symlink (path={path:?}): {e:?}");
+    })?;
+
+    #[cfg(not(unix))]
+    console_log!(Warn, "Skipping the symlink {path:?} to {target:?} as symlinks are only restored on unix.");
+
+    Ok(())
+}
+
+/// Restores metadata while a tree is being written out. Directories are
+/// left until the end as writing their contents would change them again.
+#[derive(Default)]
+pub struct MetadataRestorer {
+    directories: Vec<(PathBuf, FileMetadata)>,
+}
+
+impl MetadataRestorer {
+    pub fn restore(&mut self, path: &Path, metadata: FileMetadata) -> Result<()> {
+        if path.is_dir() && !metadata.is_symlink() {
+            self.directories.push((path.to_path_buf(), metadata));
+            return Ok(());
+        }
+        metadata.apply(path)
+    }
+    /// Restores the directories, the deepest ones first.
+    pub fn finish(mut self) -> Result<()> {
+        self.directories.sort_by_key(|(path, _)| std::cmp::Reverse(path.components().count()));
+        for (path, metadata) in &self.directories {
+            metadata.apply(path)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod xattr {
+    use std::{collections::BTreeMap, ffi::CString, io::Error, os::unix::ffi::OsStrExt, path::Path};
+
+    use anyhow::Result;
+
+    fn c_path(path: &Path) -> Result<CString> {
+        Ok(CString::new(path.as_os_str().as_bytes())?)
+    }
+
+    /// Lists the extended attributes of a path, without following symlinks.
+    pub fn list(path: &Path) -> Result<BTreeMap<String, String>> {
+        let path = c_path(path)?;
+        let mut attributes = BTreeMap::new();
+
+        let size = unsafe { libc::llistxattr(path.as_ptr(), std::ptr::null_mut(), 0) };
+        if size < 0 {
+            let error = Error::last_os_error();
+            // Some filesystems do not have them at all.
+            if error.raw_os_error() == Some(libc::ENOTSUP) {
+                return Ok(attributes);
+            }
+            return Err(error.into());
+        }
+
+        let mut names = vec![0u8; size as usize];
+        let size = unsafe { libc::llistxattr(path.as_ptr(), names.as_mut_ptr().cast(), names.len()) };
+        if size < 0 {
+            return Err(Error::last_os_error().into());
+        }
+
+        for name in names[..size as usize].split(|b| *b == 0).filter(|n| !n.is_empty()) {
+            let c_name = CString::new(name)?;
+            let size = unsafe { libc::lgetxattr(path.as_ptr(), c_name.as_ptr(), std::ptr::null_mut(), 0) };
+            if size < 0 {
+                return Err(Error::last_os_error().into());
+            }
+            let mut value = vec![0u8; size as usize];
+            let size = unsafe { libc::lgetxattr(path.as_ptr(), c_name.as_ptr(), value.as_mut_ptr().cast(), value.len()) };
+            if size < 0 {
+                return Err(Error::last_os_error().into());
+            }
+            value.truncate(size as usize);
+            attributes.insert(String::from_utf8_lossy(name).into_owned(), hex::encode(value));
+        }
+        Ok(attributes)
+    }
+
+    pub fn set(path: &Path, name: &str, value: &[u8]) -> Result<()> {
+        let path = c_path(path)?;
+        let name = CString::new(name)?;
+        let result = unsafe { libc::lsetxattr(path.as_ptr(), name.as_ptr(), value.as_ptr().cast(), value.len(), 0) };
+        if result < 0 {
+            return Err(Error::last_os_error().into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod xattr {
+    use std::{collections::BTreeMap, path::Path};
+
+    use anyhow::{Result, anyhow};
+
+    pub fn list(_: &Path) -> Result<BTreeMap<String, String>> {
+        Err(anyhow!("Extended attributes can only be kept on Linux, turn off xattrs in the novault.toml."))
+    }
+
+    pub fn set(_: &Path, _: &str, _: &[u8]) -> Result<()> {
+        Err(anyhow!("Extended attributes can only be restored on Linux."))
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{
+        os::unix::fs::PermissionsExt,
+        path::Path,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use crate::sys::{
+        compression::CompressionPolicy,
+        metadata::FileMetadata,
+        mk::MasterVaultKey,
+        objects::{Manifest, ObjectStore, ObjectWriter},
+        procedure::actions::expand_decrypted_bin,
+        stream::Padding,
+        writer::{VaultReader, VaultWriter},
+    };
+
+    const NAMES: [&str; 4] = ["bin", "bin/run.sh", "link", "dangling"];
+
+    fn check_restored(target: &Path) {
+        let script = target.join("bin/run.sh");
+        assert_eq!(std::fs::metadata(&script).unwrap().permissions().mode() & 0o7777, 0o750);
+        assert_eq!(
+            std::fs::metadata(&script).unwrap().modified().unwrap(),
+            UNIX_EPOCH + Duration::new(1_600_000_000, 123_456_789)
+        );
+        assert_eq!(std::fs::metadata(target.join("bin")).unwrap().permissions().mode() & 0o7777, 0o711);
+        assert_eq!(
+            std::fs::metadata(target.join("bin")).unwrap().modified().unwrap(),
+            UNIX_EPOCH + Duration::from_secs(1_500_000_000)
+        );
+
+        // Links are restored as links, not as copies of what they point to.
+        assert_eq!(std::fs::read_link(target.join("link")).unwrap(), Path::new("bin/run.sh"));
+        assert_eq!(std::fs::read_link(target.join("dangling")).unwrap(), Path::new("nowhere"));
+    }
+
+    #[test]
+    pub fn check_metadata_roundtrip() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("metadata");
+        let source = root.join("source");
+        std::fs::create_dir_all(source.join("bin")).unwrap();
+
+        let script = source.join("bin/run.sh");
+        std::fs::write(&script, b"#!/bin/sh\necho hello\n").unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o750)).unwrap();
+        std::fs::File::options().write(true).open(&script).unwrap()
+            .set_modified(UNIX_EPOCH + Duration::new(1_600_000_000, 123_456_789)).unwrap();
+        std::os::unix::fs::symlink("bin/run.sh", source.join("link")).unwrap();
+        std::os::unix::fs::symlink("nowhere", source.join("dangling")).unwrap();
+        std::fs::File::open(source.join("bin")).unwrap()
+            .set_modified(UNIX_EPOCH + Duration::from_secs(1_500_000_000)).unwrap();
+        std::fs::set_permissions(source.join("bin"), std::fs::Permissions::from_mode(0o711)).unwrap();
+
+        let metadata: Vec<_> = NAMES.iter().map(|n| FileMetadata::read(&source.join(n), false).unwrap()).collect();
+        assert_eq!(metadata[2].symlink.as_deref(), Some("bin/run.sh"));
+        assert_eq!(metadata[2].mode, None);
+
+        // The archive layout keeps it in the zip entries.
+        let key = [7u8; 32];
+        let mut writer = VaultWriter::new(root.join("vault.bin"), &key, "", 0, CompressionPolicy::default(), Padding::None).unwrap();
+        for (name, metadata) in NAMES.iter().zip(&metadata) {
+            writer.write_path(&source.join(name), name.as_ref(), metadata).unwrap();
+        }
+        writer.finish().unwrap();
+        expand_decrypted_bin(&root.join("archive"), VaultReader::open(root.join("vault.bin"), &key).unwrap()).unwrap();
+        check_restored(&root.join("archive"));
+
+        // The object layout keeps it in the manifest.
+        let master = MasterVaultKey::generate();
+        let mut writer = ObjectWriter::new(root.join("objects"), &master, CompressionPolicy::default(), Padding::None).unwrap();
+        for (name, metadata) in NAMES.iter().zip(&metadata) {
+            writer.write_path(&source.join(name), name.as_ref(), metadata).unwrap();
+        }
+        writer.finish(root.join("manifest.bin"), "", 0).unwrap();
+        let mut reader = VaultReader::open(root.join("manifest.bin"), master.key_bytes()).unwrap();
+        let manifest = Manifest::read(&mut reader).unwrap().unwrap();
+        ObjectStore::new(root.join("objects"), &master).unwrap().expand(&manifest, &root.join("store")).unwrap();
+        check_restored(&root.join("store"));
+    }
+}
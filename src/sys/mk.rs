@@ -1,57 +1,97 @@
 
+use std::{fmt::Display, path::Path, str::FromStr, time::{Duration, Instant}};
+
 use aes_gcm::{KeyInit, aead::Aead};
 use argon2::{Argon2, Params};
 use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
 use hex::ToHex;
 use zeroize::{ZeroizeOnDrop, Zeroizing};
+use serde::{Deserialize, Serialize};
 use anyhow::{Result, anyhow};
 
+use crate::sys::recovery::RECOVERY_SLOT;
+
 
 #[derive(ZeroizeOnDrop, Clone)]
 pub struct CachedPassword {
     password: String,
+    keyfile: Option<Vec<u8>>,
     cache: Option<CachedPasswordInner>,
     // tag: u32
 }
 
 impl PartialEq for CachedPassword {
     fn eq(&self, other: &Self) -> bool {
-        self.password.eq(&other.password)
+        self.password.eq(&other.password) && self.keyfile.eq(&other.keyfile)
     }
 }
 
+/// The largest keyfile we accept, anything past a few kilobytes
+/// adds nothing but time spent hashing it.
+const MAX_KEYFILE_SIZE: u64 = 1024 * 1024;
+
 impl CachedPassword {
     pub fn from_string(str: String) -> Self {
         Self {
             password: str,
+            keyfile: None,
             cache: None,
             // tag: rand::random()
         }
     }
-    pub fn get_password(&mut self, test_salt: &[u8; 16]) -> Result<UserVaultKey> {
+    /// Combines the password with the contents of a keyfile, which is fed
+    /// to Argon2id as its secret. An empty password leaves the keyfile
+    /// as the only factor.
+    pub fn with_keyfile(mut self, path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let size = std::fs::metadata(path)
+            .map_err(|e| anyhow!("Could not read the keyfile at {path:?}: {e}"))?
+            .len();
+        if size == 0 || size > MAX_KEYFILE_SIZE {
+            return Err(anyhow!("The keyfile should be between 1 byte and 1 MiB in size."));
+        }
+        self.keyfile = Some(std::fs::read(path)?);
+        self.cache = None;
+        Ok(self)
+    }
+    pub fn is_empty(&self) -> bool {
+        self.password.is_empty()
+    }
+    /// The factors that a key wrapped with this password will need.
+    pub fn factors(&self) -> Factors {
+        Factors {
+            password: !self.password.is_empty() || self.keyfile.is_none(),
+            keyfile: self.keyfile.is_some()
+        }
+    }
+    pub fn get_password(&mut self, test_salt: &[u8; 16], test_params: KdfParams) -> Result<UserVaultKey> {
         Ok(match &mut self.cache {
-            Some(CachedPasswordInner { salt, password }) => {
-                if *salt == *test_salt {
+            Some(CachedPasswordInner { salt, params, password }) => {
+                if *salt == *test_salt && *params == test_params {
                     UserVaultKey {
                         key: *password,
-                        salt: *salt
+                        salt: *salt,
+                        params: *params,
+                        factors: self.factors()
                     }
                 } else {
                     // println!("RESEEDING {}", self.tag);
-                    let new_pass = get_password(&self.password, test_salt)?;
+                    let new_pass = get_password(&self.password, self.keyfile.as_deref(), test_salt, test_params)?;
                     *salt = *test_salt;
+                    *params = test_params;
                     *password = new_pass;
-                    self.get_password(test_salt)?
+                    self.get_password(test_salt, test_params)?
                 }
             }
             None => {
                 // println!("RESEEDING (2) {}", self.tag);
-                let new_pass = get_password(&self.password, test_salt)?;
+                let new_pass = get_password(&self.password, self.keyfile.as_deref(), test_salt, test_params)?;
                 self.cache = Some(CachedPasswordInner {
                     password: new_pass,
-                    salt: *test_salt
+                    salt: *test_salt,
+                    params: test_params
                 });
-                self.get_password(test_salt)?
+                self.get_password(test_salt, test_params)?
             }
         })
     }
@@ -60,32 +100,175 @@ impl CachedPassword {
 #[derive(ZeroizeOnDrop, Clone)]
 struct CachedPasswordInner {
     salt: [u8; 16],
+    #[zeroize(skip)]
+    params: KdfParams,
     password: [u8; 32]
 }
 
+/// The most memory that Argon2id may be asked to use, 4 GiB.
+const MAX_KDF_MEMORY_KIB: u32 = 4 * 1024 * 1024;
+
+/// The most lanes that Argon2id may be asked to use.
+const MAX_KDF_PARALLELISM: u32 = 64;
+
+/// The most memory times passes, in KiB, which is 1024 passes over 64 MiB.
+const MAX_KDF_WORK: u64 = 64 * 1024 * 1024;
+
+/// The Argon2id cost parameters that a password was stretched with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KdfParams {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32
+}
+
+impl KdfParams {
+    /// The parameters every key was wrapped with before they were
+    /// stored alongside the wrapped key.
+    pub const LEGACY: Self = Self {
+        memory_kib: 64 * 1024,
+        iterations: 2,
+        parallelism: 1
+    };
+    /// Builds a set of parameters, checking that Argon2id accepts them and
+    /// that they stay within the limits. The parameters are read from the
+    /// synced state, so they must not be able to stall an unlock.
+    pub fn new(memory_kib: u32, iterations: u32, parallelism: u32) -> Result<Self> {
+        let params = Self {
+            memory_kib,
+            iterations,
+            parallelism
+        };
+        params.to_argon2()?;
+        if memory_kib > MAX_KDF_MEMORY_KIB || parallelism > MAX_KDF_PARALLELISM {
+            return Err(anyhow!("Argon2id parameters may use at most {} MiB and {MAX_KDF_PARALLELISM} lanes.", MAX_KDF_MEMORY_KIB / 1024));
+        }
+        if memory_kib as u64 * iterations as u64 > MAX_KDF_WORK {
+            return Err(anyhow!("Argon2id parameters of {} MiB with {iterations} passes would take too long to derive a key.", memory_kib / 1024));
+        }
+        Ok(params)
+    }
+    /// Whether these cost at least as much as the other parameters.
+    pub fn at_least(&self, other: &Self) -> bool {
+        self.memory_kib >= other.memory_kib && self.iterations >= other.iterations
+    }
+    fn to_argon2(self) -> Result<Params> {
+        Params::new(self.memory_kib, self.iterations, self.parallelism, Some(32))
+            .map_err(|e| anyhow::anyhow!("Failed to initialize Argon2id parameters: {e:?}"))
+    }
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        Self {
+            memory_kib: 64 * 1024,
+            iterations: 3,
+            parallelism: 1
+        }
+    }
+}
+
+impl Display for KdfParams {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{},{},{}", self.memory_kib, self.iterations, self.parallelism)
+    }
+}
+
+impl FromStr for KdfParams {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.split(',').map(u32::from_str);
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(memory_kib), Some(iterations), Some(parallelism), None) => {
+                Self::new(memory_kib?, iterations?, parallelism?)
+            }
+            _ => Err(anyhow!("Argon2id parameters should be of the form 'memory_kib,iterations,parallelism'."))
+        }
+    }
+}
+
+/// Picks the number of iterations that makes a single unlock take
+/// roughly the target time on this machine.
+pub fn calibrate_kdf(target: Duration, memory_kib: u32, parallelism: u32) -> Result<KdfParams> {
+    let probe = KdfParams::new(memory_kib, 1, parallelism)?;
+
+    let start = Instant::now();
+    get_password("novovault calibration", None, &[0u8; 16], probe)?;
+    let single = start.elapsed().max(Duration::from_millis(1));
+
+    // Argon2 scales linearly with the iterations, but we never go below
+    // the minimum of two passes.
+    let iterations = (target.as_secs_f64() / single.as_secs_f64()) as u32;
+    KdfParams::new(memory_kib, iterations.max(2), parallelism)
+}
+
+/// The inputs that have to be supplied to unwrap a key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Factors {
+    pub password: bool,
+    pub keyfile: bool
+}
+
+impl Factors {
+    /// Keys from before factors were recorded only ever used a password.
+    pub const PASSWORD: Self = Self {
+        password: true,
+        keyfile: false
+    };
+    fn to_byte(self) -> u8 {
+        self.password as u8 | (self.keyfile as u8) << 1
+    }
+    fn from_byte(byte: u8) -> Result<Self> {
+        match byte {
+            1..=3 => Ok(Self {
+                password: byte & 1 != 0,
+                keyfile: byte & 2 != 0
+            }),
+            _ => Err(anyhow!("Wrapped key needs an unknown set of factors ({byte}).")),
+        }
+    }
+}
+
+impl Display for Factors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.password, self.keyfile) {
+            (true, true) => write!(f, "a password and a keyfile"),
+            (false, true) => write!(f, "a keyfile"),
+            _ => write!(f, "a password")
+        }
+    }
+}
+
 #[derive(ZeroizeOnDrop)]
 pub struct UserVaultKey {
     key: [u8; 32],
-    salt: [u8; 16]
+    salt: [u8; 16],
+    #[zeroize(skip)]
+    params: KdfParams,
+    #[zeroize(skip)]
+    factors: Factors
 }
 
 impl UserVaultKey {
-    pub fn init_with_salt(password: &mut CachedPassword, salt: &[u8; 16]) -> Result<Self> {
-        password.get_password(salt)
+    pub fn init_with_salt(password: &mut CachedPassword, salt: &[u8; 16], params: KdfParams) -> Result<Self> {
+        password.get_password(salt, params)
     }
-    pub fn init_fresh(password: &mut CachedPassword) -> Result<Self> {
+    pub fn init_fresh(password: &mut CachedPassword, params: KdfParams) -> Result<Self> {
         let mut salt = [0u8; 16];
         rand::fill(&mut salt);
-        Self::init_with_salt(password, &salt)
+        Self::init_with_salt(password, &salt, params)
     }
 
     // pub fn init_raw(key: [u8; 16])
 }
 
-fn get_password(passphrase: &str, salt: &[u8]) -> Result<[u8; 32]> {
-    let params = Params::new(64 * 1024, 2, 1, Some(32))
-        .map_err(|e| anyhow::anyhow!("Failed to initialize Argon2id parameters: {e:?}"))?;
-    let argon2 = Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params);
+fn get_password(passphrase: &str, keyfile: Option<&[u8]>, salt: &[u8], params: KdfParams) -> Result<[u8; 32]> {
+    let argon2 = match keyfile {
+        Some(secret) => Argon2::new_with_secret(secret, argon2::Algorithm::Argon2id, argon2::Version::V0x13, params.to_argon2()?)
+            .map_err(|e| anyhow!("Failed to use the keyfile as an Argon2id secret: {e:?}"))?,
+        None => Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params.to_argon2()?)
+    };
 
     let mut out = [0u8; 32];
     argon2.hash_password_into(passphrase.as_bytes(), salt, &mut out)
@@ -106,13 +289,25 @@ impl MasterVaultKey {
             key: rand::random()
         }
     }
+    pub fn from_bytes(key: [u8; 32]) -> Self {
+        Self {
+            key
+        }
+    }
     pub fn key_bytes(&self) -> &[u8; 32] {
         &self.key
     }
 }
 
+/// The nonce, salt and encrypted master key (with its tag).
+const LEGACY_WRAPPED_SIZE: usize = 24 + 16 + 32 + 16;
+
 #[derive(ZeroizeOnDrop, Clone)]
 pub struct WrappedKey {
+    #[zeroize(skip)]
+    params: KdfParams,
+    #[zeroize(skip)]
+    factors: Factors,
     salt: [u8; 16],
     nonce: [u8; 24],
     payload: Vec<u8>
@@ -123,8 +318,11 @@ impl WrappedKey {
         generate_wrapped_mk(user, master)
     }
     pub fn get_master_key_with_no_rewrap(&self, passphrase: &mut CachedPassword) -> Result<MasterVaultKey> {
-        
-        let key_phrase = Zeroizing::new(passphrase.get_password(&self.salt)?.key);
+        if passphrase.factors() != self.factors {
+            return Err(anyhow!("This key needs {} to unlock.", self.factors));
+        }
+
+        let key_phrase = Zeroizing::new(passphrase.get_password(&self.salt, self.params)?.key);
         
         // let key_phrase = get_password(passphrase, &self.salt)?;
         
@@ -143,26 +341,61 @@ impl WrappedKey {
 
         Ok(new_master)
     }
-    pub fn get_master_key(&self, passphrase: &mut CachedPassword) -> Result<(Self, MasterVaultKey)> {
+    pub fn get_master_key(&self, passphrase: &mut CachedPassword, params: KdfParams) -> Result<(Self, MasterVaultKey)> {
 
         let new_master = self.get_master_key_with_no_rewrap(passphrase)?;
-        Ok((WrappedKey::init(&UserVaultKey::init_fresh(passphrase)?, &new_master)?, new_master
+        Ok((WrappedKey::init(&UserVaultKey::init_fresh(passphrase, params)?, &new_master)?, new_master
     ))
 
+    }
+    /// Wraps the same master key under a new password, the old
+    /// password has to unlock this key first.
+    pub fn rewrap(&self, old: &mut CachedPassword, new: &mut CachedPassword, params: KdfParams) -> Result<Self> {
+        let master = self.get_master_key_with_no_rewrap(old)?;
+        WrappedKey::init(&UserVaultKey::init_fresh(new, params)?, &master)
+    }
+    pub fn params(&self) -> KdfParams {
+        self.params
+    }
+    pub fn factors(&self) -> Factors {
+        self.factors
     }
     pub fn from_hex(string: &str) -> Result<Self> {
 
         let mut nonce = hex::decode(string)?;
 
-        if nonce.len() <= 16 + 24 + 32 {
-            return Err(anyhow!("Wrapped key is of the incorrect size."));
-        }
+        // Keys from before the parameters were stored are exactly this
+        // long, the newer ones lead with a version byte and the parameters.
+        let (params, factors) = if nonce.len() == LEGACY_WRAPPED_SIZE {
+            (KdfParams::LEGACY, Factors::PASSWORD)
+        } else {
+            // The first version only added the parameters, the second
+            // records the factors after them.
+            let header_size = match nonce.first() {
+                Some(1) => 13,
+                Some(2) => 14,
+                _ => return Err(anyhow!("Wrapped key is of an unknown version."))
+            };
+            if nonce.len() != LEGACY_WRAPPED_SIZE + header_size {
+                return Err(anyhow!("Wrapped key is of the incorrect size."));
+            }
+            let header = nonce.drain(..header_size).collect::<Vec<_>>();
+            let field = |i: usize| u32::from_le_bytes(header[i..i + 4].try_into().unwrap());
+            let params = KdfParams::new(field(1), field(5), field(9))?;
+            let factors = match header_size {
+                14 => Factors::from_byte(header[13])?,
+                _ => Factors::PASSWORD
+            };
+            (params, factors)
+        };
 
         let mut salt = nonce.split_off(24);
         let payload = salt.split_off(16);
 
 
         Ok(Self {
+            params,
+            factors,
             salt: salt.try_into()
                 .unwrap(),
             nonce: nonce.try_into()
@@ -176,7 +409,12 @@ impl WrappedKey {
 
     }
     pub fn to_hex(&self) -> String {
-        let mut buffer = Vec::with_capacity(16 + 24 + 32);
+        let mut buffer = Vec::with_capacity(14 + LEGACY_WRAPPED_SIZE);
+        buffer.push(2);
+        buffer.extend_from_slice(&self.params.memory_kib.to_le_bytes());
+        buffer.extend_from_slice(&self.params.iterations.to_le_bytes());
+        buffer.extend_from_slice(&self.params.parallelism.to_le_bytes());
+        buffer.push(self.factors.to_byte());
         buffer.extend_from_slice(&self.nonce);
         
         buffer.extend_from_slice(&self.salt);
@@ -186,6 +424,135 @@ impl WrappedKey {
 }
 
 
+/// The label given to the slot of a freshly initialized vault, and to
+/// the single wrapped key of vaults made before key slots existed.
+pub const DEFAULT_SLOT: &str = "default";
+
+/// A wrapped copy of the master key along with a label
+/// identifying who it belongs to.
+#[derive(Clone)]
+pub struct KeySlot {
+    pub label: String,
+    pub key: WrappedKey
+}
+
+/// Every wrapped copy of the master key, any one of them
+/// can unlock the vault.
+#[derive(Clone, Default)]
+pub struct KeySlots(Vec<KeySlot>);
+
+impl KeySlots {
+    pub fn new(label: &str, key: WrappedKey) -> Self {
+        Self(vec![KeySlot { label: label.to_string(), key }])
+    }
+    pub fn iter(&self) -> impl Iterator<Item = &KeySlot> {
+        self.0.iter()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+    /// Tries the password against every slot, returning the index of
+    /// the slot it opened along with the master key.
+    pub fn unlock(&self, passphrase: &mut CachedPassword) -> Result<(usize, MasterVaultKey)> {
+        for (index, slot) in self.0.iter().enumerate() {
+            if let Ok(master) = slot.key.get_master_key_with_no_rewrap(passphrase) {
+                return Ok((index, master));
+            }
+        }
+        // Point out a missing keyfile rather than blaming the password.
+        if passphrase.keyfile.is_none() && self.0.iter().all(|s| s.key.factors().keyfile) {
+            return Err(anyhow!("This vault needs a keyfile to unlock, pass it with --keyfile."));
+        }
+        Err(anyhow!("Failed to decrypt master key with provided password."))
+    }
+    /// Unlocks the vault and rewraps the slot that was used with a fresh salt.
+    /// The slot keeps its own parameters unless the given ones are stronger.
+    pub fn unlock_and_rewrap(&mut self, passphrase: &mut CachedPassword, params: KdfParams) -> Result<MasterVaultKey> {
+        let (index, _) = self.unlock(passphrase)?;
+        let (rewrapped, master) = self.0[index].key.get_master_key(passphrase, self.0[index].key.params())?;
+        self.0[index].key = rewrapped;
+        self.upgrade(index, passphrase, params)?;
+        Ok(master)
+    }
+    /// Rewraps a slot if the given parameters are stronger than the ones it
+    /// was made with, returning whether anything changed. The parameters come
+    /// from the synced state, so a slot is never moved to weaker ones.
+    pub fn upgrade(&mut self, index: usize, passphrase: &mut CachedPassword, params: KdfParams) -> Result<bool> {
+        let current = self.0[index].key.params();
+        if current == params || !params.at_least(&current) {
+            return Ok(false);
+        }
+        let (rewrapped, _) = self.0[index].key.get_master_key(passphrase, params)?;
+        self.0[index].key = rewrapped;
+        Ok(true)
+    }
+    /// Changes the password of whichever slot the old password opens.
+    pub fn change_password(&mut self, old: &mut CachedPassword, new: &mut CachedPassword, params: KdfParams) -> Result<&str> {
+        let (index, _) = self.unlock(old)?;
+        self.0[index].key = self.0[index].key.rewrap(old, new, params)?;
+        Ok(&self.0[index].label)
+    }
+    pub fn add(&mut self, label: &str, key: WrappedKey) -> Result<()> {
+        check_slot_label(label)?;
+        self.insert(label, key)
+    }
+    /// Adds a slot that was read back or carried over, unlike `add`
+    /// this accepts the reserved labels.
+    pub fn insert(&mut self, label: &str, key: WrappedKey) -> Result<()> {
+        check_label_characters(label)?;
+        if self.0.iter().any(|s| s.label == label) {
+            return Err(anyhow!("There is already a key slot labelled '{label}'."));
+        }
+        self.0.push(KeySlot { label: label.to_string(), key });
+        Ok(())
+    }
+    /// Adds a slot, replacing any existing slot with the same label.
+    pub fn set(&mut self, label: &str, key: WrappedKey) -> Result<()> {
+        check_slot_label(label)?;
+        self.replace(label, key);
+        Ok(())
+    }
+    /// Sets the slot that the recovery phrase unlocks.
+    pub fn set_recovery(&mut self, key: WrappedKey) {
+        self.replace(RECOVERY_SLOT, key);
+    }
+    fn replace(&mut self, label: &str, key: WrappedKey) {
+        match self.0.iter_mut().find(|s| s.label == label) {
+            Some(slot) => slot.key = key,
+            None => self.0.push(KeySlot { label: label.to_string(), key })
+        }
+    }
+    pub fn remove(&mut self, label: &str) -> Result<()> {
+        let Some(index) = self.0.iter().position(|s| s.label == label) else {
+            return Err(anyhow!("There is no key slot labelled '{label}'."));
+        };
+        if self.0.len() == 1 {
+            return Err(anyhow!("Refusing to remove the last key slot, the vault would become impossible to unlock."));
+        }
+        self.0.remove(index);
+        Ok(())
+    }
+}
+
+/// Checks a label that a user picked for a slot, the labels that
+/// novovault manages itself are off limits.
+pub fn check_slot_label(label: &str) -> Result<()> {
+    check_label_characters(label)?;
+    if label == RECOVERY_SLOT {
+        return Err(anyhow!("The key slot label '{label}' is reserved for the recovery phrase."));
+    }
+    Ok(())
+}
+
+/// Slot labels end up as keys in the state file, so they are
+/// restricted to a safe set of characters.
+fn check_label_characters(label: &str) -> Result<()> {
+    if label.is_empty() || !label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(anyhow!("Key slot labels may only contain letters, digits, '-' and '_'."));
+    }
+    Ok(())
+}
+
 fn generate_wrapped_mk(
     rkey: &UserVaultKey,
     master: &MasterVaultKey
@@ -203,6 +570,8 @@ fn generate_wrapped_mk(
         .map_err(|_| anyhow!("Failed to encrypt the new wrapped key."))?;
 
     Ok(WrappedKey {
+        params: rkey.params,
+        factors: rkey.factors,
         salt: rkey.salt,
         nonce: nbytes,
         payload: result
@@ -212,21 +581,28 @@ fn generate_wrapped_mk(
 
 #[cfg(test)]
 mod tests {
-    use crate::sys::mk::{CachedPassword, MasterVaultKey, UserVaultKey, WrappedKey};
+    use crate::sys::mk::{CachedPassword, KdfParams, KeySlots, MasterVaultKey, UserVaultKey, WrappedKey};
+
+    /// Cheap parameters so that the tests stay quick.
+    const FAST: KdfParams = KdfParams {
+        memory_kib: 8 * 1024,
+        iterations: 1,
+        parallelism: 1
+    };
 
 
     #[test]
     pub fn check_password_match() {
         let salt = [0u8; 16];
-        let password = UserVaultKey::init_with_salt(&mut CachedPassword::from_string("hello".to_string()), &salt).unwrap();
-        let pass2 = UserVaultKey::init_with_salt(&mut CachedPassword::from_string("hello".to_string()), &salt).unwrap();
+        let password = UserVaultKey::init_with_salt(&mut CachedPassword::from_string("hello".to_string()), &salt, KdfParams::LEGACY).unwrap();
+        let pass2 = UserVaultKey::init_with_salt(&mut CachedPassword::from_string("hello".to_string()), &salt, KdfParams::LEGACY).unwrap();
         assert_eq!(pass2.key, password.key);
     }
 
     #[test]
     pub fn check_wrapped_key() {
         let salt = [0u8; 16];
-        let password = UserVaultKey::init_with_salt(&mut CachedPassword::from_string("helo".to_string()), &salt)
+        let password = UserVaultKey::init_with_salt(&mut CachedPassword::from_string("helo".to_string()), &salt, KdfParams::LEGACY)
             .unwrap();
 
         let master = MasterVaultKey::generate();
@@ -235,12 +611,108 @@ mod tests {
         let wrapped = WrappedKey::init(&password, &master).unwrap();
 
 
-        let (new_wrap, master_dev) = wrapped.get_master_key(&mut CachedPassword::from_string("helo".to_string())).unwrap();
+        let (new_wrap, master_dev) = wrapped.get_master_key(&mut CachedPassword::from_string("helo".to_string()), KdfParams::LEGACY).unwrap();
         assert_eq!(master_dev.key, master.key);
 
-        let (_, master_dev) = new_wrap.get_master_key(&mut CachedPassword::from_string("helo".to_string())).unwrap();
+        let (_, master_dev) = new_wrap.get_master_key(&mut CachedPassword::from_string("helo".to_string()), KdfParams::LEGACY).unwrap();
         assert_eq!(master_dev.key, master.key);
 
 
     }
-}
\ No newline at end of file
+
+    #[test]
+    pub fn check_rewrap() {
+        let master = MasterVaultKey::generate();
+        let wrapped = WrappedKey::init(&UserVaultKey::init_fresh(&mut CachedPassword::from_string("old".to_string()), FAST).unwrap(), &master).unwrap();
+
+        assert!(wrapped.rewrap(&mut CachedPassword::from_string("wrong".to_string()), &mut CachedPassword::from_string("new".to_string()), FAST).is_err());
+
+        let rewrapped = wrapped.rewrap(&mut CachedPassword::from_string("old".to_string()), &mut CachedPassword::from_string("new".to_string()), FAST).unwrap();
+        assert!(rewrapped.get_master_key_with_no_rewrap(&mut CachedPassword::from_string("old".to_string())).is_err());
+
+        let master_dev = rewrapped.get_master_key_with_no_rewrap(&mut CachedPassword::from_string("new".to_string())).unwrap();
+        assert_eq!(master_dev.key, master.key);
+    }
+
+    #[test]
+    pub fn check_key_slots() {
+        let master = MasterVaultKey::generate();
+        let wrap = |pass: &str| WrappedKey::init(&UserVaultKey::init_fresh(&mut CachedPassword::from_string(pass.to_string()), FAST).unwrap(), &master).unwrap();
+
+        let mut slots = KeySlots::new("alice", wrap("a"));
+        slots.add("bob", wrap("b")).unwrap();
+        assert!(slots.add("bob", wrap("c")).is_err());
+        assert!(slots.add("not=valid", wrap("c")).is_err());
+        // The recovery slot can only be set through the recovery phrase.
+        assert!(slots.add("recovery", wrap("c")).is_err());
+        assert!(slots.set("recovery", wrap("c")).is_err());
+        slots.insert("recovery", wrap("c")).unwrap();
+        slots.remove("recovery").unwrap();
+
+        let (index, master_dev) = slots.unlock(&mut CachedPassword::from_string("b".to_string())).unwrap();
+        assert_eq!(index, 1);
+        assert_eq!(master_dev.key, master.key);
+
+        slots.remove("bob").unwrap();
+        assert!(slots.unlock(&mut CachedPassword::from_string("b".to_string())).is_err());
+        assert!(slots.remove("alice").is_err());
+    }
+
+    #[test]
+    pub fn check_params_stored_and_upgraded() {
+        let master = MasterVaultKey::generate();
+        let mut password = CachedPassword::from_string("pass".to_string());
+
+        // A key from before the parameters were stored has no header.
+        let legacy = WrappedKey::init(&UserVaultKey::init_fresh(&mut password, KdfParams::LEGACY).unwrap(), &master).unwrap();
+        let legacy_hex = legacy.to_hex()[28..].to_string();
+        assert_eq!(WrappedKey::from_hex(&legacy_hex).unwrap().params(), KdfParams::LEGACY);
+
+        let stronger = KdfParams::new(64 * 1024, 3, 1).unwrap();
+        let mut slots = KeySlots::new("default", WrappedKey::from_hex(&legacy_hex).unwrap());
+        assert!(slots.upgrade(0, &mut password, stronger).unwrap());
+        assert!(!slots.upgrade(0, &mut password, stronger).unwrap());
+
+        let upgraded = WrappedKey::from_hex(&slots.iter().next().unwrap().key.to_hex()).unwrap();
+        assert_eq!(upgraded.params(), stronger);
+        assert_eq!(upgraded.get_master_key_with_no_rewrap(&mut password).unwrap().key, master.key);
+
+        // The slot is never rewrapped with weaker parameters.
+        assert!(!slots.upgrade(0, &mut password, FAST).unwrap());
+        assert_eq!(slots.iter().next().unwrap().key.params(), stronger);
+
+        assert_eq!("8192,1,1".parse::<KdfParams>().unwrap(), FAST);
+        assert!("8192,1".parse::<KdfParams>().is_err());
+        assert!("65536,4096,1".parse::<KdfParams>().is_err());
+        assert!("8388608,1,1".parse::<KdfParams>().is_err());
+
+        // A tampered key can not ask for an unbounded amount of work.
+        let mut tampered = upgraded.clone();
+        tampered.params.iterations = u32::MAX;
+        assert!(WrappedKey::from_hex(&tampered.to_hex()).is_err());
+    }
+
+    #[test]
+    pub fn check_keyfile_factors() {
+        let tmp = tempfile::tempdir().unwrap();
+        let keyfile = tmp.path().join("keyfile");
+        std::fs::write(&keyfile, [42u8; 64]).unwrap();
+
+        let master = MasterVaultKey::generate();
+        let both = || CachedPassword::from_string("pass".to_string()).with_keyfile(&keyfile).unwrap();
+        let wrapped = WrappedKey::init(&UserVaultKey::init_fresh(&mut both(), FAST).unwrap(), &master).unwrap();
+
+        let wrapped = WrappedKey::from_hex(&wrapped.to_hex()).unwrap();
+        assert!(wrapped.factors().password && wrapped.factors().keyfile);
+        assert_eq!(wrapped.get_master_key_with_no_rewrap(&mut both()).unwrap().key, master.key);
+
+        // Either factor alone is not enough.
+        assert!(wrapped.get_master_key_with_no_rewrap(&mut CachedPassword::from_string("pass".to_string())).is_err());
+        let mut keyfile_only = CachedPassword::from_string(String::new()).with_keyfile(&keyfile).unwrap();
+        assert!(wrapped.get_master_key_with_no_rewrap(&mut keyfile_only).is_err());
+
+        let slots = KeySlots::new("default", wrapped);
+        let err = slots.unlock(&mut CachedPassword::from_string("pass".to_string())).err().unwrap();
+        assert!(err.to_string().contains("keyfile"));
+    }
+}
@@ -2,8 +2,21 @@ pub mod common;
 pub mod init;
 pub mod filter;
 pub mod writer;
+pub mod compression;
+pub mod metadata;
+pub mod objects;
+pub mod stream;
+pub mod parity;
+pub mod shards;
+pub mod generation;
+pub mod header;
 pub mod statefile;
+pub mod lock;
+pub mod fault;
 pub mod mk;
+pub mod recovery;
+pub mod recipient;
+pub mod signature;
 pub mod process;
 pub mod procedure;
 pub mod lib;
\ No newline at end of file
@@ -0,0 +1,647 @@
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{Cursor, Read, Seek, SeekFrom},
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{Result, anyhow};
+use fastcdc::v2020::{Normalization, StreamCDC};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use walkdir::WalkDir;
+use zeroize::Zeroizing;
+
+use crate::{
+    console_log,
+    sys::{
+        compression::{CompressionPolicy, decompress},
+        fault::checkpoint,
+        header::CompressionId,
+        metadata::{FileMetadata, MetadataRestorer, create_symlink},
+        mk::MasterVaultKey,
+        stream::Padding,
+        writer::{VaultReader, read_generation, reencrypt, write_stream},
+    },
+};
+
+/// Marks the plaintext of a vault binary as a manifest rather than a zip archive.
+pub const MANIFEST_MAGIC: &[u8; 13] = b"NOVOMANIFEST\n";
+
+/// Binds the object naming key to its purpose.
+const OBJECT_ID_INFO: &[u8] = b"novovault object id";
+
+/// Binds the chunking seed to its purpose.
+const CHUNK_SEED_INFO: &[u8] = b"novovault chunk seed";
+
+/// The length of an object id, a hex encoded HMAC-SHA256.
+const OBJECT_ID_SIZE: usize = 64;
+
+/// The bounds on the size of a chunk, files smaller than the
+/// minimum are always stored as a single chunk.
+const MIN_CHUNK_SIZE: u32 = 64 * 1024;
+const AVG_CHUNK_SIZE: u32 = 256 * 1024;
+const MAX_CHUNK_SIZE: u32 = 1024 * 1024;
+
+/// A path in the vault, directories and symlinks have no chunks.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    pub path: String,
+    /// The whole file as a single object, this is how files were
+    /// stored before they were chunked.
+    #[serde(default, skip_serializing)]
+    object: Option<String>,
+    /// The objects that the file is made of, in order.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunks: Option<Vec<String>>,
+    /// The codec that every chunk of the file was compressed with.
+    #[serde(default = "stored", skip_serializing_if = "is_stored")]
+    pub compression: CompressionId,
+    #[serde(flatten)]
+    pub metadata: FileMetadata,
+}
+
+fn stored() -> CompressionId {
+    CompressionId::Stored
+}
+
+fn is_stored(compression: &CompressionId) -> bool {
+    *compression == CompressionId::Stored
+}
+
+impl ManifestEntry {
+    pub fn new(path: String, chunks: Option<Vec<String>>, compression: CompressionId, metadata: FileMetadata) -> Self {
+        Self {
+            path,
+            object: None,
+            chunks,
+            compression,
+            metadata,
+        }
+    }
+}
+
+/// Maps every path in the vault to the object holding its contents, this
+/// is what gets encrypted into the vault binary in the object layout.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    /// How the objects were padded, this is kept when they are rewritten.
+    #[serde(default)]
+    pub padding: Padding,
+    #[serde(default)]
+    pub entries: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut bytes = MANIFEST_MAGIC.to_vec();
+        bytes.extend_from_slice(toml::to_string(self)?.as_bytes());
+        Ok(bytes)
+    }
+    /// Reads the manifest out of a decrypted vault, if the vault holds a zip
+    /// archive instead this returns nothing and rewinds the reader.
+    pub fn read(vault: &mut (impl Read + Seek)) -> Result<Option<Self>> {
+        let mut magic = [0u8; MANIFEST_MAGIC.len()];
+        if vault.read_exact(&mut magic).is_err() || magic != *MANIFEST_MAGIC {
+            vault.seek(SeekFrom::Start(0))?;
+            return Ok(None);
+        }
+
+        let mut string = String::new();
+        vault.read_to_string(&mut string)?;
+        let mut manifest: Self = toml::from_str(&string)
+            .map_err(|e| anyhow!("The manifest of the vault is corrupted: {e}"))?;
+
+        // A whole file object is read as a file of one chunk.
+        for entry in &mut manifest.entries {
+            if let Some(object) = entry.object.take() {
+                entry.chunks = Some(vec![object]);
+            }
+        }
+        Ok(Some(manifest))
+    }
+    /// Every object that the manifest refers to.
+    pub fn objects(&self) -> HashSet<&str> {
+        self.entries
+            .iter()
+            .filter_map(|e| e.chunks.as_ref())
+            .flatten()
+            .map(String::as_str)
+            .collect()
+    }
+}
+
+/// Feeds everything that is read through the keyed hash.
+struct HashingReader<R: Read> {
+    inner: R,
+    mac: Hmac<Sha256>,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.mac.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+/// Reads a file back out of its chunks, each chunk is verified as it is opened.
+struct ChunkReader<'s, 'a> {
+    store: &'s ObjectStore<'a>,
+    chunks: std::slice::Iter<'s, String>,
+    compression: CompressionId,
+    current: Option<Box<dyn Read>>,
+}
+
+impl Read for ChunkReader<'_, '_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            if let Some(current) = &mut self.current {
+                let read = current.read(buf)?;
+                if read > 0 || buf.is_empty() {
+                    return Ok(read);
+                }
+            }
+            match self.chunks.next() {
+                Some(id) => {
+                    let chunk = self.store.open_verified(id, self.compression).map_err(std::io::Error::other)?;
+                    self.current = Some(chunk);
+                }
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+/// A folder of encrypted objects, each named by a keyed hash of its
+/// plaintext so identical contents are only ever stored once.
+///
+/// Files are split into chunks along boundaries picked by their contents,
+/// so an edit or a rename only produces the chunks that actually changed.
+/// Both the naming key and the chunking seed are derived from the master
+/// key, so neither the names nor the sizes of the chunks can be matched
+/// against known files by anyone without it.
+pub struct ObjectStore<'a> {
+    folder: PathBuf,
+    master: &'a MasterVaultKey,
+    id_key: Zeroizing<[u8; 32]>,
+    seed: u64,
+    padding: Padding,
+}
+
+impl<'a> ObjectStore<'a> {
+    pub fn new(folder: impl AsRef<Path>, master: &'a MasterVaultKey) -> Result<Self> {
+        let mut id_key = Zeroizing::new([0u8; 32]);
+        Hkdf::<Sha256>::new(None, master.key_bytes())
+            .expand(OBJECT_ID_INFO, &mut id_key[..])
+            .map_err(|_| anyhow!("Failed to derive the object naming key."))?;
+
+        let mut seed = [0u8; 8];
+        Hkdf::<Sha256>::new(None, master.key_bytes())
+            .expand(CHUNK_SEED_INFO, &mut seed)
+            .map_err(|_| anyhow!("Failed to derive the chunking seed."))?;
+        Ok(Self {
+            folder: folder.as_ref().to_path_buf(),
+            master,
+            id_key,
+            seed: u64::from_le_bytes(seed),
+            padding: Padding::None,
+        })
+    }
+    /// Pads every object that is written from here on.
+    pub fn with_padding(mut self, padding: Padding) -> Self {
+        self.padding = padding;
+        self
+    }
+    /// The codec goes into the hash, so the same contents compressed
+    /// differently are separate objects.
+    fn hashing<R: Read>(&self, inner: R, compression: CompressionId) -> Result<HashingReader<R>> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.id_key[..])
+            .map_err(|_| anyhow!("Failed to create the object hash."))?;
+        if compression != CompressionId::Stored {
+            mac.update(&[b'c', compression as u8]);
+        }
+        Ok(HashingReader { inner, mac })
+    }
+    /// The id of an object with these contents.
+    pub fn object_id(&self, contents: &mut impl Read, compression: CompressionId) -> Result<String> {
+        let mut hashing = self.hashing(contents, compression)?;
+        std::io::copy(&mut hashing, &mut std::io::sink())?;
+        Ok(hex::encode(hashing.mac.finalize().into_bytes()))
+    }
+    /// Objects are fanned out by the first byte of their id.
+    fn object_path(&self, id: &str) -> Result<PathBuf> {
+        if id.len() != OBJECT_ID_SIZE || !id.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+            return Err(anyhow!("The object id '{id}' is malformed."));
+        }
+        Ok(self.folder.join(&id[..2]).join(&id[2..]))
+    }
+    /// Stores the contents as an object unless it is already there, returning
+    /// the id and whether anything was written.
+    pub fn put(&self, contents: &[u8], compression: CompressionId, policy: &CompressionPolicy) -> Result<(String, bool)> {
+        let id = self.object_id(&mut Cursor::new(contents), compression)?;
+        let path = self.object_path(&id)?;
+        if path.exists() {
+            return Ok((id, false));
+        }
+
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        // The object is written to the side so that a half written one is
+        // never mistaken for the real thing.
+        let temp = path.with_extension("tmp");
+        let compressed = policy.compress(compression, contents)?;
+        write_stream(&temp, self.master.key_bytes(), "", 0, compression, self.padding, &mut Cursor::new(compressed))?;
+        std::fs::rename(&temp, &path)?;
+        Ok((id, true))
+    }
+    /// Splits the contents into chunks and stores each of them, returning
+    /// the ids in order and how many chunks had to be written.
+    pub fn put_chunked(
+        &self,
+        contents: impl Read,
+        compression: CompressionId,
+        policy: &CompressionPolicy,
+    ) -> Result<(Vec<String>, usize)> {
+        let chunker = StreamCDC::with_level_and_seed(
+            contents,
+            MIN_CHUNK_SIZE,
+            AVG_CHUNK_SIZE,
+            MAX_CHUNK_SIZE,
+            Normalization::Level1,
+            self.seed,
+        );
+
+        let mut ids = vec![];
+        let mut written = 0;
+        for chunk in chunker {
+            let chunk = chunk.map_err(|e| anyhow!("Failed to split the file into chunks: {e}"))?;
+            let (id, new) = self.put(&chunk.data, compression, policy)?;
+            if new {
+                written += 1;
+            }
+            ids.push(id);
+        }
+        Ok((ids, written))
+    }
+    /// The contents of a file that was stored with the given chunks.
+    fn read_chunks<'s>(&'s self, chunks: &'s [String], compression: CompressionId) -> ChunkReader<'s, 'a> {
+        ChunkReader {
+            store: self,
+            chunks: chunks.iter(),
+            compression,
+            current: None,
+        }
+    }
+    /// Authenticates an object and checks that it is the one the id names,
+    /// returning its decompressed contents.
+    pub fn open_verified(&self, id: &str, compression: CompressionId) -> Result<Box<dyn Read>> {
+        let path = self.object_path(id)?;
+        if !path.exists() {
+            return Err(anyhow!("The object {id} is missing from the object store."));
+        }
+        let mut reader = VaultReader::open(&path, self.master.key_bytes())
+            .map_err(|e| anyhow!("Failed to open the object {id}: {e}"))?;
+        reader.verify()
+            .map_err(|e| anyhow!("The object {id} is corrupted: {e}"))?;
+        reader.seek(SeekFrom::Start(0))?;
+
+        // A valid object under the wrong name would otherwise pass.
+        let check = |actual: String| {
+            if actual != id {
+                return Err(anyhow!("The object {id} does not hold the contents it is named after."));
+            }
+            Ok(())
+        };
+
+        // Stored objects may be whole files, so these are streamed rather
+        // than held in memory, compressed ones are single chunks.
+        if compression == CompressionId::Stored {
+            check(self.object_id(&mut reader, compression)?)?;
+            reader.seek(SeekFrom::Start(0))?;
+            return Ok(Box::new(reader));
+        }
+
+        let mut compressed = vec![];
+        reader.read_to_end(&mut compressed)?;
+        let contents = decompress(compression, &compressed)
+            .map_err(|e| anyhow!("The object {id} could not be decompressed: {e}"))?;
+        check(self.object_id(&mut contents.as_slice(), compression)?)?;
+        Ok(Box::new(Cursor::new(contents)))
+    }
+    /// Checks every object in the manifest before anything is expanded.
+    pub fn verify(&self, manifest: &Manifest) -> Result<()> {
+        let mut checked = HashSet::new();
+        for entry in &manifest.entries {
+            for id in entry.chunks.iter().flatten() {
+                if checked.insert(id.as_str()) {
+                    self.open_verified(id, entry.compression)?;
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Writes out every path in the manifest underneath the target.
+    pub fn expand(&self, manifest: &Manifest, target: &Path) -> Result<()> {
+        let mut restorer = MetadataRestorer::default();
+        for entry in &manifest.entries {
+            let Some(rel_path) = enclosed_name(&entry.path) else {
+                console_log!(Warn, "Skipping the manifest entry {:?} as it leaves the vault.", entry.path);
+                continue;
+            };
+            let out_path = target.join(rel_path);
+
+            match (&entry.metadata.symlink, &entry.chunks) {
+                (Some(link), _) => {
+                    if let Some(parent) = out_path.parent() {
+                        std::fs::create_dir_all(parent)?;
+                    }
+                    create_symlink(link, &out_path)?;
+                }
+                (None, None) => std::fs::create_dir_all(&out_path).inspect_err(|e| {
+                    console_log!(Error, "Failed creating a directory (path={out_path:?}): {e:?}");
+                })?,
+                (None, Some(chunks)) => {
+                    if let Some(parent) = out_path.parent() {
+                        std::fs::create_dir_all(parent)?;
+                    }
+                    let mut reader = self.read_chunks(chunks, entry.compression);
+                    let mut outfile = File::create(&out_path).inspect_err(|e| {
+                        console_log!(Error, "Failed creating a file (path={out_path:?}): {e:?}");
+                    })?;
+                    std::io::copy(&mut reader, &mut outfile)?;
+                }
+            }
+            restorer.restore(&out_path, entry.metadata.clone())?;
+            checkpoint("expanding a file")?;
+        }
+        restorer.finish()
+    }
+    /// Deletes every object that is not kept, along with any left over
+    /// temporary files. Returns the number of files removed.
+    pub fn collect_garbage(&self, keep: &HashSet<&str>) -> Result<usize> {
+        if !self.folder.exists() {
+            return Ok(0);
+        }
+
+        let mut removed = 0;
+        for file in WalkDir::new(&self.folder).min_depth(2).max_depth(2) {
+            let file = file?;
+            let fan = file.path().parent().and_then(|p| p.file_name()).unwrap_or_default();
+            let id = format!("{}{}", fan.to_string_lossy(), file.file_name().to_string_lossy());
+            if !keep.contains(id.as_str()) {
+                std::fs::remove_file(file.path())?;
+                removed += 1;
+            }
+        }
+
+        for fan in std::fs::read_dir(&self.folder)? {
+            let fan = fan?.path();
+            if fan.is_dir() && std::fs::read_dir(&fan)?.next().is_none() {
+                std::fs::remove_dir(&fan)?;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+/// Builds up the object store and its manifest during a seal.
+pub struct ObjectWriter<'a> {
+    store: ObjectStore<'a>,
+    compression: CompressionPolicy,
+    manifest: Manifest,
+    written: usize,
+}
+
+impl<'a> ObjectWriter<'a> {
+    pub fn new(
+        folder: impl AsRef<Path>,
+        master: &'a MasterVaultKey,
+        compression: CompressionPolicy,
+        padding: Padding,
+    ) -> Result<Self> {
+        Ok(Self {
+            store: ObjectStore::new(folder, master)?.with_padding(padding),
+            compression,
+            manifest: Manifest {
+                padding,
+                entries: vec![],
+            },
+            written: 0,
+        })
+    }
+    pub fn write_path(&mut self, path: &Path, name: &Path, metadata: &FileMetadata) -> Result<()> {
+        let (chunks, codec) = if !metadata.is_symlink() && path.is_file() {
+            let codec = self.compression.choose(path)?;
+            let (ids, written) = self.store.put_chunked(File::open(path)?, codec, &self.compression)?;
+            self.written += written;
+            (Some(ids), codec)
+        } else {
+            (None, CompressionId::Stored)
+        };
+        self.manifest
+            .entries
+            .push(ManifestEntry::new(name.to_string_lossy().replace('\\', "/"), chunks, codec, metadata.clone()));
+        Ok(())
+    }
+    /// Encrypts the manifest into the vault binary and then drops any
+    /// objects that are no longer referenced.
+    pub fn finish(self, target: impl AsRef<Path>, key_block: &str, generation: u64) -> Result<()> {
+        write_stream(
+            target,
+            self.store.master.key_bytes(),
+            key_block,
+            generation,
+            CompressionId::Stored,
+            self.manifest.padding,
+            &mut Cursor::new(self.manifest.to_bytes()?),
+        )?;
+
+        let total = self.manifest.objects().len();
+        let removed = self.store.collect_garbage(&self.manifest.objects())?;
+        console_log!(
+            Info,
+            "Wrote {} new chunks, {} were unchanged and {removed} were removed.",
+            self.written,
+            total.saturating_sub(self.written)
+        );
+        Ok(())
+    }
+}
+
+/// Moves a vault binary and its objects over to a new master key, a vault
+/// binary holding a zip archive is simply re-encrypted.
+///
+/// Each file is chunked again and stored under the new ids before the manifest
+/// is swapped, and the old objects are only removed afterwards, so this can
+/// be run again if it is interrupted.
+pub fn reencrypt_store(
+    vault: &Path,
+    folder: &Path,
+    old: &MasterVaultKey,
+    new: &MasterVaultKey,
+    key_block: &str,
+    policy: &CompressionPolicy,
+) -> Result<()> {
+    if let Ok(mut reader) = VaultReader::open(vault, new.key_bytes()) {
+        // The manifest was already swapped, only the old objects remain.
+        if let Some(manifest) = Manifest::read(&mut reader)? {
+            ObjectStore::new(folder, new)?.collect_garbage(&manifest.objects())?;
+        }
+        return Ok(());
+    }
+
+    let mut reader = VaultReader::open(vault, old.key_bytes())?;
+    reader.verify()?;
+    reader.seek(SeekFrom::Start(0))?;
+    let Some(manifest) = Manifest::read(&mut reader)? else {
+        return reencrypt(vault, old.key_bytes(), new.key_bytes(), key_block);
+    };
+
+    let old_store = ObjectStore::new(folder, old)?;
+    let new_store = ObjectStore::new(folder, new)?.with_padding(manifest.padding);
+    let mut rotated = Manifest {
+        padding: manifest.padding,
+        entries: vec![],
+    };
+    for entry in &manifest.entries {
+        let chunks = match &entry.chunks {
+            Some(chunks) => Some(new_store.put_chunked(old_store.read_chunks(chunks, entry.compression), entry.compression, policy)?.0),
+            None => None,
+        };
+        rotated.entries.push(ManifestEntry::new(entry.path.clone(), chunks, entry.compression, entry.metadata.clone()));
+    }
+
+    let temp = vault.with_extension("rewrite");
+    let generation = read_generation(vault)?;
+    write_stream(&temp, new.key_bytes(), key_block, generation, CompressionId::Stored, rotated.padding, &mut Cursor::new(rotated.to_bytes()?))?;
+    atomicwrites::replace_atomic(&temp, vault)?;
+
+    new_store.collect_garbage(&rotated.objects())?;
+    Ok(())
+}
+
+/// Only relative paths that stay inside the vault are accepted.
+fn enclosed_name(path: &str) -> Option<PathBuf> {
+    let path = Path::new(path);
+    if path.as_os_str().is_empty() || !path.components().all(|c| matches!(c, Component::Normal(_))) {
+        return None;
+    }
+    Some(path.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read};
+
+    use crate::sys::{
+        compression::CompressionPolicy,
+        header::CompressionId,
+        metadata::FileMetadata,
+        mk::MasterVaultKey,
+        objects::{Manifest, ObjectStore, ObjectWriter, reencrypt_store},
+        stream::Padding,
+        writer::VaultReader,
+    };
+
+    #[test]
+    pub fn check_object_store() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("objects");
+        let source = root.join("source");
+        let objects = root.join("objects");
+        std::fs::create_dir_all(source.join("notes")).unwrap();
+        std::fs::write(source.join("notes/a.txt"), b"same contents").unwrap();
+        std::fs::write(source.join("b.txt"), b"same contents").unwrap();
+        std::fs::write(source.join("c.txt"), b"other contents").unwrap();
+
+        let master = MasterVaultKey::generate();
+        let seal = |vault: &str| {
+            let mut writer = ObjectWriter::new(&objects, &master, CompressionPolicy::default(), Padding::Padme).unwrap();
+            for name in ["notes", "notes/a.txt", "b.txt", "c.txt"] {
+                writer.write_path(&source.join(name), name.as_ref(), &FileMetadata::read(&source.join(name), false).unwrap()).unwrap();
+            }
+            writer.finish(root.join(vault), "", 0).unwrap();
+        };
+        seal("first.bin");
+
+        let count = || walkdir::WalkDir::new(&objects).min_depth(2).into_iter().count();
+        // Identical contents are only stored once.
+        assert_eq!(count(), 2);
+
+        let store = ObjectStore::new(&objects, &master).unwrap();
+        let mut reader = VaultReader::open(root.join("first.bin"), master.key_bytes()).unwrap();
+        let manifest = Manifest::read(&mut reader).unwrap().unwrap();
+        store.verify(&manifest).unwrap();
+
+        // Changing one file only replaces its own object.
+        std::fs::write(source.join("c.txt"), b"changed contents").unwrap();
+        let untouched = manifest.entries[1].chunks.clone().unwrap().remove(0);
+        let modified = store.object_id(&mut Cursor::new(b"changed contents"), CompressionId::Deflate).unwrap();
+        seal("second.bin");
+        assert_eq!(count(), 2);
+        assert!(objects.join(&untouched[..2]).join(&untouched[2..]).exists());
+        assert!(objects.join(&modified[..2]).join(&modified[2..]).exists());
+
+        // Swapping two objects is caught even though both decrypt.
+        let swapped = objects.join(&modified[..2]).join(&modified[2..]);
+        let original = std::fs::read(&swapped).unwrap();
+        std::fs::copy(objects.join(&untouched[..2]).join(&untouched[2..]), &swapped).unwrap();
+        assert!(store.open_verified(&modified, CompressionId::Deflate).is_err());
+        std::fs::write(&swapped, original).unwrap();
+
+        // Rotating moves every object over to the new key.
+        let new = MasterVaultKey::generate();
+        reencrypt_store(&root.join("second.bin"), &objects, &master, &new, "", &CompressionPolicy::default()).unwrap();
+        assert!(VaultReader::open(root.join("second.bin"), master.key_bytes()).is_err());
+        assert_eq!(count(), 2);
+
+        let mut reader = VaultReader::open(root.join("second.bin"), new.key_bytes()).unwrap();
+        let manifest = Manifest::read(&mut reader).unwrap().unwrap();
+        assert_eq!(manifest.padding, Padding::Padme);
+        let target = root.join("target");
+        ObjectStore::new(&objects, &new).unwrap().expand(&manifest, &target).unwrap();
+
+        let mut contents = String::new();
+        std::fs::File::open(target.join("c.txt")).unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "changed contents");
+        assert_eq!(std::fs::read(target.join("notes/a.txt")).unwrap(), b"same contents");
+    }
+
+    #[test]
+    pub fn check_chunk_dedup() {
+        let tmp = tempfile::tempdir().unwrap();
+        let folder = tmp.path().join("chunks");
+        let master = MasterVaultKey::generate();
+        let store = ObjectStore::new(&folder, &master).unwrap();
+        let policy = CompressionPolicy::default();
+
+        let mut contents = vec![0u8; 4 * 1024 * 1024];
+        rand::fill(&mut contents[..]);
+        let (first, written) = store.put_chunked(Cursor::new(&contents), CompressionId::Stored, &policy).unwrap();
+        assert!(first.len() > 1);
+        assert_eq!(written, first.len());
+
+        // Inserting at the front only disturbs the chunks around the edit.
+        contents.splice(0..0, *b"a few new bytes");
+        let (second, written) = store.put_chunked(Cursor::new(&contents), CompressionId::Stored, &policy).unwrap();
+        assert!(written <= 2);
+        assert_eq!(first[first.len() - 1], second[second.len() - 1]);
+
+        let mut read = vec![];
+        store.read_chunks(&second, CompressionId::Stored).read_to_end(&mut read).unwrap();
+        assert_eq!(read, contents);
+
+        // A manifest from before chunking is read as single chunk files.
+        let manifest = Manifest::read(&mut Cursor::new(
+            format!("NOVOMANIFEST\n[[entries]]\npath = \"a.txt\"\nobject = \"{}\"\n", first[0]).into_bytes(),
+        ))
+        .unwrap()
+        .unwrap();
+        assert_eq!(manifest.entries[0].chunks, Some(vec![first[0].clone()]));
+    }
+}
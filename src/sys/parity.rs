@@ -0,0 +1,469 @@
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use anyhow::{Result, anyhow};
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use sha2::{Digest, Sha256};
+
+use crate::console_log;
+
+/// Marks a parity file, the last byte is the version of the layout.
+const PARITY_MAGIC: &[u8; 8] = b"NOVOPAR\x01";
+
+/// The size of the blocks that are checked and repaired.
+const BLOCK_SIZE: usize = 4096;
+
+/// The number of vault blocks that each group of parity blocks covers.
+pub const DATA_SHARDS: usize = 32;
+
+const HASH_SIZE: usize = 32;
+
+/// The magic, block size, shard counts and vault length, then the hash of
+/// the block table.
+const PARITY_HEADER_SIZE: usize = 8 + 4 + 2 + 2 + 8 + HASH_SIZE;
+
+/// Describes how a parity file is laid out.
+///
+/// The vault is cut into blocks and the blocks are interleaved into groups, so
+/// group `g` holds blocks `g`, `g + groups`, `g + 2 * groups` and so on. A run of
+/// damaged blocks is then spread over many groups rather than wiping out one.
+/// After the header come the hashes of every vault block, the hashes of every
+/// parity block and finally the parity blocks themselves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ParityLayout {
+    parity_shards: usize,
+    length: u64,
+}
+
+impl ParityLayout {
+    fn new(parity_shards: usize, length: u64) -> Result<Self> {
+        if parity_shards == 0 || parity_shards > DATA_SHARDS {
+            return Err(anyhow!("The parity should be between 1 and {DATA_SHARDS} blocks for every {DATA_SHARDS} blocks of the vault."));
+        }
+        Ok(Self { parity_shards, length })
+    }
+    fn blocks(&self) -> usize {
+        self.length.div_ceil(BLOCK_SIZE as u64) as usize
+    }
+    fn groups(&self) -> usize {
+        self.blocks().div_ceil(DATA_SHARDS)
+    }
+    /// The vault block at a position in a group, positions past
+    /// the end of the vault are all zeroes and are not stored.
+    fn member(&self, group: usize, position: usize) -> Option<usize> {
+        let block = group + position * self.groups();
+        (block < self.blocks()).then_some(block)
+    }
+    /// Where the tables end and the parity blocks begin, or nothing if
+    /// a forged length would not even fit in a u64.
+    fn table_end(&self) -> Option<u64> {
+        let blocks = self.length.div_ceil(BLOCK_SIZE as u64);
+        let groups = blocks.div_ceil(DATA_SHARDS as u64);
+        groups
+            .checked_mul(self.parity_shards as u64)?
+            .checked_add(blocks)?
+            .checked_mul(HASH_SIZE as u64)?
+            .checked_add(PARITY_HEADER_SIZE as u64)
+    }
+    fn parity_hashes_offset(&self) -> u64 {
+        (PARITY_HEADER_SIZE + self.blocks() * HASH_SIZE) as u64
+    }
+    fn parity_blocks_offset(&self) -> u64 {
+        self.parity_hashes_offset() + (self.groups() * self.parity_shards * HASH_SIZE) as u64
+    }
+    fn header(&self) -> Vec<u8> {
+        let mut header = PARITY_MAGIC.to_vec();
+        header.extend_from_slice(&(BLOCK_SIZE as u32).to_le_bytes());
+        header.extend_from_slice(&(DATA_SHARDS as u16).to_le_bytes());
+        header.extend_from_slice(&(self.parity_shards as u16).to_le_bytes());
+        header.extend_from_slice(&self.length.to_le_bytes());
+        header
+    }
+    fn codec(&self) -> Result<ReedSolomon> {
+        ReedSolomon::new(DATA_SHARDS, self.parity_shards)
+            .map_err(|e| anyhow!("Failed to set up the parity codec: {e:?}"))
+    }
+}
+
+/// What a check of the vault against its parity found.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ParityReport {
+    pub blocks: usize,
+    /// The vault blocks that do not match their hash.
+    pub damaged: Vec<usize>,
+    /// The number of parity blocks that are damaged themselves.
+    pub damaged_parity: usize,
+    /// The number of groups that lost more blocks than they have parity for.
+    pub unrecoverable: usize,
+    /// Whether the vault is longer or shorter than when the parity was written.
+    pub resized: bool,
+}
+
+impl ParityReport {
+    pub fn is_clean(&self) -> bool {
+        self.damaged.is_empty() && self.damaged_parity == 0 && !self.resized
+    }
+    pub fn is_recoverable(&self) -> bool {
+        self.unrecoverable == 0
+    }
+}
+
+/// A parity file that has been read and checked for consistency.
+struct ParityFile {
+    layout: ParityLayout,
+    block_hashes: Vec<u8>,
+    parity_hashes: Vec<u8>,
+    file: File,
+}
+
+impl ParityFile {
+    fn open(path: &Path) -> Result<Self> {
+        let mut file = File::open(path)?;
+        let mut header = [0u8; PARITY_HEADER_SIZE];
+        file.read_exact(&mut header)
+            .map_err(|_| anyhow!("The parity file is too short to contain its header."))?;
+        if &header[..8] != PARITY_MAGIC {
+            return Err(anyhow!("This is not a parity file, or it was written by a newer version."));
+        }
+
+        let block_size = u32::from_le_bytes(header[8..12].try_into()?) as usize;
+        let data_shards = u16::from_le_bytes(header[12..14].try_into()?) as usize;
+        if block_size != BLOCK_SIZE || data_shards != DATA_SHARDS {
+            return Err(anyhow!("The parity file is damaged, its header does not match this format."));
+        }
+        let parity_shards = u16::from_le_bytes(header[14..16].try_into()?) as usize;
+        let length = u64::from_le_bytes(header[16..24].try_into()?);
+        let layout = ParityLayout::new(parity_shards, length)
+            .map_err(|_| anyhow!("The parity file is damaged, its header does not match this format."))?;
+
+        // The tables are sized by the header, so it is held to the length of the
+        // file before anything is allocated for them.
+        let file_length = file.metadata()?.len();
+        if layout.table_end().is_none_or(|end| end > file_length) {
+            return Err(anyhow!("The parity file is too short to contain its block table."));
+        }
+
+        let mut block_hashes = vec![0u8; layout.blocks() * HASH_SIZE];
+        let mut parity_hashes = vec![0u8; layout.groups() * parity_shards * HASH_SIZE];
+        file.read_exact(&mut block_hashes)
+            .and_then(|_| file.read_exact(&mut parity_hashes))
+            .map_err(|_| anyhow!("The parity file is too short to contain its block table."))?;
+
+        // Without a sound table there is no telling which blocks are damaged.
+        let table = Sha256::new()
+            .chain_update(&header[..24])
+            .chain_update(&block_hashes)
+            .chain_update(&parity_hashes)
+            .finalize();
+        if table[..] != header[24..] {
+            return Err(anyhow!("The parity file is damaged, so it can not be used to check the vault."));
+        }
+
+        Ok(Self {
+            layout,
+            block_hashes,
+            parity_hashes,
+            file,
+        })
+    }
+    fn block_hash(&self, block: usize) -> &[u8] {
+        &self.block_hashes[block * HASH_SIZE..(block + 1) * HASH_SIZE]
+    }
+    fn parity_hash(&self, group: usize, shard: usize) -> &[u8] {
+        let index = group * self.layout.parity_shards + shard;
+        &self.parity_hashes[index * HASH_SIZE..(index + 1) * HASH_SIZE]
+    }
+    /// Reads a parity block, or nothing if it does not match its hash.
+    fn parity_block(&mut self, group: usize, shard: usize) -> Result<Option<Vec<u8>>> {
+        let index = (group * self.layout.parity_shards + shard) as u64;
+        self.file.seek(SeekFrom::Start(self.layout.parity_blocks_offset() + index * BLOCK_SIZE as u64))?;
+        let block = read_block(&mut self.file)?;
+        Ok((hash(&block)[..] == *self.parity_hash(group, shard)).then_some(block))
+    }
+}
+
+/// Reads the next block, padding it with zeroes if the file ends first.
+fn read_block(file: &mut impl Read) -> Result<Vec<u8>> {
+    let mut block = Vec::with_capacity(BLOCK_SIZE);
+    file.take(BLOCK_SIZE as u64).read_to_end(&mut block)?;
+    block.resize(BLOCK_SIZE, 0);
+    Ok(block)
+}
+
+fn read_block_at(file: &mut File, block: usize) -> Result<Vec<u8>> {
+    file.seek(SeekFrom::Start((block * BLOCK_SIZE) as u64))?;
+    read_block(file)
+}
+
+fn hash(block: &[u8]) -> [u8; HASH_SIZE] {
+    Sha256::digest(block).into()
+}
+
+/// Writes the parity of a vault binary, with the given number of
+/// parity blocks for every [`DATA_SHARDS`] blocks of the vault.
+pub fn write_parity(vault: &Path, target: &Path, parity_shards: usize) -> Result<()> {
+    let mut source = File::open(vault)?;
+    let layout = ParityLayout::new(parity_shards, source.metadata()?.len())?;
+    let codec = layout.codec()?;
+
+    let mut block_hashes = Vec::with_capacity(layout.blocks() * HASH_SIZE);
+    {
+        let mut reader = BufReader::new(&mut source);
+        for _ in 0..layout.blocks() {
+            block_hashes.extend_from_slice(&hash(&read_block(&mut reader)?));
+        }
+    }
+
+    let temp = target.with_extension("tmp");
+    let mut out = BufWriter::new(File::create(&temp)?);
+    out.seek(SeekFrom::Start(layout.parity_blocks_offset()))?;
+
+    let mut parity_hashes = Vec::with_capacity(layout.groups() * parity_shards * HASH_SIZE);
+    for group in 0..layout.groups() {
+        let mut shards = Vec::with_capacity(DATA_SHARDS + parity_shards);
+        for position in 0..DATA_SHARDS {
+            shards.push(match layout.member(group, position) {
+                Some(block) => read_block_at(&mut source, block)?,
+                None => vec![0u8; BLOCK_SIZE],
+            });
+        }
+        shards.resize(DATA_SHARDS + parity_shards, vec![0u8; BLOCK_SIZE]);
+        codec.encode(&mut shards).map_err(|e| anyhow!("Failed to compute the parity: {e:?}"))?;
+
+        for block in &shards[DATA_SHARDS..] {
+            parity_hashes.extend_from_slice(&hash(block));
+            out.write_all(block)?;
+        }
+    }
+
+    let header = layout.header();
+    let table = Sha256::new()
+        .chain_update(&header)
+        .chain_update(&block_hashes)
+        .chain_update(&parity_hashes)
+        .finalize();
+    out.seek(SeekFrom::Start(0))?;
+    out.write_all(&header)?;
+    out.write_all(&table)?;
+    out.write_all(&block_hashes)?;
+    out.write_all(&parity_hashes)?;
+    out.into_inner()?.sync_all()?;
+
+    std::fs::rename(temp, target)?;
+    Ok(())
+}
+
+/// Rewrites the parity of a vault binary that has changed, with the same
+/// amount of parity as before. Nothing is done if there is no parity.
+pub fn refresh_parity(vault: &Path, parity: &Path) -> Result<()> {
+    if !parity.exists() {
+        return Ok(());
+    }
+    // A damaged parity file still says how much parity it had, but
+    // there is nothing to go on if the header itself is gone.
+    let shards = ParityFile::open(parity)
+        .map(|p| p.layout.parity_shards)
+        .unwrap_or(DATA_SHARDS / 8);
+    write_parity(vault, parity, shards)
+}
+
+/// Repairs the vault ahead of an operation that rewrites it, as the parity
+/// is written afresh afterwards and would otherwise take on any damage.
+pub fn repair_before_rewrite(vault: &Path, parity: &Path) -> Result<()> {
+    if !vault.exists() || !parity.exists() {
+        return Ok(());
+    }
+    let report = match check_parity(vault, parity) {
+        Ok(report) => report,
+        Err(e) => {
+            console_log!(Warn, "{e} The parity will be written afresh.");
+            return Ok(());
+        }
+    };
+    if report.is_clean() {
+        return Ok(());
+    }
+    if !report.is_recoverable() {
+        // This is also what an interrupted rewrite leaves behind.
+        console_log!(Warn, "The vault binary no longer matches its parity, the parity will be written afresh.");
+        return Ok(());
+    }
+    let repaired = repair_parity(vault, parity, &report)?;
+    console_log!(Warn, "Repaired {repaired} damaged blocks of the vault binary from its parity.");
+    Ok(())
+}
+
+/// Finds the blocks of the vault that no longer match their parity.
+pub fn check_parity(vault: &Path, parity: &Path) -> Result<ParityReport> {
+    let mut parity = ParityFile::open(parity)?;
+    let layout = parity.layout;
+
+    let mut source = File::open(vault)?;
+    let mut report = ParityReport {
+        blocks: layout.blocks(),
+        resized: source.metadata()?.len() != layout.length,
+        ..Default::default()
+    };
+
+    let mut damaged = vec![false; layout.blocks()];
+    {
+        // Blocks past a truncated end come out as zeroes and fail their hash.
+        let mut reader = BufReader::new((&mut source).take(layout.length));
+        for (block, damaged) in damaged.iter_mut().enumerate() {
+            *damaged = hash(&read_block(&mut reader)?)[..] != *parity.block_hash(block);
+        }
+    }
+    report.damaged = damaged.iter().enumerate().filter(|(_, d)| **d).map(|(b, _)| b).collect();
+
+    for group in 0..layout.groups() {
+        let mut lost = (0..DATA_SHARDS)
+            .filter_map(|p| layout.member(group, p))
+            .filter(|b| damaged[*b])
+            .count();
+        for shard in 0..layout.parity_shards {
+            if parity.parity_block(group, shard)?.is_none() {
+                report.damaged_parity += 1;
+                lost += 1;
+            }
+        }
+        if lost > layout.parity_shards {
+            report.unrecoverable += 1;
+        }
+    }
+    Ok(report)
+}
+
+/// Rebuilds the damaged blocks of the vault from the parity and then
+/// rewrites any damaged parity blocks. Returns the number of vault
+/// blocks that were repaired.
+pub fn repair_parity(vault: &Path, parity_path: &Path, report: &ParityReport) -> Result<usize> {
+    if !report.is_recoverable() {
+        return Err(anyhow!(
+            "The vault binary is damaged in {} places that the parity can not cover, it can not be repaired.",
+            report.unrecoverable
+        ));
+    }
+    if report.is_clean() {
+        return Ok(0);
+    }
+
+    let mut parity = ParityFile::open(parity_path)?;
+    let layout = parity.layout;
+    let codec = layout.codec()?;
+
+    // The repair is made on a copy so that a failure leaves the vault as it was.
+    let temp = vault.with_extension("repair");
+    std::fs::copy(vault, &temp)?;
+    let mut source = File::options().read(true).write(true).open(&temp)?;
+    source.set_len(layout.length)?;
+
+    let damaged: std::collections::HashSet<usize> = report.damaged.iter().copied().collect();
+    for group in 0..layout.groups() {
+        let members: Vec<_> = (0..DATA_SHARDS).map(|p| layout.member(group, p)).collect();
+        if !members.iter().flatten().any(|b| damaged.contains(b)) {
+            continue;
+        }
+
+        let mut shards: Vec<Option<Vec<u8>>> = Vec::with_capacity(DATA_SHARDS + layout.parity_shards);
+        for member in &members {
+            shards.push(match member {
+                Some(block) if damaged.contains(block) => None,
+                Some(block) => Some(read_block_at(&mut source, *block)?),
+                None => Some(vec![0u8; BLOCK_SIZE]),
+            });
+        }
+        for shard in 0..layout.parity_shards {
+            shards.push(parity.parity_block(group, shard)?);
+        }
+        codec.reconstruct_data(&mut shards)
+            .map_err(|e| anyhow!("Failed to rebuild the damaged blocks: {e:?}"))?;
+
+        for (member, shard) in members.iter().zip(&shards) {
+            let (Some(block), Some(contents)) = (member, shard) else {
+                continue;
+            };
+            if !damaged.contains(block) {
+                continue;
+            }
+            if hash(contents)[..] != *parity.block_hash(*block) {
+                return Err(anyhow!("The rebuilt block {block} does not match its hash, the vault can not be repaired."));
+            }
+            let end = ((block + 1) * BLOCK_SIZE).min(layout.length as usize);
+            source.seek(SeekFrom::Start((block * BLOCK_SIZE) as u64))?;
+            source.write_all(&contents[..end - block * BLOCK_SIZE])?;
+        }
+    }
+    source.sync_all()?;
+    drop(source);
+    std::fs::rename(&temp, vault)?;
+
+    if report.damaged_parity > 0 {
+        write_parity(vault, parity_path, layout.parity_shards)?;
+    }
+    Ok(report.damaged.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sys::parity::{BLOCK_SIZE, check_parity, repair_parity, write_parity};
+
+    #[test]
+    pub fn check_parity_repair() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("parity");
+        std::fs::create_dir_all(&dir).unwrap();
+        let vault = dir.join("vault.bin");
+        let parity = dir.join("vault.par");
+
+        let mut original = vec![0u8; 300 * BLOCK_SIZE + 123];
+        rand::fill(&mut original[..]);
+        std::fs::write(&vault, &original).unwrap();
+        write_parity(&vault, &parity, 4).unwrap();
+        assert!(check_parity(&vault, &parity).unwrap().is_clean());
+
+        // A run of damage is spread over the interleaved groups.
+        let mut damaged = original.clone();
+        for byte in &mut damaged[10 * BLOCK_SIZE..30 * BLOCK_SIZE] {
+            *byte ^= 0x5a;
+        }
+        *damaged.last_mut().unwrap() ^= 1;
+        std::fs::write(&vault, &damaged).unwrap();
+
+        let report = check_parity(&vault, &parity).unwrap();
+        assert_eq!(report.damaged.len(), 21);
+        assert!(report.is_recoverable());
+        assert_eq!(repair_parity(&vault, &parity, &report).unwrap(), 21);
+        assert_eq!(std::fs::read(&vault).unwrap(), original);
+
+        // A truncated vault is grown back and damaged parity is rewritten.
+        std::fs::write(&vault, &original[..original.len() - 100]).unwrap();
+        let mut contents = std::fs::read(&parity).unwrap();
+        let end = contents.len() - 10;
+        contents[end] ^= 0xff;
+        std::fs::write(&parity, contents).unwrap();
+
+        let report = check_parity(&vault, &parity).unwrap();
+        assert!(report.resized);
+        assert_eq!(report.damaged_parity, 1);
+        repair_parity(&vault, &parity, &report).unwrap();
+        assert_eq!(std::fs::read(&vault).unwrap(), original);
+        assert!(check_parity(&vault, &parity).unwrap().is_clean());
+
+        // Too much damage in one place is reported rather than guessed at.
+        std::fs::write(&vault, vec![0u8; original.len()]).unwrap();
+        let report = check_parity(&vault, &parity).unwrap();
+        assert!(!report.is_recoverable());
+        assert!(repair_parity(&vault, &parity, &report).is_err());
+
+        // A header claiming a huge vault is refused before its tables are allocated.
+        let mut contents = std::fs::read(&parity).unwrap();
+        contents[16..24].copy_from_slice(&(u64::MAX / 2).to_le_bytes());
+        std::fs::write(&parity, &contents).unwrap();
+        assert!(check_parity(&vault, &parity).is_err());
+        std::fs::write(&parity, &contents[..100]).unwrap();
+        assert!(check_parity(&vault, &parity).is_err());
+    }
+}
@@ -2,8 +2,9 @@ use anyhow::{Result, anyhow};
 #[cfg(windows)]
 use std::ffi::OsStr;
 use std::{
+    collections::HashSet,
     fs::File,
-    io::{BufRead, BufReader, BufWriter, Cursor, Write},
+    io::{BufRead, BufReader, BufWriter, Read, Seek, Write},
     path::{Path, PathBuf},
     str::FromStr,
 };
@@ -12,17 +13,26 @@ use zip::ZipArchive;
 
 use crate::{
     console_log, printing::SteppedComputationHandle, sys::{
-        common::{exists_git_repo, make_git_repo},
-        filter::{FilterDecision, NovFilter},
+        common::{exists_git_repo, make_git_repo, prompt_confirm},
+        fault::checkpoint,
+        filter::{FilterDecision, NovFilter, VaultLayout},
         lib::path::{Normal, RootPath},
-        mk::{CachedPassword, MasterVaultKey, UserVaultKey, WrappedKey},
-        procedure::sequence::{Playable, SEAL_FULL, UNSEAL_FULL},
-        statefile::{StateFileHandle, SyncMethod},
-        writer::{VaultWriter, decrypt},
+        lock::VaultLock,
+        metadata::{FileMetadata, MetadataRestorer, create_symlink},
+        mk::{CachedPassword, DEFAULT_SLOT, KeySlots, MasterVaultKey, UserVaultKey, WrappedKey},
+        objects::{Manifest, ObjectStore, ObjectWriter, reencrypt_store},
+        parity::{check_parity, refresh_parity, repair_parity, write_parity},
+        shards::{join_file, move_sharded, remove_sharded, sharded_files, split_file, with_joined},
+        generation::{check_generation, next_generation, record_generation},
+        procedure::sequence::{ComposedSequence, INIT_FULL, Playable, ROTATE_MASTER, SEAL_FULL, UNSEAL_FULL},
+        recipient::Identity,
+        signature::{check_signature, sign_vault},
+        statefile::{StateFileHandle, SyncMethod, encode_key_block},
+        writer::{VaultReader, VaultWriter, read_generation, reencrypt},
     }
 };
 
-#[derive(Debug, Clone, Copy, strum::EnumString, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, strum::EnumString, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub enum VaultState {
     Uninit,
 
@@ -53,6 +63,14 @@ pub enum VaultState {
     CleanupOldBinaries,
     RestoreUnsecureFiles,
     Unsealed, // SET REMOTE
+
+    // ROTATING
+    /// Wraps a new master key into pending key slots.
+    RotateSeed,
+    /// Moves the vault binaries over to the new master key.
+    RotateReencrypt,
+    /// Replaces the key slots with the pending ones.
+    RotateCommitKeys,
 }
 
 impl VaultState {
@@ -66,16 +84,30 @@ impl VaultState {
     }
 }
 
+/// How a command deals with a vault that was left in an incomplete state.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum RepairMode {
+    /// Nothing is repaired, the command fails instead.
+    Refuse,
+    /// Anything that gets deleted is asked about first.
+    Confirm,
+    /// Everything is repaired without asking.
+    Automatic,
+}
+
 pub struct Context<'a> {
     password: &'a mut CachedPassword,
     handle: StateFileHandle,
     master: Option<MasterVaultKey>,
-    new_wrapped: Option<WrappedKey>,
-    decrypted_zip_bytes: Option<Vec<u8>>,
-    decrypted_local_bytes: Option<Vec<u8>>,
+    new_wrapped: Option<KeySlots>,
     starting: bool,
     skip_local_zip: bool,
     fallthrough: bool,
+    rotation_passwords: Vec<(String, CachedPassword)>,
+    identity: Option<Identity>,
+    repair: RepairMode,
+    /// Keeps other processes away from the vault for as long as this lives.
+    _lock: VaultLock,
 }
 
 impl<'a> Context<'a> {
@@ -89,18 +121,40 @@ impl<'a> Context<'a> {
         &mut self.handle
     }
     pub fn new(root: &RootPath<Normal>, pass: &'a mut CachedPassword) -> Result<Self> {
+        // The lock is taken before the state is read, so that it can not
+        // change underneath us.
+        let lock = VaultLock::acquire(root)?;
         Ok(Self {
             starting: true,
             password: pass,
             handle: StateFileHandle::new(root.path())?,
             master: None,
             new_wrapped: None,
-            decrypted_zip_bytes: None,
-            decrypted_local_bytes: None,
             fallthrough: false,
             skip_local_zip: false,
+            rotation_passwords: vec![],
+            identity: None,
+            repair: RepairMode::Refuse,
+            _lock: lock,
         })
     }
+    /// Sets the passwords that the new master key will be wrapped
+    /// under during a rotation, keyed by slot label.
+    pub fn set_rotation_passwords(&mut self, passwords: Vec<(String, CachedPassword)>) {
+        self.rotation_passwords = passwords;
+    }
+    /// Unlocks the vault with an identity instead of the password, the
+    /// key slots are then left exactly as they are.
+    pub fn set_identity(&mut self, identity: Identity) {
+        self.identity = Some(identity);
+    }
+    pub fn identity(&self) -> Option<&Identity> {
+        self.identity.as_ref()
+    }
+    /// Sets how an incomplete state is dealt with, by default nothing is repaired.
+    pub fn set_repair_mode(&mut self, mode: RepairMode) {
+        self.repair = mode;
+    }
 }
 
 impl VaultState {
@@ -108,8 +162,121 @@ impl VaultState {
         self._act(root, master)
             .map_err(|e| anyhow!("({self:?}) {e:?}"))
     }
+    /// Repairs a vault that was left in an incomplete state, giving back
+    /// the rest state that it was brought to.
+    pub fn recover(root: &RootPath<Normal>, master: &mut Context) -> Result<VaultState> {
+        let state = master.handle.get_state()?;
+        if state.is_rest_state() {
+            return Ok(state);
+        }
+        if master.repair == RepairMode::Refuse {
+            return Err(anyhow!(
+                "The vault was left in an incomplete state ({state:?}). Run 'novovault repair' to see what it takes to fix, or pass --auto-repair."
+            ));
+        }
+        console_log!(
+            Warn,
+            "We are not in a rest state, we were left in an incomplete state ({state:?})."
+        );
+
+        // Perform reparations.
+        Self::repair(state, root, master)?;
+        master.handle.writeback()?;
+        checkpoint("repairing the vault")?;
+        master.handle.get_state()
+    }
+    /// Describes what repairing this state would do, without doing any of it.
+    pub fn repair_plan(&self, root: &RootPath<Normal>, handle: &StateFileHandle) -> Result<Vec<String>> {
+        let initializing = handle.get_init().unwrap_or(false);
+        let finish_init = "Finish the initialization, sealing the vault.".to_string();
+
+        let plan = match self {
+            Self::Uninit | Self::Sealed | Self::Unsealed => vec![],
+
+            Self::Seed | Self::InitFileSystem => {
+                let mut plan: Vec<_> = metadata_files(root)?
+                    .into_iter()
+                    .map(|path| format!("Delete {path:?}, the initialization then has to be run again."))
+                    .collect();
+                plan.push("Leave the vault uninitialized.".to_string());
+                plan
+            }
+            Self::MakeExternalGitRepo => vec![
+                "Create the git repository of the vault.".to_string(),
+                "Mark the vault as initialized.".to_string(),
+            ],
+            Self::MarkInitDone => vec!["Mark the vault as initialized.".to_string()],
+
+            Self::RecreatingDirectories => vec![
+                "Recreate the folders for the unsecured and locally secured files.".to_string(),
+                if initializing { finish_init } else { "Leave the vault unsealed.".to_string() },
+            ],
+            Self::Encrypting => {
+                let mut plan: Vec<_> = encryption_leftovers(root)
+                    .into_iter()
+                    .map(|path| format!("Delete {path:?}, which was partially written."))
+                    .collect();
+                plan.push(if initializing { finish_init } else { "Leave the vault unsealed.".to_string() });
+                plan
+            }
+            Self::UnlinkPostSeal
+            | Self::RelocateEncryptedBinaries
+            | Self::WriteMandatoryPostSealFiles
+            | Self::RestoreVaultGit => vec![if initializing {
+                finish_init
+            } else {
+                "Finish sealing the vault, the vault binaries are already written.".to_string()
+            }],
+
+            Self::DecryptMainVault | Self::DecryptLocallySecuredVault => {
+                vec!["Leave the vault sealed, nothing was changed yet.".to_string()]
+            }
+            Self::StashExternalGitRepo
+            | Self::DeleteSealedGitFiles
+            | Self::ExpandMainVault
+            | Self::ExpandLocalVault => {
+                let mut plan: Vec<_> = expanded_files(root)?
+                    .into_iter()
+                    .map(|path| format!("Delete {path:?}, which was expanded from the vault."))
+                    .collect();
+                plan.push("Restore the .gitignore and .gitattributes of the sealed vault.".to_string());
+                plan.push("Move the git repository of the vault back into place.".to_string());
+                plan.push("Leave the vault sealed.".to_string());
+                plan
+            }
+            Self::CleanupOldBinaries | Self::RestoreUnsecureFiles => {
+                vec!["Finish unsealing the vault, everything is already expanded.".to_string()]
+            }
+
+            Self::RotateSeed => {
+                let mut plan: Vec<_> = handle
+                    .get_pending_key_slots()?
+                    .iter()
+                    .flat_map(|slots| slots.iter())
+                    .map(|slot| format!("Discard the pending key slot '{}'.", slot.label))
+                    .collect();
+                let recipients = handle.get_pending_recipients()?.iter().count();
+                if recipients > 0 {
+                    plan.push(format!("Discard the {recipients} pending recipients."));
+                }
+                plan.push("Leave the vault sealed, the rotation then has to be run again.".to_string());
+                plan
+            }
+            Self::RotateReencrypt | Self::RotateCommitKeys => {
+                vec!["Finish moving the vault over to the new master key.".to_string()]
+            }
+        };
+        Ok(plan)
+    }
+    /// Whether repairing this state has to unlock the vault.
+    pub fn repair_needs_password(&self, handle: &StateFileHandle) -> bool {
+        match self {
+            Self::RecreatingDirectories | Self::Encrypting => handle.get_init().unwrap_or(false),
+            Self::RotateReencrypt => true,
+            _ => false,
+        }
+    }
     fn repair(
-        &self,
         source: VaultState,
         root: &RootPath<Normal>,
         master: &mut Context,
@@ -118,11 +285,21 @@ impl VaultState {
             Self::Uninit => { /* No repairs needed. */ }
 
             Self::Seed | Self::InitFileSystem => {
-                // TOOD: We should probably ask permission to do this?
-
-                if root.metadata_folder().exists() {
-                    std::fs::remove_dir_all(root.metadata_folder())?;
+                // The lock is ours and stays, everything else in the metadata folder goes.
+                let files = metadata_files(root)?;
+                if !files.is_empty() {
+                    confirm_repair(master, &format!("Delete {} so that the initialization can start over?", list_paths(&files)))?;
+                }
+                for path in files {
+                    if path.is_dir() {
+                        std::fs::remove_dir_all(&path)?;
+                    } else {
+                        std::fs::remove_file(&path)?;
+                    }
+                    checkpoint("removing the metadata of an initialization")?;
                 }
+                // The state is started over from the empty files as well.
+                master.handle.reload()?;
                 master.handle.set_state(Self::Uninit);
             }
 
@@ -142,35 +319,26 @@ impl VaultState {
             Self::RecreatingDirectories => {
                 recreate_directories(root)?;
                 master.handle.set_state(Self::Unsealed);
+                resume_init(root, master)?;
             }
 
-            Self::Encrypting => revert_encryption_state(root, master)?,
-
-            Self::DeleteSealedGitFiles => {
+            Self::Encrypting => {
                 revert_encryption_state(root, master)?;
-                create_mandatory_post_seal_files(root)?;
-                master.handle.set_state(Self::Unsealed);
+                resume_init(root, master)?;
             }
 
-            Self::UnlinkPostSeal => {
-                master.starting = false;
-                SEAL_FULL.resume(Self::UnlinkPostSeal).play(root, master)?;
-            }
-            Self::RelocateEncryptedBinaries => {
-                master.starting = false;
-                SEAL_FULL
-                    .resume(Self::RelocateEncryptedBinaries)
-                    .play(root, master)?;
-            }
-            Self::WriteMandatoryPostSealFiles => {
-                master.starting = false;
-                SEAL_FULL
-                    .resume(Self::WriteMandatoryPostSealFiles)
-                    .play(root, master)?;
-            }
-            Self::RestoreVaultGit => {
+            Self::UnlinkPostSeal
+            | Self::RelocateEncryptedBinaries
+            | Self::WriteMandatoryPostSealFiles
+            | Self::RestoreVaultGit => {
+                // The list of files to unlink is only removed once all of them are.
+                let resume = if source == Self::UnlinkPostSeal && !root.deletion_shards().exists() {
+                    Self::RelocateEncryptedBinaries
+                } else {
+                    source
+                };
                 master.starting = false;
-                SEAL_FULL.resume(Self::RestoreVaultGit).play(root, master)?;
+                seal_sequence(master).resume(resume).play(root, master)?;
             }
             Self::Sealed => { /* Nothing to do */ }
 
@@ -178,17 +346,10 @@ impl VaultState {
             Self::DecryptMainVault | Self::DecryptLocallySecuredVault => {
                 master.handle.set_state(Self::Sealed);
             }
-            Self::StashExternalGitRepo => {
-                restore_vault_git(root, master)?;
-                master.handle.set_state(Self::Sealed);
-            }
-            Self::ExpandMainVault => {
-                // TODO: Cleanup files that were produced here.
-                master.handle.set_state(Self::Sealed);
-            }
-            Self::ExpandLocalVault => {
-                master.handle.set_state(Self::Sealed);
-            }
+            Self::StashExternalGitRepo
+            | Self::DeleteSealedGitFiles
+            | Self::ExpandMainVault
+            | Self::ExpandLocalVault => revert_unsealing(root, master)?,
             Self::CleanupOldBinaries => {
                 master.starting = false;
                 UNSEAL_FULL
@@ -202,6 +363,22 @@ impl VaultState {
                     .play(root, master)?;
             }
             Self::Unsealed => { /* Nothing to do */ }
+
+            // ROTATING
+            Self::RotateSeed => {
+                // The binaries have not been touched yet, but the passwords for
+                // the other slots are gone, so the rotation has to be restarted.
+                console_log!(Warn, "The master key rotation was interrupted before it began, it will need to be run again.");
+                if master.handle.get_pending_key_slots()?.is_some() {
+                    confirm_repair(master, "Discard the pending key slots of the interrupted rotation?")?;
+                }
+                master.handle.clear_pending_key_slots();
+                master.handle.set_state(Self::Sealed);
+            }
+            Self::RotateReencrypt | Self::RotateCommitKeys => {
+                master.starting = false;
+                ROTATE_MASTER.resume(source).play(root, master)?;
+            }
         }
         Ok(())
     }
@@ -210,18 +387,7 @@ impl VaultState {
             return Ok(());
         }
         if master.starting {
-            let mut state = master.handle.get_state()?;
-            if !state.is_rest_state() {
-                console_log!(
-                    Warn,
-                    "We are not in a rest state, we were left in an incomplete state ({state:?})."
-                );
-
-                // Perform reparations.
-                self.repair(state, root, master)?;
-                master.handle.writeback()?;
-                state = master.handle.get_state()?;
-            }
+            let state = Self::recover(root, master)?;
             if state == VaultState::Unsealed && *self == Self::DecryptMainVault {
                 console_log!(Info, "The vault is already unsealed.");
                 master.fallthrough = true;
@@ -238,6 +404,7 @@ impl VaultState {
 
         master.handle.set_state(*self);
         master.handle.writeback()?;
+        checkpoint(&format!("entering {self:?}"))?;
         match self {
             VaultState::Uninit => {}
             VaultState::Seed => {
@@ -275,10 +442,10 @@ impl VaultState {
             VaultState::Sealed => {}
 
             VaultState::DecryptMainVault => {
-                decrypt_main_vault(root, master)?;
+                decrypt_main_vault(root, master).map_err(|e| abandon_unseal(master, e))?;
             }
             VaultState::DecryptLocallySecuredVault => {
-                decrypt_local_vault(root, master)?;
+                decrypt_local_vault(root, master).map_err(|e| abandon_unseal(master, e))?;
             }
             VaultState::StashExternalGitRepo => {
                 stash_external_git_repo(root, master)?;
@@ -287,22 +454,13 @@ impl VaultState {
                 delete_sealed_git_files(root, master)?;
             }
             VaultState::ExpandMainVault => {
-                expand_decrypted_bin(
-                    root.path(),
-                    master
-                        .decrypted_zip_bytes
-                        .take()
-                        .ok_or_else(|| anyhow!("Could not get bytes."))?,
-                )?;
+                expand_main_vault(root, installed_master(master)?)?;
             }
             VaultState::ExpandLocalVault => {
                 if !master.skip_local_zip {
                     expand_decrypted_bin(
                         root.path(),
-                        master
-                            .decrypted_local_bytes
-                            .take()
-                            .ok_or_else(|| anyhow!("Could not get bytes."))?,
+                        VaultReader::open(root.secure_local_zip(), installed_master(master)?.key_bytes())?,
                     )?;
                 }
             }
@@ -313,13 +471,106 @@ impl VaultState {
                 relocate_unsecure_files(root)?;
             }
             VaultState::Unsealed => {}
+
+            VaultState::RotateSeed => {
+                seed_rotation(master)?;
+            }
+            VaultState::RotateReencrypt => {
+                reencrypt_for_rotation(root, master)?;
+            }
+            VaultState::RotateCommitKeys => {
+                commit_rotated_keys(master)?;
+            }
         }
         master.handle.writeback()?;
+        checkpoint(&format!("finishing {self:?}"))?;
 
         Ok(())
     }
 }
 
+/// Asks before a repair deletes something, unless it was allowed up front.
+fn confirm_repair(ctx: &Context, question: &str) -> Result<()> {
+    if ctx.repair == RepairMode::Confirm && !prompt_confirm(question)? {
+        return Err(anyhow!("The repair was cancelled, nothing was deleted."));
+    }
+    Ok(())
+}
+
+/// Names the paths that a repair is about to delete.
+fn list_paths(paths: &[PathBuf]) -> String {
+    paths.iter().map(|path| format!("{path:?}")).collect::<Vec<_>>().join(", ")
+}
+
+/// Puts the vault back to sealed when it could not be decrypted, nothing
+/// was expanded yet so there is nothing left to repair.
+fn abandon_unseal(ctx: &mut Context, error: anyhow::Error) -> anyhow::Error {
+    // Whatever was changed in memory before the failure is dropped.
+    let reset = ctx.handle.reload().and_then(|_| {
+        ctx.handle.set_state(VaultState::Sealed);
+        ctx.handle.writeback()
+    });
+    match reset {
+        Ok(()) => error,
+        Err(e) => anyhow!("{error:?}\nThe vault could not be put back to sealed either: {e}"),
+    }
+}
+
+/// Everything in the metadata folder except the lock, which the repair holds.
+fn metadata_files(root: &RootPath<Normal>) -> Result<Vec<PathBuf>> {
+    if !root.metadata_folder().exists() {
+        return Ok(vec![]);
+    }
+    let mut files = vec![];
+    for entry in std::fs::read_dir(root.metadata_folder())? {
+        let path = entry?.path();
+        if path != root.lock_file() {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// The files that a seal writes before it is committed to, an
+/// interrupted seal leaves them partially written.
+fn encryption_leftovers(root: &RootPath<Normal>) -> Vec<PathBuf> {
+    let mut files: Vec<_> = [root.deletion_shards(), root.secure_local_zip()]
+        .into_iter()
+        .filter(|path| path.exists())
+        .collect();
+    files.extend(sharded_files(&root.inprogress_vault()));
+    files.extend(sharded_files(&root.inprogress_parity()));
+    files
+}
+
+/// The sequence that a seal is part of, an initialization carries on
+/// past the seal itself.
+fn seal_sequence(ctx: &Context) -> ComposedSequence {
+    if ctx.handle.get_init().unwrap_or(false) {
+        INIT_FULL
+    } else {
+        SEAL_FULL
+    }
+}
+
+/// An initialization that was interrupted before the vault was sealed has
+/// no unsealed vault to go back to, so it is carried on instead.
+fn resume_init(root: &RootPath<Normal>, ctx: &mut Context) -> Result<()> {
+    if ctx.handle.get_init().unwrap_or(false) {
+        ctx.starting = false;
+        INIT_FULL.resume(VaultState::RecreatingDirectories).play(root, ctx)?;
+    }
+    Ok(())
+}
+
+/// Gets the master key that was installed by the decryption step.
+fn installed_master<'b>(ctx: &'b Context) -> Result<&'b MasterVaultKey> {
+    ctx.master.as_ref().ok_or_else(|| {
+        anyhow!("There was no master key installed, which usually means that the vault was expanded prior to being decrypted.")
+    })
+}
+
 fn mark_init_done(ctx: &mut Context) -> Result<()> {
     ctx.handle.set_init(false);
 
@@ -336,41 +587,67 @@ fn relocate_unsecure_files(root: &RootPath<Normal>) -> Result<()> {
 
     let unsecure_path = root.unsecure_folder();
 
-    for dir in WalkDir::new(&unsecure_path) {
-        let dir = dir?;
-        if dir.depth() == 0 {
-            continue;
-        }
-        let lpath = dir.path();
-        let name = lpath.strip_prefix(&unsecure_path)?;
+    // Folders are recreated rather than moved, the expanded vault may already
+    // hold them. The walk does not follow links, so a dangling one is moved too.
+    for entry in WalkDir::new(&unsecure_path).min_depth(1) {
+        let entry = entry?;
+        let name = entry.path().strip_prefix(&unsecure_path)?;
+        let target = root.path().join(name);
 
-        if !lpath.exists() {
-            // TODO: How do we want to handle this case?
+        if entry.file_type().is_dir() {
+            std::fs::create_dir_all(&target)?;
         } else {
-            std::fs::rename(lpath, root.path().join(name))?;
+            std::fs::rename(entry.path(), &target)?;
+            checkpoint("restoring an unsecured file")?;
         }
     }
 
     // Now we delete the directory.
     std::fs::remove_dir_all(unsecure_path)?;
+    checkpoint("removing the unsecured folder")?;
 
     Ok(())
 }
 
 fn cleanup_old_binaries(root: &RootPath<Normal>) -> Result<()> {
     // Remove the ignore and attributes files.
-    std::fs::remove_file(root.vault_binary())?;
+    remove_sharded(&root.vault_binary())?;
+    remove_sharded(&root.vault_parity())?;
+    if root.vault_signature().exists() {
+        std::fs::remove_file(root.vault_signature())?;
+        checkpoint("removing the signature")?;
+    }
 
     // Remove the locally secured files.
     if root.secure_local_folder().exists() {
         std::fs::remove_dir_all(root.secure_local_folder())?;
+        checkpoint("removing the locally secured files")?;
+    }
+
+    // The vault is expanded in full, there is nothing left to undo.
+    if root.sealed_listing().exists() {
+        std::fs::remove_file(root.sealed_listing())?;
+        checkpoint("removing the sealed listing")?;
     }
 
     Ok(())
 }
 
-fn expand_decrypted_bin(path: &Path, vault: Vec<u8>) -> Result<()> {
-    let mut real = ZipArchive::new(Cursor::new(vault))?;
+/// Expands the main vault, which either holds the zip archive itself or
+/// the manifest of the object store.
+fn expand_main_vault(root: &RootPath<Normal>, master: &MasterVaultKey) -> Result<()> {
+    write_sealed_listing(root)?;
+
+    let mut vault = VaultReader::open(root.vault_binary(), master.key_bytes())?;
+    match Manifest::read(&mut vault)? {
+        Some(manifest) => ObjectStore::new(root.objects_folder(), master)?.expand(&manifest, root.path()),
+        None => expand_decrypted_bin(root.path(), vault),
+    }
+}
+
+pub fn expand_decrypted_bin(path: &Path, vault: impl Read + Seek) -> Result<()> {
+    let mut real = ZipArchive::new(vault)?;
+    let mut restorer = MetadataRestorer::default();
 
     for i in 0..real.len() {
         let mut entry = real.by_index(i)?;
@@ -382,7 +659,15 @@ fn expand_decrypted_bin(path: &Path, vault: Vec<u8>) -> Result<()> {
 
         let out_path = path.join(rel_path);
 
-        if entry.is_dir() {
+        // Archives from before the metadata was kept have none to restore.
+        let metadata = FileMetadata::from_extra_data(entry.extra_data().unwrap_or_default())?;
+
+        if let Some(link) = metadata.as_ref().and_then(|m| m.symlink.as_ref()) {
+            if let Some(parent) = out_path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            create_symlink(link, &out_path)?;
+        } else if entry.is_dir() {
             std::fs::create_dir_all(&out_path).inspect_err(|e| {
                 console_log!(
                     Error,
@@ -409,7 +694,83 @@ fn expand_decrypted_bin(path: &Path, vault: Vec<u8>) -> Result<()> {
                 );
             })?;
         }
+
+        if let Some(metadata) = metadata {
+            restorer.restore(&out_path, metadata)?;
+        }
+        checkpoint("expanding a file")?;
+    }
+    restorer.finish()
+}
+
+/// Writes down what the vault directory holds while sealed, right before
+/// anything is expanded into it.
+fn write_sealed_listing(root: &RootPath<Normal>) -> Result<()> {
+    let mut listing = String::new();
+    for entry in std::fs::read_dir(root.path())? {
+        listing.push_str(&entry?.file_name().to_string_lossy());
+        listing.push('\n');
     }
+
+    // A partial listing would get sealed files removed, so it is written in one go.
+    let temp = root.sealed_listing().with_extension("temp");
+    std::fs::write(&temp, listing)?;
+    atomicwrites::replace_atomic(&temp, &root.sealed_listing())?;
+    checkpoint("writing the sealed listing")?;
+    Ok(())
+}
+
+/// Removes whatever an interrupted expansion left behind, which is everything
+/// in the vault directory that was not there while it was sealed.
+fn remove_expanded_files(root: &RootPath<Normal>) -> Result<()> {
+    for path in expanded_files(root)? {
+        if path.symlink_metadata()?.is_dir() {
+            std::fs::remove_dir_all(&path)?;
+        } else {
+            std::fs::remove_file(&path)?;
+        }
+        checkpoint("removing an expanded file")?;
+    }
+
+    if root.sealed_listing().exists() {
+        std::fs::remove_file(root.sealed_listing())?;
+        checkpoint("removing the sealed listing")?;
+    }
+    Ok(())
+}
+
+/// The entries of the vault directory that an interrupted expansion left
+/// behind, there are none if it never started.
+fn expanded_files(root: &RootPath<Normal>) -> Result<Vec<PathBuf>> {
+    let listing = root.sealed_listing();
+    if !listing.exists() {
+        return Ok(vec![]);
+    }
+    let contents = std::fs::read_to_string(&listing)?;
+    let sealed: HashSet<&str> = contents.lines().collect();
+
+    let mut expanded = vec![];
+    for entry in std::fs::read_dir(root.path())? {
+        let name = entry?.file_name();
+        if name != ".nov" && !sealed.contains(name.to_string_lossy().as_ref()) {
+            expanded.push(root.path().join(name));
+        }
+    }
+    expanded.sort();
+    Ok(expanded)
+}
+
+/// Puts a vault back the way it was sealed when an unseal was interrupted
+/// before the binaries were cleaned up, they still hold everything.
+fn revert_unsealing(root: &RootPath<Normal>, ctx: &mut Context) -> Result<()> {
+    let expanded = expanded_files(root)?.len();
+    if expanded > 0 {
+        confirm_repair(ctx, &format!("Delete the {expanded} entries that were expanded before the unseal was interrupted? The vault still holds them."))?;
+    }
+    remove_expanded_files(root)?;
+    write_sealed_git_files(root)?;
+    restore_vault_git(root, ctx)?;
+    ctx.handle.set_state(VaultState::Sealed);
     Ok(())
 }
 
@@ -419,7 +780,9 @@ fn delete_sealed_git_files(root: &RootPath<Normal>, ctx: &mut Context) -> Result
     }
     // Remove the ignore and attributes files.
     std::fs::remove_file(root.gitignore())?;
+    checkpoint("removing the sealed .gitignore")?;
     std::fs::remove_file(root.gitattributes())?;
+    checkpoint("removing the sealed .gitattributes")?;
     Ok(())
 }
 
@@ -432,6 +795,7 @@ fn stash_external_git_repo(root: &RootPath<Normal>, context: &mut Context) -> Re
     // Create the wrap directory.
     if !root.wrap_folder().exists() {
         std::fs::create_dir_all(root.wrap_folder())?;
+        checkpoint("creating the wrap folder")?;
     }
 
     if !root.local_git().exists() {
@@ -445,48 +809,107 @@ fn stash_external_git_repo(root: &RootPath<Normal>, context: &mut Context) -> Re
     } else {
         // Move it into the wrap directory.
         std::fs::rename(root.local_git(), root.external_git())?;
+        checkpoint("stashing the git repository")?;
     }
 
     Ok(())
 }
 
-fn decrypt_zip(vault_path: &Path, master: &MasterVaultKey) -> Result<Vec<u8>> {
-    let mut stepped = SteppedComputationHandle::start(&format!("Decrypting zip"), 2);
-    let mut header =
-        stepped.start_next("Reading .zip", "Read .zip", || std::fs::read(vault_path).map_err(|e| anyhow!("Failed to read zip error: {e:?}")))?;
-
-    let mut vault = header.split_off(32);
+/// Authenticates the whole vault binary without keeping the plaintext, the
+/// contents are decrypted again while they are being expanded.
+fn verify_zip(vault_path: &Path, master: &MasterVaultKey) -> Result<()> {
+    let mut stepped = SteppedComputationHandle::start("Verifying zip", 2);
+    let mut vault =
+        stepped.start_next("Opening .zip", "Opened .zip", || VaultReader::open(vault_path, master.key_bytes()).map_err(|e| anyhow!("Failed to open zip error: {e:?}")))?;
 
-    stepped.start_next("Decrypting bytes", "Decrypted bytes", || decrypt(&mut header, &mut vault, master.key_bytes()))?;
+    stepped.start_next("Verifying chunks", "Verified chunks", || vault.verify())?;
     stepped.finish();
-    Ok(vault)
+    Ok(())
+}
+
+/// Authenticates every object that the manifest refers to, this is a
+/// no-op when the vault holds a zip archive.
+fn verify_objects(root: &RootPath<Normal>, master: &MasterVaultKey) -> Result<()> {
+    let mut vault = VaultReader::open(root.vault_binary(), master.key_bytes())?;
+    if let Some(manifest) = Manifest::read(&mut vault)? {
+        let mut stepped = SteppedComputationHandle::start("Verifying objects", 1);
+        stepped.start_next("Verifying objects", "Verified objects", || ObjectStore::new(root.objects_folder(), master)?.verify(&manifest))?;
+        stepped.finish();
+    }
+    Ok(())
 }
 
 fn decrypt_main_vault(root: &RootPath<Normal>, master: &mut Context) -> Result<()> {
     let mut stepped = SteppedComputationHandle::start("Decrypting", 2);
     
-    let master_key = stepped.start_next("Loading wrapped key", "Loaded wrapped key", || master.handle.get_wrapped_key())?;
-    master.new_wrapped = Some(master_key.clone());
+    let mut slots = stepped.start_next("Loading key slots", "Loaded key slots", || master.handle.get_key_slots())?;
 
-    let master_key = stepped.start_next("Decrypting master key", "Decrypted master key", || master_key.get_master_key_with_no_rewrap(master.password))?;
+    let master_key = match &master.identity {
+        Some(identity) => {
+            let recipients = master.handle.get_recipients()?;
+            stepped.start_next("Decrypting master key", "Decrypted master key", || recipients.unlock(identity))?
+        }
+        None => {
+            let (index, master_key) = stepped.start_next("Decrypting master key", "Decrypted master key", || slots.unlock(master.password))?;
+
+            // Slots made with weaker parameters are brought up to date while we have the password.
+            if slots.upgrade(index, master.password, master.handle.get_kdf_params()?)? {
+                master.handle.set_key_slots(&slots);
+            }
+            master_key
+        }
+    };
+    master.new_wrapped = Some(slots.clone());
 
     master.master = Some(master_key.clone());
 
-    master.decrypted_zip_bytes = Some(decrypt_zip(&root.vault_binary(), &master_key)?);
+    // Split vaults are joined back together before anything reads them.
+    join_file(&root.vault_binary())?;
+    join_file(&root.vault_parity())?;
+
+    repair_main_vault(root)?;
+    check_signature(root, &master.handle.get_trusted_signers(&master_key)?)?;
+    verify_zip(&root.vault_binary(), &master_key)?;
+    verify_objects(root, &master_key)?;
+
+    // The generation is bound into every chunk, so it can be trusted now.
+    let generation = read_generation(root.vault_binary())?;
+    check_generation(&master.handle, generation)?;
+    record_generation(&mut master.handle, generation);
     stepped.finish();
     Ok(())
 }
 
+/// Repairs the vault binary from its parity before it is decrypted, a
+/// single flipped bit would otherwise fail the whole decryption.
+fn repair_main_vault(root: &RootPath<Normal>) -> Result<()> {
+    if !root.vault_parity().exists() {
+        return Ok(());
+    }
+    // Damaged parity is no reason to give up on the vault, the zip is verified next.
+    let report = match check_parity(&root.vault_binary(), &root.vault_parity()) {
+        Ok(report) => report,
+        Err(e) => {
+            console_log!(Warn, "The parity of the vault binary could not be read, so it is not used: {e}");
+            return Ok(());
+        }
+    };
+    if report.is_clean() {
+        return Ok(());
+    }
+    let repaired = repair_parity(&root.vault_binary(), &root.vault_parity(), &report)?;
+    console_log!(Warn, "Repaired {repaired} damaged blocks of the vault binary from its parity.");
+    Ok(())
+}
+
 fn decrypt_local_vault(root: &RootPath<Normal>, master: &mut Context) -> Result<()> {
     match master.master.clone() {
         Some(k) => {
             if !root.secure_local_zip().exists() {
                 master.skip_local_zip = true;
             } else {
-                master.decrypted_local_bytes = Some(
-                    decrypt_zip(&root.secure_local_zip(), &k)
-                        .map_err(|e| anyhow!("Failed to decrypt zip: {e:?}"))?,
-                );
+                verify_zip(&root.secure_local_zip(), &k)
+                    .map_err(|e| anyhow!("Failed to decrypt zip: {e:?}"))?;
             }
 
             Ok(())
@@ -505,6 +928,7 @@ fn to_wide(s: &OsStr) -> Vec<u16> {
 
 fn make_external_git_repo(path: &RootPath<Normal>) -> Result<()> {
     make_git_repo(path.path())?;
+    checkpoint("creating the git repository")?;
     Ok(())
 }
 
@@ -518,6 +942,7 @@ fn init_filesystem(path: &RootPath<Normal>) -> Result<()> {
     if !exists_git_repo(path.path()) {
         console_log!(Info, "Initializing a new git repository.");
         make_git_repo(path.path())?;
+        checkpoint("creating the git repository")?;
     } else {
         console_log!(Info, "There is already an existing git repository.");
     }
@@ -525,6 +950,7 @@ fn init_filesystem(path: &RootPath<Normal>) -> Result<()> {
     if !met_path.exists() {
         // Create the metadata directory.
         std::fs::create_dir_all(&met_path)?;
+        checkpoint("creating the metadata folder")?;
     }
 
     #[cfg(windows)]
@@ -553,11 +979,13 @@ fn init_filesystem(path: &RootPath<Normal>) -> Result<()> {
             &ig_path,
             "# Feel free to customize.\n\n# Leave the next line be.\n/.nov\n",
         )?;
+        checkpoint("writing the .gitignore")?;
     }
 
     let toml = path.config();
     if !toml.exists() {
-        std::fs::write(&toml, "[settings]\ndefault_policy = \"IgnoreAndEncrypt\"\n\n[rules]\nunsecured = []\ndelete = []\n".as_bytes())?;
+        std::fs::write(&toml, "[settings]\ndefault_policy = \"IgnoreAndEncrypt\"\nlayout = \"Archive\"\ncompression = \"Deflate\"\npadding = \"None\"\n\n[rules]\nunsecured = []\ndelete = []\n".as_bytes())?;
+        checkpoint("writing the config")?;
     }
 
     Ok(())
@@ -565,9 +993,9 @@ fn init_filesystem(path: &RootPath<Normal>) -> Result<()> {
 
 fn seed_state(ctx: &mut Context) -> Result<()> {
     let master = MasterVaultKey::generate();
-    let wrapped = WrappedKey::init(&UserVaultKey::init_fresh(ctx.password)?, &master)?;
+    let wrapped = WrappedKey::init(&UserVaultKey::init_fresh(ctx.password, ctx.handle.get_kdf_params()?)?, &master)?;
 
-    ctx.handle.set_master_key(&wrapped);
+    ctx.handle.set_key_slots(&KeySlots::new(DEFAULT_SLOT, wrapped));
     ctx.handle.set_init(true);
 
     Ok(())
@@ -579,8 +1007,10 @@ fn recreate_dir(path: &Path) -> Result<()> {
         std::fs::create_dir_all(path)?;
     } else {
         std::fs::remove_dir_all(path)?;
+        checkpoint("removing a folder")?;
         std::fs::create_dir_all(path)?;
     }
+    checkpoint("creating a folder")?;
     Ok(())
 }
 
@@ -590,40 +1020,68 @@ fn recreate_dir(path: &Path) -> Result<()> {
 /// as it is not safe to proceed with sealing unless we have already written
 /// the new wrapped key.
 fn revert_encryption_state(root: &RootPath<Normal>, ctx: &mut Context) -> Result<()> {
+    let leftovers = encryption_leftovers(root);
+    if !leftovers.is_empty() {
+        confirm_repair(ctx, &format!("Delete {}? The seal was interrupted while writing them.", list_paths(&leftovers)))?;
+    }
+
     if root.deletion_shards().exists() {
         // If we have deletion shards, those will need to be cleaned up.
         std::fs::remove_file(root.deletion_shards())?;
+        checkpoint("removing the deletion shards")?;
     }
 
     if root.secure_local_zip().exists() {
         std::fs::remove_file(root.secure_local_zip())?;
+        checkpoint("removing the local vault")?;
     }
 
-    if root.inprogress_vault().exists() {
-        std::fs::remove_file(root.inprogress_vault())?;
-    }
+    // The binaries may have been split already.
+    remove_sharded(&root.inprogress_vault())?;
+    remove_sharded(&root.inprogress_parity())?;
 
     // Now we can move ourselves to the unsealed state.
-    // TODO: What about the case where encryption fails during init?
-    if ctx.handle.get_init()? {
-        ctx.handle.set_state(VaultState::RecreatingDirectories);
-    } else {
-        ctx.handle.set_state(VaultState::Unsealed);
-    }
+    ctx.handle.set_state(VaultState::Unsealed);
 
     Ok(())
 }
 
+/// Where the files that are synced end up, depending on the layout.
+enum MainWriter<'a> {
+    Archive(Box<VaultWriter>),
+    Objects(ObjectWriter<'a>),
+}
+
+impl MainWriter<'_> {
+    fn write_path(&mut self, path: &Path, name: &Path, metadata: &FileMetadata) -> Result<()> {
+        match self {
+            Self::Archive(writer) => writer.write_path(path, name, metadata),
+            Self::Objects(writer) => writer.write_path(path, name, metadata),
+        }
+    }
+}
+
 fn write_encrypted_archives(root: &RootPath<Normal>, ctx: &mut Context) -> Result<()> {
-    let (new_wrap, master) = ctx.handle.get_wrapped_key()?.get_master_key(ctx.password)?;
+    let mut new_wrap = ctx.handle.get_key_slots()?;
+    let master = match &ctx.identity {
+        Some(identity) => ctx.handle.get_recipients()?.unlock(identity)?,
+        None => new_wrap.unlock_and_rewrap(ctx.password, ctx.handle.get_kdf_params()?)?
+    };
 
     ctx.new_wrapped = Some(new_wrap.clone());
 
-    let mut sec_local_writer = VaultWriter::new(root.secure_local_zip(), master.key_bytes())?;
-
-    let mut enc_writer = VaultWriter::new(root.inprogress_vault(), master.key_bytes())?;
+    let key_block = encode_key_block(&new_wrap, &ctx.handle.get_recipients()?);
 
     let filter = NovFilter::from_root(root.path())?;
+    let generation = next_generation(&ctx.handle);
+    ctx.handle.set_compression(filter.compression());
+
+    let mut sec_local_writer = VaultWriter::new(root.secure_local_zip(), master.key_bytes(), &key_block, generation, filter.compression().clone(), filter.padding())?;
+
+    let mut enc_writer = match filter.layout() {
+        VaultLayout::Archive => MainWriter::Archive(Box::new(VaultWriter::new(root.inprogress_vault(), master.key_bytes(), &key_block, generation, filter.compression().clone(), filter.padding())?)),
+        VaultLayout::Objects => MainWriter::Objects(ObjectWriter::new(root.objects_folder(), &master, filter.compression().clone(), filter.padding())?),
+    };
 
     let src_dir = root.canonicalize()?;
 
@@ -641,6 +1099,7 @@ fn write_encrypted_archives(root: &RootPath<Normal>, ctx: &mut Context) -> Resul
 
         // Schedule this path for unlinking.
         to_unlink.push(file.clone());
+        let metadata = FileMetadata::read(path, filter.xattrs())?;
         match filter.check_decision(path)? {
             FilterDecision::Delete => {
 
@@ -648,19 +1107,28 @@ fn write_encrypted_archives(root: &RootPath<Normal>, ctx: &mut Context) -> Resul
             }
             FilterDecision::Encrypt => {
                 // We just write it to the normal zip.
-                enc_writer.write_path(path, name)?;
+                enc_writer.write_path(path, name, &metadata)?;
                 // write_path_to_zip(&mut enc_zip, enc_options, path, name)?;
             }
             FilterDecision::IgnoreAndEncrypt => {
                 // println!("IGNORE AND ENCRYPT: {:?}", path);
-                sec_local_writer.write_path(path, name)?;
+                sec_local_writer.write_path(path, name, &metadata)?;
             }
             FilterDecision::Unsecure => {
-                if path.is_dir() {
-                    std::fs::create_dir_all(root.unsecure_folder().join(name))?;
+                let target = root.unsecure_folder().join(name);
+                // The folders above it are sealed, so they are not copied over.
+                if let Some(parent) = target.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                if let Some(link) = &metadata.symlink {
+                    create_symlink(link, &target)?;
+                } else if path.is_dir() {
+                    std::fs::create_dir_all(&target)?;
                 } else {
-                    std::fs::write(root.unsecure_folder().join(name), std::fs::read(path)?)?;
+                    std::fs::copy(path, &target)?;
+                    metadata.apply(&target)?;
                 }
+                checkpoint("copying an unsecured file")?;
             } // }
         }
     }
@@ -675,11 +1143,35 @@ fn write_encrypted_archives(root: &RootPath<Normal>, ctx: &mut Context) -> Resul
 
     // Flush the buffer to the disk.
     buf.flush()?;
+    checkpoint("writing the deletion shards")?;
+
+    match enc_writer {
+        MainWriter::Archive(mut writer) => {
+            writer.finish()?;
 
-    enc_writer.finish()?;
+            // Nothing refers to the objects of an earlier seal anymore.
+            if root.objects_folder().exists() {
+                std::fs::remove_dir_all(root.objects_folder())?;
+            }
+        }
+        MainWriter::Objects(writer) => writer.finish(root.inprogress_vault(), &key_block, generation)?,
+    }
     sec_local_writer.finish()?;
+    record_generation(&mut ctx.handle, generation);
+    checkpoint("writing the vault binaries")?;
 
-    ctx.handle.set_master_key(&new_wrap);
+    if filter.parity() > 0 {
+        write_parity(&root.inprogress_vault(), &root.inprogress_parity(), filter.parity())?;
+        checkpoint("writing the parity")?;
+    }
+    if let Some(size) = filter.shard_size() {
+        split_file(&root.inprogress_vault(), size)?;
+        if root.inprogress_parity().exists() {
+            split_file(&root.inprogress_parity(), size)?;
+        }
+    }
+
+    ctx.handle.set_key_slots(&new_wrap);
 
     Ok(())
 }
@@ -701,9 +1193,10 @@ fn unlink_other_archives(root: &RootPath<Normal>) -> Result<()> {
             continue; // We do not want to interact with empty lines.
         }
         let path = PathBuf::from_str(&line)?;
-        if path.exists() {
+        // Symlinks are removed themselves, even when they dangle.
+        if let Ok(meta) = path.symlink_metadata() {
             // Unlink the file.
-            if path.is_dir() {
+            if meta.is_dir() {
                 std::fs::remove_dir_all(&path).map_err(|e| {
                     anyhow!("Failed unlinking directory (path={path:?}) with error {e:?}")
                 })?;
@@ -712,17 +1205,22 @@ fn unlink_other_archives(root: &RootPath<Normal>) -> Result<()> {
                     anyhow!("Failed unlinking file (path={path:?}) with error {e:?}")
                 })?;
             }
+            checkpoint("unlinking a file")?;
         }
     }
 
     // Remove the deletion shards.
     std::fs::remove_file(root.deletion_shards())?;
+    checkpoint("removing the deletion shards")?;
 
     Ok(())
 }
 
 fn relocate_encrypted_binaries(root: &RootPath<Normal>) -> Result<()> {
-    std::fs::rename(root.inprogress_vault(), root.vault_binary())?;
+    // The vault binary goes last, so that if it is still in progress the
+    // parity is either still in progress too or already in place.
+    move_sharded(&root.inprogress_parity(), &root.vault_parity())?;
+    move_sharded(&root.inprogress_vault(), &root.vault_binary())?;
     Ok(())
 }
 
@@ -733,25 +1231,38 @@ fn recreate_directories(root: &RootPath<Normal>) -> Result<()> {
 
     if root.deletion_shards().exists() {
         std::fs::remove_file(root.deletion_shards())?;
+        checkpoint("removing the deletion shards")?;
     }
 
     // Delete the in progress zip.
-    let zip_loc = root.inprogress_vault();
-    if zip_loc.exists() {
-        std::fs::remove_file(&zip_loc)?;
-    }
+    remove_sharded(&root.inprogress_vault())?;
+    remove_sharded(&root.inprogress_parity())?;
+
+    // Parity of an earlier seal would no longer match, so it goes either way.
+    remove_sharded(&root.vault_parity())?;
     Ok(())
 }
 
-fn create_mandatory_post_seal_files(root: &RootPath<Normal>) -> Result<()> {
+fn write_sealed_git_files(root: &RootPath<Normal>) -> Result<()> {
     std::fs::write(
         root.gitignore(),
-        "# NOVAULT\n# DO NOT MODIFY THIS\n/.nov/unsecure\n/.nov/secure_local\n/.nov/.s3auth\n",
+        "# NOVAULT\n# DO NOT MODIFY THIS\n/.nov/unsecure\n/.nov/secure_local\n/.nov/.s3auth\n/.nov/.local-state\n/.nov/.device\n/.nov/.lock\n",
     )?;
+    checkpoint("writing the sealed .gitignore")?;
     std::fs::write(
         root.gitattributes(),
-        "# NOVAULT\n# DO NOT MODIFY THIS\nvault.bin binary",
+        "# NOVAULT\n# DO NOT MODIFY THIS\nvault.bin binary\nvault.bin.* binary\nvault.par binary\nvault.par.* binary\n.nov/objects/** binary\n",
     )?;
+    checkpoint("writing the sealed .gitattributes")?;
+    Ok(())
+}
+
+fn create_mandatory_post_seal_files(root: &RootPath<Normal>) -> Result<()> {
+    write_sealed_git_files(root)?;
+
+    // The binaries are in their final place, so this device can sign them.
+    sign_vault(root)?;
+    checkpoint("signing the vault")?;
     Ok(())
 }
 
@@ -759,11 +1270,96 @@ fn restore_vault_git(root: &RootPath<Normal>, ctx: &mut Context) -> Result<()> {
     if ctx.handle.get_remote_storage()? == Some(SyncMethod::TigrisS3) {
         return Ok(());
     }
-    // Now we need to move the .git back out to the top.
-    std::fs::rename(root.external_git(), root.local_git())?;
+    // Now we need to move the .git back out to the top, an interrupted
+    // run may have done so already.
+    if root.external_git().exists() {
+        std::fs::rename(root.external_git(), root.local_git())?;
+        checkpoint("restoring the git repository")?;
+    }
 
     // Remove the wrap directory as it serves no purpose when we are sealed.
-    std::fs::remove_dir(root.wrap_folder())?;
+    if root.wrap_folder().exists() {
+        std::fs::remove_dir(root.wrap_folder())?;
+        checkpoint("removing the wrap folder")?;
+    }
+
+    Ok(())
+}
+
+/// Generates the new master key and wraps it under every password
+/// that is being carried over, these are held as pending slots until
+/// the vault binaries have been moved over.
+fn seed_rotation(ctx: &mut Context) -> Result<()> {
+    let passwords = std::mem::take(&mut ctx.rotation_passwords);
+    if passwords.is_empty() {
+        return Err(anyhow!("There are no key slots to carry over to the new master key."));
+    }
+
+    let master = MasterVaultKey::generate();
+    let params = ctx.handle.get_kdf_params()?;
+    let mut pending = KeySlots::default();
+    for (label, mut password) in passwords {
+        pending.insert(&label, WrappedKey::init(&UserVaultKey::init_fresh(&mut password, params)?, &master)?)?;
+    }
+
+    ctx.handle.set_pending_key_slots(&pending);
+
+    // Recipients only need their public keys to be carried over.
+    let recipients = ctx.handle.get_recipients()?.rewrap(&master)?;
+    ctx.handle.set_pending_recipients(&recipients);
 
+    // The trusted signers are checked under the old key before they are vouched for under the new one.
+    let old = match &ctx.identity {
+        Some(identity) => ctx.handle.get_recipients()?.unlock(identity)?,
+        None => ctx.handle.get_key_slots()?.unlock(ctx.password)?.1,
+    };
+    let signers = ctx.handle.get_trusted_signers(&old)?;
+    ctx.handle.set_pending_trusted_signers(&signers, &master)?;
+    Ok(())
+}
+
+/// Re-encrypts each vault binary under the new master key.
+///
+/// Each binary is replaced atomically, so if we are interrupted we
+/// simply skip the ones that already open with the new key.
+fn reencrypt_for_rotation(root: &RootPath<Normal>, ctx: &mut Context) -> Result<()> {
+    let pending = ctx.handle.get_pending_key_slots()?.ok_or_else(|| {
+        anyhow!("There are no pending key slots, so the new master key could not be found.")
+    })?;
+    let (new, old) = match &ctx.identity {
+        Some(identity) => (
+            ctx.handle.get_pending_recipients()?.unlock(identity)?,
+            ctx.handle.get_recipients()?.unlock(identity)?,
+        ),
+        None => (pending.unlock(ctx.password)?.1, ctx.handle.get_key_slots()?.unlock(ctx.password)?.1),
+    };
+    let key_block = encode_key_block(&pending, &ctx.handle.get_pending_recipients()?);
+
+    with_joined(root, || {
+        if root.vault_binary().exists() {
+            reencrypt_store(&root.vault_binary(), &root.objects_folder(), &old, &new, &key_block, &ctx.handle.get_compression()?)?;
+            refresh_parity(&root.vault_binary(), &root.vault_parity())?;
+            sign_vault(root)?;
+        }
+        Ok(())
+    })?;
+
+    let local = root.secure_local_zip();
+    if local.exists() && VaultReader::open(&local, new.key_bytes()).is_err() {
+        reencrypt(&local, old.key_bytes(), new.key_bytes(), &key_block)?;
+    }
+
+    ctx.master = Some(new);
+    Ok(())
+}
+
+fn commit_rotated_keys(ctx: &mut Context) -> Result<()> {
+    // If there is nothing pending we were interrupted after committing.
+    if let Some(pending) = ctx.handle.get_pending_key_slots()? {
+        ctx.handle.set_key_slots(&pending);
+        ctx.handle.set_recipients(&ctx.handle.get_pending_recipients()?);
+        ctx.handle.commit_pending_trusted_signers();
+        ctx.handle.clear_pending_key_slots();
+    }
     Ok(())
 }
@@ -29,6 +29,14 @@ pub const INIT_FULL: ComposedSequence = ComposedSequence(&[
     NormalSequence(&[ VaultState::MakeExternalGitRepo, VaultState::MarkInitDone, VaultState::Sealed ])
 ]);
 
+/// Swaps the master key of a sealed vault for a new one.
+pub const ROTATE_MASTER: NormalSequence = NormalSequence(&[
+    VaultState::RotateSeed,
+    VaultState::RotateReencrypt,
+    VaultState::RotateCommitKeys,
+    VaultState::Sealed
+]);
+
 pub const UNSEAL_FULL: NormalSequence = NormalSequence(&[
     VaultState::DecryptMainVault,
     VaultState::DecryptLocallySecuredVault,
@@ -0,0 +1,258 @@
+use std::{fmt::Display, path::Path, str::FromStr};
+
+use anyhow::{Result, anyhow};
+use bech32::{Bech32, Hrp};
+use chacha20poly1305::{Key, KeyInit, XChaCha20Poly1305, XNonce, aead::Aead};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, SharedSecret, StaticSecret};
+use zeroize::Zeroizing;
+
+use crate::sys::mk::MasterVaultKey;
+
+/// The prefix of an encoded public key.
+const RECIPIENT_HRP: &str = "novo";
+
+/// The prefix of an encoded private key, these are written in upper case.
+const IDENTITY_HRP: &str = "novo-secret-key-";
+
+/// Binds the derived wrapping key to its purpose.
+const WRAP_INFO: &[u8] = b"novovault x25519 master key";
+
+/// The public half of a key pair, anyone holding it can wrap
+/// the master key to the matching identity.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Recipient(PublicKey);
+
+impl FromStr for Recipient {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (hrp, data) = bech32::decode(s.trim())
+            .map_err(|e| anyhow!("The recipient is not a valid public key: {e}"))?;
+        if !hrp.as_str().eq_ignore_ascii_case(RECIPIENT_HRP) {
+            return Err(anyhow!("The recipient should start with '{RECIPIENT_HRP}1'."));
+        }
+        let bytes: [u8; 32] = data
+            .try_into()
+            .map_err(|_| anyhow!("The recipient is of the incorrect size."))?;
+        Ok(Self(PublicKey::from(bytes)))
+    }
+}
+
+impl Display for Recipient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let encoded = bech32::encode::<Bech32>(Hrp::parse_unchecked(RECIPIENT_HRP), self.0.as_bytes())
+            .map_err(|_| std::fmt::Error)?;
+        write!(f, "{encoded}")
+    }
+}
+
+/// The private half of a key pair, this unlocks every vault
+/// that the matching recipient was added to.
+#[derive(Clone)]
+pub struct Identity(StaticSecret);
+
+impl Identity {
+    pub fn generate() -> Self {
+        Self(StaticSecret::from(rand::random::<[u8; 32]>()))
+    }
+    pub fn recipient(&self) -> Recipient {
+        Recipient(PublicKey::from(&self.0))
+    }
+    /// Reads an identity file, blank lines and lines starting
+    /// with '#' are skipped.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let contents = Zeroizing::new(
+            std::fs::read_to_string(path.as_ref())
+                .map_err(|e| anyhow!("Could not read the identity file at {:?}: {e}", path.as_ref()))?,
+        );
+        let Some(line) = contents
+            .lines()
+            .map(str::trim)
+            .find(|l| !l.is_empty() && !l.starts_with('#'))
+        else {
+            return Err(anyhow!("The identity file does not contain a key."));
+        };
+
+        let (hrp, data) = bech32::decode(line)
+            .map_err(|e| anyhow!("The identity file does not contain a valid key: {e}"))?;
+        if !hrp.as_str().eq_ignore_ascii_case(IDENTITY_HRP) {
+            return Err(anyhow!("The identity file does not contain a novovault secret key."));
+        }
+        let data = Zeroizing::new(data);
+        let bytes: [u8; 32] = data
+            .as_slice()
+            .try_into()
+            .map_err(|_| anyhow!("The secret key in the identity file is of the incorrect size."))?;
+        Ok(Self(StaticSecret::from(bytes)))
+    }
+    /// The contents of an identity file, the public key is noted in a comment.
+    pub fn to_file_contents(&self) -> Result<Zeroizing<String>> {
+        let encoded = bech32::encode_upper::<Bech32>(Hrp::parse_unchecked(IDENTITY_HRP), self.0.as_bytes())
+            .map_err(|e| anyhow!("Failed to encode the identity: {e}"))?;
+        Ok(Zeroizing::new(format!("# public key: {}\n{encoded}\n", self.recipient())))
+    }
+}
+
+/// The master key wrapped to a recipient, this works like a single
+/// age stanza: an ephemeral key agreement, HKDF and an AEAD.
+#[derive(Clone)]
+pub struct RecipientKey {
+    ephemeral: [u8; 32],
+    nonce: [u8; 24],
+    payload: Vec<u8>,
+}
+
+impl RecipientKey {
+    pub fn wrap(recipient: &Recipient, master: &MasterVaultKey) -> Result<Self> {
+        let ephemeral = StaticSecret::from(rand::random::<[u8; 32]>());
+        let ephemeral_public = PublicKey::from(&ephemeral);
+        let cipher = wrapping_cipher(ephemeral.diffie_hellman(&recipient.0), &ephemeral_public, &recipient.0)?;
+
+        let nonce: [u8; 24] = rand::random();
+        let payload = cipher
+            .encrypt(XNonce::from_slice(&nonce), master.key_bytes() as &[u8])
+            .map_err(|_| anyhow!("Failed to wrap the master key to the recipient."))?;
+
+        Ok(Self {
+            ephemeral: *ephemeral_public.as_bytes(),
+            nonce,
+            payload,
+        })
+    }
+    pub fn unwrap(&self, identity: &Identity) -> Result<MasterVaultKey> {
+        let ephemeral = PublicKey::from(self.ephemeral);
+        let cipher = wrapping_cipher(identity.0.diffie_hellman(&ephemeral), &ephemeral, &identity.recipient().0)?;
+
+        let key = Zeroizing::new(
+            cipher
+                .decrypt(XNonce::from_slice(&self.nonce), &*self.payload)
+                .map_err(|_| anyhow!("Failed to decrypt master key with provided identity."))?,
+        );
+        Ok(MasterVaultKey::from_bytes(
+            key.as_slice()
+                .try_into()
+                .map_err(|_| anyhow!("The wrapped master key is of the incorrect size."))?,
+        ))
+    }
+    pub fn from_hex(string: &str) -> Result<Self> {
+        let bytes = hex::decode(string)?;
+        if bytes.len() != 32 + 24 + 32 + 16 {
+            return Err(anyhow!("Recipient key is of the incorrect size."));
+        }
+        Ok(Self {
+            ephemeral: bytes[..32].try_into().unwrap(),
+            nonce: bytes[32..56].try_into().unwrap(),
+            payload: bytes[56..].to_vec(),
+        })
+    }
+    pub fn to_hex(&self) -> String {
+        let mut buffer = Vec::with_capacity(32 + 24 + self.payload.len());
+        buffer.extend_from_slice(&self.ephemeral);
+        buffer.extend_from_slice(&self.nonce);
+        buffer.extend_from_slice(&self.payload);
+        hex::encode(buffer)
+    }
+}
+
+/// Derives the cipher from the shared secret, both public keys go
+/// into the salt so the wrap is bound to this exact exchange.
+fn wrapping_cipher(shared: SharedSecret, ephemeral: &PublicKey, recipient: &PublicKey) -> Result<XChaCha20Poly1305> {
+    if !shared.was_contributory() {
+        return Err(anyhow!("The key agreement produced a low-order point, the public key is invalid."));
+    }
+
+    let mut salt = [0u8; 64];
+    salt[..32].copy_from_slice(ephemeral.as_bytes());
+    salt[32..].copy_from_slice(recipient.as_bytes());
+
+    let mut key = Zeroizing::new([0u8; 32]);
+    Hkdf::<Sha256>::new(Some(&salt), shared.as_bytes())
+        .expand(WRAP_INFO, &mut key[..])
+        .map_err(|_| anyhow!("Failed to derive the wrapping key."))?;
+    Ok(XChaCha20Poly1305::new(Key::from_slice(&key[..])))
+}
+
+/// Every recipient that the master key is wrapped to.
+#[derive(Clone, Default)]
+pub struct Recipients(Vec<(Recipient, RecipientKey)>);
+
+impl Recipients {
+    pub fn iter(&self) -> impl Iterator<Item = &(Recipient, RecipientKey)> {
+        self.0.iter()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+    pub fn add(&mut self, recipient: Recipient, master: &MasterVaultKey) -> Result<()> {
+        if self.0.iter().any(|(r, _)| *r == recipient) {
+            return Err(anyhow!("The vault is already shared with {recipient}."));
+        }
+        self.0.push((recipient, RecipientKey::wrap(&recipient, master)?));
+        Ok(())
+    }
+    pub fn insert(&mut self, recipient: Recipient, key: RecipientKey) {
+        self.0.push((recipient, key));
+    }
+    pub fn remove(&mut self, recipient: &Recipient) -> Result<()> {
+        let before = self.0.len();
+        self.0.retain(|(r, _)| r != recipient);
+        if self.0.len() == before {
+            return Err(anyhow!("The vault is not shared with {recipient}."));
+        }
+        Ok(())
+    }
+    /// Wraps a new master key to the same set of recipients, this only
+    /// needs their public keys.
+    pub fn rewrap(&self, master: &MasterVaultKey) -> Result<Self> {
+        let mut rewrapped = Self::default();
+        for (recipient, _) in &self.0 {
+            rewrapped.add(*recipient, master)?;
+        }
+        Ok(rewrapped)
+    }
+    /// Finds the entry for this identity and unwraps the master key from it.
+    pub fn unlock(&self, identity: &Identity) -> Result<MasterVaultKey> {
+        let recipient = identity.recipient();
+        let (_, key) = self
+            .0
+            .iter()
+            .find(|(r, _)| *r == recipient)
+            .ok_or_else(|| anyhow!("The vault is not shared with this identity ({recipient})."))?;
+        key.unwrap(identity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sys::{mk::MasterVaultKey, recipient::{Identity, Recipient, RecipientKey, Recipients}};
+
+    #[test]
+    pub fn check_recipient_wrap() {
+        let identity = Identity::generate();
+        let recipient: Recipient = identity.recipient().to_string().parse().unwrap();
+        assert!(recipient == identity.recipient());
+
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("identity");
+        std::fs::write(&path, identity.to_file_contents().unwrap().as_bytes()).unwrap();
+        let identity = Identity::from_file(&path).unwrap();
+
+        let master = MasterVaultKey::generate();
+        let mut recipients = Recipients::default();
+        recipients.add(recipient, &master).unwrap();
+        assert!(recipients.add(recipient, &master).is_err());
+
+        let (_, key) = recipients.iter().next().unwrap();
+        let key = RecipientKey::from_hex(&key.to_hex()).unwrap();
+        assert_eq!(key.unwrap(&identity).unwrap().key_bytes(), master.key_bytes());
+
+        // Someone else's identity can neither find nor open the entry.
+        let other = Identity::generate();
+        assert!(recipients.unlock(&other).is_err());
+        assert!(key.unwrap(&other).is_err());
+
+        assert!("novo1qqqq".parse::<Recipient>().is_err());
+    }
+}
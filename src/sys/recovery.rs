@@ -0,0 +1,120 @@
+use anyhow::{Result, anyhow};
+use zeroize::Zeroizing;
+
+use crate::sys::mk::CachedPassword;
+
+/// The label of the key slot that the recovery phrase unlocks.
+pub const RECOVERY_SLOT: &str = "recovery";
+
+/// Crockford's base32 alphabet, which leaves out the letters
+/// that are easily mistaken for digits.
+const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+/// The number of random characters, at five bits each this is 160 bits.
+const DATA_CHARS: usize = 32;
+
+/// The number of random characters in each group, every group
+/// is followed by its own check character.
+const GROUP_CHARS: usize = 4;
+
+/// A high-entropy phrase that is written down in place of a password.
+///
+/// It is made of groups of four random base32 characters followed by
+/// a check character, so that a typo can be pinned to its group.
+pub struct RecoveryPhrase(Zeroizing<String>);
+
+impl RecoveryPhrase {
+    pub fn generate() -> Self {
+        let mut data = Zeroizing::new([0u8; DATA_CHARS]);
+        rand::fill(&mut data[..]);
+
+        let mut phrase = Zeroizing::new(String::with_capacity(DATA_CHARS / GROUP_CHARS * (GROUP_CHARS + 1)));
+        for group in data.chunks(GROUP_CHARS) {
+            let values = group.iter().map(|b| b % 32).collect::<Vec<_>>();
+            for v in &values {
+                phrase.push(ALPHABET[*v as usize] as char);
+            }
+            phrase.push(ALPHABET[check_value(&values) as usize] as char);
+        }
+        Self(phrase)
+    }
+    /// Parses a phrase as it was typed back in, separators and case are
+    /// ignored, and the characters that Crockford's alphabet leaves out are
+    /// read as the digits they resemble.
+    pub fn parse(input: &str) -> Result<Self> {
+        let mut values = Zeroizing::new(vec![]);
+        for c in input.chars().filter(|c| !c.is_whitespace() && *c != '-') {
+            let c = match c.to_ascii_uppercase() {
+                'O' => '0',
+                'I' | 'L' => '1',
+                c => c,
+            };
+            let Some(v) = ALPHABET.iter().position(|a| *a as char == c) else {
+                return Err(anyhow!("The recovery phrase contains '{c}', which is not a valid character."));
+            };
+            values.push(v as u8);
+        }
+
+        if values.len() != DATA_CHARS / GROUP_CHARS * (GROUP_CHARS + 1) {
+            return Err(anyhow!("The recovery phrase should have {} groups of {} characters.", DATA_CHARS / GROUP_CHARS, GROUP_CHARS + 1));
+        }
+
+        for (i, group) in values.chunks(GROUP_CHARS + 1).enumerate() {
+            if check_value(&group[..GROUP_CHARS]) != group[GROUP_CHARS] {
+                return Err(anyhow!("Group {} of the recovery phrase has a typo in it.", i + 1));
+            }
+        }
+
+        Ok(Self(Zeroizing::new(values.iter().map(|v| ALPHABET[*v as usize] as char).collect())))
+    }
+    /// The phrase split into groups for writing down.
+    pub fn display(&self) -> String {
+        self.0
+            .as_bytes()
+            .chunks(GROUP_CHARS + 1)
+            .map(|g| String::from_utf8_lossy(g).into_owned())
+            .collect::<Vec<_>>()
+            .join("-")
+    }
+    /// The phrase in its canonical form, which is what the key slot is wrapped under.
+    pub fn to_password(&self) -> CachedPassword {
+        CachedPassword::from_string(self.0.to_string())
+    }
+}
+
+/// The Luhn mod N check character, this catches any single substituted
+/// character and any swap of two neighbouring characters.
+fn check_value(values: &[u8]) -> u8 {
+    let mut factor = 2;
+    let mut sum = 0u32;
+    for v in values.iter().rev() {
+        let addend = factor * *v as u32;
+        sum += addend / 32 + addend % 32;
+        factor = if factor == 2 { 1 } else { 2 };
+    }
+    ((32 - sum % 32) % 32) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sys::recovery::RecoveryPhrase;
+
+    #[test]
+    pub fn check_phrase_roundtrip() {
+        let phrase = RecoveryPhrase::generate();
+        let shown = phrase.display();
+        assert_eq!(shown.len(), 8 * 5 + 7);
+
+        // Case and separators do not matter.
+        let typed = shown.to_lowercase().replace('-', " ");
+        assert_eq!(RecoveryPhrase::parse(&typed).unwrap().display(), shown);
+
+        // A changed character is caught by its group.
+        let mut bytes = shown.clone().into_bytes();
+        bytes[6] = if bytes[6] == b'A' { b'B' } else { b'A' };
+        let err = RecoveryPhrase::parse(&String::from_utf8(bytes).unwrap()).err().unwrap();
+        assert!(err.to_string().contains("Group 2"));
+
+        assert!(RecoveryPhrase::parse(&shown[..20]).is_err());
+    }
+}
@@ -0,0 +1,339 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Read},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
+
+use crate::sys::{fault::checkpoint, lib::path::{Normal, RootPath}, parity::repair_before_rewrite};
+
+/// The shard of a file at an index, `vault.bin` becomes `vault.bin.000`.
+pub fn shard_path(path: &Path, index: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".{index:03}"));
+    PathBuf::from(name)
+}
+
+/// Where the shards of a file are recorded, `vault.bin` becomes `vault.bin.shards`.
+fn manifest_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(".shards");
+    PathBuf::from(name)
+}
+
+/// How many shards a file was split into and the length they add up
+/// to, so that a missing shard is noticed rather than cutting it short.
+#[derive(Serialize, Deserialize)]
+struct ShardManifest {
+    count: usize,
+    length: u64,
+}
+
+fn read_manifest(path: &Path) -> Result<Option<ShardManifest>> {
+    let manifest = manifest_path(path);
+    if !manifest.exists() {
+        return Ok(None);
+    }
+    toml::from_str(&std::fs::read_to_string(&manifest)?)
+        .map(Some)
+        .map_err(|e| anyhow!("The shard manifest {manifest:?} is damaged: {e}"))
+}
+
+/// The indices of the shards of a file that are on the disk, in order.
+/// Any that the manifest records are found even past a gap in the numbering.
+fn shard_indices(path: &Path) -> Vec<usize> {
+    let recorded = read_manifest(path).ok().flatten().map_or(0, |m| m.count);
+    let contiguous = (0..).take_while(|index| shard_path(path, *index).exists()).count();
+    (0..recorded.max(contiguous))
+        .filter(|index| shard_path(path, *index).exists())
+        .collect()
+}
+
+/// The shards of a file that are on the disk, in order.
+pub fn shard_paths(path: &Path) -> Vec<PathBuf> {
+    shard_indices(path).into_iter().map(|index| shard_path(path, index)).collect()
+}
+
+/// The shards of a file, checked against the count and length that
+/// were recorded when it was split.
+fn verified_shards(path: &Path) -> Result<Vec<PathBuf>> {
+    let manifest = read_manifest(path)?.ok_or_else(|| {
+        anyhow!("The shards of {path:?} have no manifest, so there is no telling whether any are missing.")
+    })?;
+    let shards: Vec<_> = (0..manifest.count).map(|index| shard_path(path, index)).collect();
+    let mut length = 0;
+    for shard in &shards {
+        length += std::fs::metadata(shard)
+            .map_err(|_| anyhow!("The shard {shard:?} is missing, it has to be restored before the vault can be read."))?
+            .len();
+    }
+    if length != manifest.length {
+        return Err(anyhow!(
+            "The shards of {path:?} add up to {length} bytes rather than the {} that were split.",
+            manifest.length
+        ));
+    }
+    Ok(shards)
+}
+
+/// Whether the file exists, either whole or as shards.
+pub fn exists_sharded(path: &Path) -> bool {
+    path.exists() || shard_path(path, 0).exists() || manifest_path(path).exists()
+}
+
+/// Every file on the disk that makes up a file, whole or as shards along
+/// with their manifest.
+pub fn sharded_files(path: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<_> = std::iter::once(path.to_path_buf())
+        .filter(|path| path.exists())
+        .collect();
+    files.extend(shard_paths(path));
+    files.extend(Some(manifest_path(path)).filter(|manifest| manifest.exists()));
+    files
+}
+
+/// Removes the shards of a file and then its manifest.
+///
+/// The last shard goes first and the manifest last, so whatever is left
+/// after an interruption is still found and removed the next time around.
+fn remove_shards(path: &Path) -> Result<()> {
+    for shard in shard_paths(path).into_iter().rev() {
+        std::fs::remove_file(shard)?;
+        checkpoint("removing a shard")?;
+    }
+    if manifest_path(path).exists() {
+        std::fs::remove_file(manifest_path(path))?;
+        checkpoint("removing a shard manifest")?;
+    }
+    Ok(())
+}
+
+/// Removes a file along with any shards of it.
+pub fn remove_sharded(path: &Path) -> Result<()> {
+    if path.exists() {
+        std::fs::remove_file(path)?;
+        checkpoint("removing a file")?;
+    }
+    remove_shards(path)
+}
+
+/// Splits a file into shards of at most the given size, files that
+/// already fit are left whole.
+pub fn split_file(path: &Path, max_size: u64) -> Result<()> {
+    if max_size == 0 {
+        return Err(anyhow!("The shard size has to be at least one byte."));
+    }
+    // Shards from an earlier split would be mistaken for part of this one.
+    remove_shards(path)?;
+    let length = std::fs::metadata(path)?.len();
+    if length <= max_size {
+        return Ok(());
+    }
+
+    let mut source = File::open(path)?;
+    let count = length.div_ceil(max_size) as usize;
+    for index in 0..count {
+        let mut out = BufWriter::new(File::create(shard_path(path, index))?);
+        std::io::copy(&mut (&mut source).take(max_size), &mut out)?;
+        out.into_inner()?.sync_all()?;
+        checkpoint("writing a shard")?;
+    }
+    let temp = manifest_path(path).with_extension("temp");
+    std::fs::write(&temp, toml::to_string(&ShardManifest { count, length })?)?;
+    atomicwrites::replace_atomic(&temp, &manifest_path(path))?;
+    checkpoint("writing a shard manifest")?;
+
+    // The whole file is only removed once every shard is on the disk.
+    std::fs::remove_file(path)?;
+    checkpoint("splitting a file")?;
+    Ok(())
+}
+
+/// Joins the shards of a file back together, returning the size they
+/// were split at. A whole file always wins over shards, which are only
+/// left next to it when a split or join was interrupted.
+pub fn join_file(path: &Path) -> Result<Option<u64>> {
+    if path.exists() || !exists_sharded(path) {
+        remove_shards(path)?;
+        return Ok(None);
+    }
+
+    let shards = verified_shards(path)?;
+    let size = std::fs::metadata(&shards[0])?.len();
+    let temp = shard_path(path, 0).with_extension("join");
+    let mut out = BufWriter::new(File::create(&temp)?);
+    for shard in &shards {
+        std::io::copy(&mut File::open(shard)?, &mut out)?;
+    }
+    out.into_inner()?.sync_all()?;
+    std::fs::rename(&temp, path)?;
+    checkpoint("joining a file")?;
+
+    remove_shards(path)?;
+    Ok(Some(size))
+}
+
+/// Opens a file for reading, chaining its shards together if it was split.
+pub fn open_sharded(path: &Path) -> Result<Box<dyn Read>> {
+    if path.exists() {
+        return Ok(Box::new(File::open(path)?));
+    }
+    if !exists_sharded(path) {
+        return Err(anyhow!("Could not find {path:?} or any shards of it."));
+    }
+    let mut reader: Box<dyn Read> = Box::new(std::io::empty());
+    for shard in verified_shards(path)? {
+        reader = Box::new(reader.chain(File::open(shard)?));
+    }
+    Ok(reader)
+}
+
+/// Reads a file whole, joining its shards in memory if it was split.
+pub fn read_sharded(path: &Path) -> Result<Vec<u8>> {
+    let mut bytes = vec![];
+    open_sharded(path)?.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Moves a file and its shards over to a new name, replacing whatever
+/// was there before. Nothing happens when there is nothing to move.
+///
+/// The last shard is moved first and the manifest last, so an interrupted
+/// move is finished by moving again without the shards that already made
+/// it over being lost.
+pub fn move_sharded(from: &Path, to: &Path) -> Result<()> {
+    if !from.exists() && !shard_path(from, 0).exists() {
+        // Every shard made it over, only the manifest is left to follow.
+        if manifest_path(from).exists() {
+            std::fs::rename(manifest_path(from), manifest_path(to))?;
+            checkpoint("moving a shard manifest")?;
+        }
+        return Ok(());
+    }
+    remove_sharded(to)?;
+    if from.exists() {
+        std::fs::rename(from, to)?;
+        checkpoint("moving a file")?;
+    }
+    for index in shard_indices(from).into_iter().rev() {
+        std::fs::rename(shard_path(from, index), shard_path(to, index))?;
+        checkpoint("moving a shard")?;
+    }
+    if manifest_path(from).exists() {
+        std::fs::rename(manifest_path(from), manifest_path(to))?;
+        checkpoint("moving a shard manifest")?;
+    }
+    Ok(())
+}
+
+/// Runs an operation that rewrites the vault binary with its shards joined
+/// and any damage repaired, splitting it again at the same size afterwards.
+pub fn with_joined<T>(root: &RootPath<Normal>, operation: impl FnOnce() -> Result<T>) -> Result<T> {
+    let vault = join_file(&root.vault_binary())?;
+    let parity = join_file(&root.vault_parity())?;
+
+    // The binary is only ever replaced whole, so it is split again even
+    // when the operation fails, and that failure is the one reported.
+    let result = repair_before_rewrite(&root.vault_binary(), &root.vault_parity()).and_then(|_| operation());
+    let resplit = split_joined(root, vault, parity);
+    let result = result?;
+    resplit?;
+    Ok(result)
+}
+
+fn split_joined(root: &RootPath<Normal>, vault: Option<u64>, parity: Option<u64>) -> Result<()> {
+    if let Some(size) = vault {
+        split_file(&root.vault_binary(), size)?;
+    }
+    if let Some(size) = parity && root.vault_parity().exists() {
+        split_file(&root.vault_parity(), size)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sys::{
+        fault::{arm, disarm},
+        lib::path::RootPath,
+        shards::{exists_sharded, join_file, manifest_path, move_sharded, read_sharded, shard_path, shard_paths, split_file, with_joined},
+    };
+
+    #[test]
+    pub fn check_shard_roundtrip() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("shards");
+        std::fs::create_dir_all(&dir).unwrap();
+        let vault = dir.join("vault.bin");
+
+        let mut contents = vec![0u8; 10_000];
+        rand::fill(&mut contents[..]);
+        std::fs::write(&vault, &contents).unwrap();
+
+        split_file(&vault, 3000).unwrap();
+        assert!(!vault.exists());
+        assert_eq!(shard_paths(&vault).len(), 4);
+        assert_eq!(std::fs::metadata(shard_path(&vault, 3)).unwrap().len(), 1000);
+        assert_eq!(read_sharded(&vault).unwrap(), contents);
+
+        // A missing or cut short shard is refused instead of ending the file early.
+        let last = std::fs::read(shard_path(&vault, 3)).unwrap();
+        std::fs::remove_file(shard_path(&vault, 3)).unwrap();
+        assert!(read_sharded(&vault).is_err());
+        std::fs::write(shard_path(&vault, 3), &last[..999]).unwrap();
+        assert!(read_sharded(&vault).is_err());
+        assert!(join_file(&vault).is_err());
+        std::fs::write(shard_path(&vault, 3), &last).unwrap();
+
+        assert_eq!(join_file(&vault).unwrap(), Some(3000));
+        assert_eq!(std::fs::read(&vault).unwrap(), contents);
+        assert!(shard_paths(&vault).is_empty());
+        assert!(!manifest_path(&vault).exists());
+
+        // Splitting again clears out the shards of the earlier split.
+        split_file(&vault, 6000).unwrap();
+        assert_eq!(shard_paths(&vault).len(), 2);
+        std::fs::write(&vault, &contents).unwrap();
+        split_file(&vault, 20_000).unwrap();
+        assert!(vault.exists());
+        assert!(shard_paths(&vault).is_empty());
+
+        // A move that is interrupted anywhere is finished by moving again,
+        // the longer file that it replaces included.
+        split_file(&vault, 3000).unwrap();
+        let moved = dir.join("moved.bin");
+        for after in 0.. {
+            std::fs::write(&moved, [1u8; 20_000]).unwrap();
+            split_file(&moved, 3000).unwrap();
+
+            arm(after);
+            let result = move_sharded(&vault, &moved);
+            let fired = !disarm();
+            if fired {
+                assert!(result.is_err());
+                move_sharded(&vault, &moved).unwrap();
+            } else {
+                result.unwrap();
+            }
+            assert!(!exists_sharded(&vault));
+            assert_eq!(read_sharded(&moved).unwrap(), contents);
+            if !fired {
+                break;
+            }
+            move_sharded(&moved, &vault).unwrap();
+        }
+
+        // The vault is split again even when the operation on it fails.
+        let root = RootPath::new(&dir);
+        move_sharded(&moved, &root.vault_binary()).unwrap();
+        assert!(with_joined(&root, || -> anyhow::Result<()> {
+            assert!(root.vault_binary().exists());
+            Err(anyhow::anyhow!("The operation failed."))
+        })
+        .is_err());
+        assert!(!root.vault_binary().exists());
+        assert_eq!(read_sharded(&root.vault_binary()).unwrap(), contents);
+    }
+}
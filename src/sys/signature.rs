@@ -0,0 +1,363 @@
+use std::{fmt::Display, io::{Read, Write}, path::Path, str::FromStr};
+
+use anyhow::{Result, anyhow};
+use bech32::{Bech32, Hrp};
+use ed25519_dalek::{Signature, Signer as _, SigningKey, VerifyingKey};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use zeroize::Zeroizing;
+
+use crate::{
+    console_log,
+    sys::{
+        header::{FormatVersion, HEADER_SIZE, VaultHeader},
+        lib::path::{Normal, RootPath},
+        mk::MasterVaultKey,
+        shards::open_sharded,
+        statefile::string_to_hashmap,
+    },
+};
+
+/// The prefix of an encoded signer.
+const SIGNER_HRP: &str = "novosig";
+
+/// The prefix of an encoded device key, these are written in upper case.
+const DEVICE_KEY_HRP: &str = "novo-device-key-";
+
+/// Binds a signature to its purpose, so it can not be replayed elsewhere.
+const SIGNATURE_CONTEXT: &[u8] = b"novovault vault signature v1";
+
+/// Derives the key that the trusted signers are authenticated with.
+const SIGNERS_TAG_INFO: &[u8] = b"novovault trusted signers v1";
+
+/// The public half of a device key, this is what the trusted signers
+/// list holds.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Signer(VerifyingKey);
+
+impl FromStr for Signer {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (hrp, data) = bech32::decode(s.trim())
+            .map_err(|e| anyhow!("The signer is not a valid public key: {e}"))?;
+        if !hrp.as_str().eq_ignore_ascii_case(SIGNER_HRP) {
+            return Err(anyhow!("The signer should start with '{SIGNER_HRP}1'."));
+        }
+        let bytes: [u8; 32] = data
+            .try_into()
+            .map_err(|_| anyhow!("The signer is of the incorrect size."))?;
+        Ok(Self(
+            VerifyingKey::from_bytes(&bytes).map_err(|_| anyhow!("The signer is not a valid public key."))?,
+        ))
+    }
+}
+
+impl Display for Signer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let encoded = bech32::encode::<Bech32>(Hrp::parse_unchecked(SIGNER_HRP), self.0.as_bytes())
+            .map_err(|_| std::fmt::Error)?;
+        write!(f, "{encoded}")
+    }
+}
+
+/// The signing key of this device, it is kept next to the vault
+/// but never leaves the device.
+pub struct DeviceKey(SigningKey);
+
+impl DeviceKey {
+    pub fn generate() -> Self {
+        Self(SigningKey::from_bytes(&rand::random::<[u8; 32]>()))
+    }
+    pub fn signer(&self) -> Signer {
+        Signer(self.0.verifying_key())
+    }
+    /// Loads the key of this device, generating one the first time.
+    pub fn load_or_generate(root: &RootPath<Normal>) -> Result<Self> {
+        let path = root.device_key();
+        if path.exists() {
+            return Self::from_file(&path);
+        }
+
+        let key = Self::generate();
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options.open(&path)?.write_all(key.to_file_contents()?.as_bytes())?;
+
+        console_log!(Info, "Generated a signing key for this device, its public key is {}.", key.signer());
+        Ok(key)
+    }
+    /// Reads a device key file, blank lines and lines starting
+    /// with '#' are skipped.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let contents = Zeroizing::new(
+            std::fs::read_to_string(path)
+                .map_err(|e| anyhow!("Could not read the device key at {path:?}: {e}"))?,
+        );
+        let Some(line) = contents
+            .lines()
+            .map(str::trim)
+            .find(|l| !l.is_empty() && !l.starts_with('#'))
+        else {
+            return Err(anyhow!("The device key file does not contain a key."));
+        };
+
+        let (hrp, data) = bech32::decode(line)
+            .map_err(|e| anyhow!("The device key file does not contain a valid key: {e}"))?;
+        if !hrp.as_str().eq_ignore_ascii_case(DEVICE_KEY_HRP) {
+            return Err(anyhow!("The device key file does not contain a novovault device key."));
+        }
+        let data = Zeroizing::new(data);
+        let bytes: [u8; 32] = data
+            .as_slice()
+            .try_into()
+            .map_err(|_| anyhow!("The device key is of the incorrect size."))?;
+        Ok(Self(SigningKey::from_bytes(&bytes)))
+    }
+    /// The contents of a device key file, the public key is noted in a comment.
+    pub fn to_file_contents(&self) -> Result<Zeroizing<String>> {
+        let encoded = bech32::encode_upper::<Bech32>(Hrp::parse_unchecked(DEVICE_KEY_HRP), self.0.as_bytes())
+            .map_err(|e| anyhow!("Failed to encode the device key: {e}"))?;
+        Ok(Zeroizing::new(format!("# public key: {}\n{encoded}\n", self.signer())))
+    }
+    /// Signs a vault binary, which may be split into shards.
+    pub fn sign(&self, vault: &Path) -> Result<VaultSignature> {
+        Ok(VaultSignature {
+            signer: self.signer(),
+            signature: self.0.sign(&signed_message(vault)?),
+        })
+    }
+}
+
+/// The devices whose vaults are accepted, each under a label that
+/// says who they belong to.
+#[derive(Clone, Default)]
+pub struct TrustedSigners(Vec<(String, Signer)>);
+
+impl TrustedSigners {
+    pub fn iter(&self) -> impl Iterator<Item = &(String, Signer)> {
+        self.0.iter()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+    pub fn add(&mut self, label: &str, signer: Signer) -> Result<()> {
+        if let Some((existing, _)) = self.0.iter().find(|(l, s)| l == label || *s == signer) {
+            return Err(anyhow!("The signer is already trusted as '{existing}'."));
+        }
+        self.0.push((label.to_string(), signer));
+        Ok(())
+    }
+    pub fn remove(&mut self, label: &str) -> Result<()> {
+        let before = self.0.len();
+        self.0.retain(|(l, _)| l != label);
+        if self.0.len() == before {
+            return Err(anyhow!("There is no trusted signer labelled '{label}'."));
+        }
+        Ok(())
+    }
+    /// The label that a signer is trusted under.
+    pub fn find(&self, signer: &Signer) -> Option<&str> {
+        self.0.iter().find(|(_, s)| s == signer).map(|(l, _)| l.as_str())
+    }
+    /// The list is kept in the synced state file, so it is authenticated under
+    /// the master key to stop anyone who can only push from changing it.
+    fn mac(&self, master: &MasterVaultKey) -> Result<Hmac<Sha256>> {
+        let mut key = Zeroizing::new([0u8; 32]);
+        Hkdf::<Sha256>::new(None, master.key_bytes())
+            .expand(SIGNERS_TAG_INFO, &mut key[..])
+            .map_err(|_| anyhow!("Failed to derive the key of the trusted signers."))?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(&key[..])
+            .map_err(|_| anyhow!("Failed to authenticate the trusted signers."))?;
+        for (label, signer) in &self.0 {
+            mac.update(&(label.len() as u32).to_le_bytes());
+            mac.update(label.as_bytes());
+            mac.update(signer.0.as_bytes());
+        }
+        Ok(mac)
+    }
+    pub fn tag(&self, master: &MasterVaultKey) -> Result<String> {
+        Ok(hex::encode(self.mac(master)?.finalize().into_bytes()))
+    }
+    pub fn verify_tag(&self, master: &MasterVaultKey, tag: &str) -> Result<()> {
+        self.mac(master)?
+            .verify_slice(&hex::decode(tag)?)
+            .map_err(|_| anyhow!("The trusted signers were changed without the master key, so none of them can be relied on."))
+    }
+}
+
+/// A signature over a vault binary along with the device that made it.
+pub struct VaultSignature {
+    pub signer: Signer,
+    signature: Signature,
+}
+
+impl VaultSignature {
+    /// Reads the signature next to a vault, vaults sealed before signatures
+    /// existed do not have one.
+    pub fn read(path: &Path) -> Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let fields = string_to_hashmap(&std::fs::read_to_string(path)?);
+        let signer = fields
+            .get("signer")
+            .ok_or_else(|| anyhow!("The vault signature does not name its signer."))?
+            .parse()?;
+        let bytes: [u8; 64] = hex::decode(
+            fields
+                .get("signature")
+                .ok_or_else(|| anyhow!("The vault signature is missing."))?,
+        )?
+        .try_into()
+        .map_err(|_| anyhow!("The vault signature is of the incorrect size."))?;
+        Ok(Some(Self {
+            signer,
+            signature: Signature::from_bytes(&bytes),
+        }))
+    }
+    pub fn write(&self, path: &Path) -> Result<()> {
+        std::fs::write(
+            path,
+            format!("signer={}\nsignature={}\n", self.signer, hex::encode(self.signature.to_bytes())),
+        )?;
+        Ok(())
+    }
+    pub fn verify(&self, vault: &Path) -> Result<()> {
+        self.signer
+            .0
+            .verify_strict(&signed_message(vault)?, &self.signature)
+            .map_err(|_| anyhow!("The signature does not match the vault, it was modified after it was signed."))
+    }
+}
+
+/// What gets signed, the hash of the vault binary without its key block.
+///
+/// The key block is left out for the same reason that it is left out of the
+/// associated data, so passwords and recipients can change without a new
+/// signature. Everything else, the generation included, is covered.
+fn signed_message(vault: &Path) -> Result<Vec<u8>> {
+    let mut reader = open_sharded(vault)?;
+    let mut hasher = Sha256::new();
+
+    let mut header = [0u8; HEADER_SIZE];
+    reader
+        .read_exact(&mut header)
+        .map_err(|_| anyhow!("The vault binary is too short to contain a header."))?;
+    hasher.update(header);
+
+    if VaultHeader::parse(&header)?.version >= FormatVersion::EmbeddedKeys {
+        let mut length = [0u8; 4];
+        reader
+            .read_exact(&mut length)
+            .map_err(|_| anyhow!("The vault binary is too short to contain its key block."))?;
+        std::io::copy(&mut (&mut reader).take(u32::from_le_bytes(length) as u64), &mut std::io::sink())?;
+    }
+    std::io::copy(&mut reader, &mut hasher)?;
+
+    let mut message = SIGNATURE_CONTEXT.to_vec();
+    message.extend_from_slice(&hasher.finalize());
+    Ok(message)
+}
+
+/// Signs the sealed vault with the key of this device.
+pub fn sign_vault(root: &RootPath<Normal>) -> Result<()> {
+    DeviceKey::load_or_generate(root)?
+        .sign(&root.vault_binary())?
+        .write(&root.vault_signature())
+}
+
+/// Checks that the sealed vault was signed by a trusted device, nothing is
+/// enforced until the first signer is trusted.
+pub fn check_signature(root: &RootPath<Normal>, trusted: &TrustedSigners) -> Result<()> {
+    if trusted.is_empty() {
+        return Ok(());
+    }
+    let signature = VaultSignature::read(&root.vault_signature())?
+        .ok_or_else(|| anyhow!("The vault is not signed, but only vaults from trusted devices are accepted."))?;
+    let Some(label) = trusted.find(&signature.signer) else {
+        return Err(anyhow!("The vault was signed by an unknown device ({}).", signature.signer));
+    };
+    signature.verify(&root.vault_binary())?;
+
+    console_log!(Info, "The vault was signed by '{label}'.");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sys::{
+        lib::path::RootPath,
+        mk::MasterVaultKey,
+        signature::{DeviceKey, Signer, TrustedSigners, check_signature, sign_vault},
+        statefile::{STATE_VERSION, StateFileHandle},
+    };
+
+    #[test]
+    pub fn check_vault_signature() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("signature");
+        let root = RootPath::new(&dir);
+        std::fs::create_dir_all(root.metadata_folder()).unwrap();
+
+        let mut vault = b"NOVO\x05\0\0\0".to_vec();
+        vault.extend_from_slice(&3u32.to_le_bytes());
+        vault.extend_from_slice(b"abc");
+        vault.extend_from_slice(&[7u8; 100]);
+        std::fs::write(root.vault_binary(), &vault).unwrap();
+
+        sign_vault(&root).unwrap();
+        let device = DeviceKey::from_file(&root.device_key()).unwrap();
+        let signer: Signer = device.signer().to_string().parse().unwrap();
+
+        // Nothing is enforced without trusted signers.
+        check_signature(&root, &TrustedSigners::default()).unwrap();
+
+        let mut other = TrustedSigners::default();
+        other.add("other", DeviceKey::generate().signer()).unwrap();
+        assert!(check_signature(&root, &other).is_err());
+
+        let mut trusted = TrustedSigners::default();
+        trusted.add("laptop", signer).unwrap();
+        assert!(trusted.add("desktop", signer).is_err());
+        check_signature(&root, &trusted).unwrap();
+
+        // The key block can change, the rest of the binary can not.
+        vault[12] = b'x';
+        std::fs::write(root.vault_binary(), &vault).unwrap();
+        check_signature(&root, &trusted).unwrap();
+        vault[20] = 8;
+        std::fs::write(root.vault_binary(), &vault).unwrap();
+        assert!(check_signature(&root, &trusted).is_err());
+
+        // The list only counts when it was written with the master key.
+        let master = MasterVaultKey::generate();
+        let mut handle = StateFileHandle::new(&dir).unwrap();
+        assert!(handle.get_trusted_signers(&master).unwrap().is_empty());
+        handle.set_trusted_signers(&trusted, &master).unwrap();
+        handle.writeback().unwrap();
+        let mut handle = StateFileHandle::new(&dir).unwrap();
+        assert!(handle.get_trusted_signers(&master).unwrap().find(&signer) == Some("laptop"));
+        assert!(handle.get_trusted_signers(&MasterVaultKey::generate()).is_err());
+
+        let state = root.metadata_folder().join(".state");
+        let contents = std::fs::read_to_string(&state).unwrap();
+        let swapped = contents.replace(&signer.to_string(), &DeviceKey::generate().signer().to_string());
+        std::fs::write(&state, swapped).unwrap();
+        assert!(StateFileHandle::new(&dir).unwrap().get_trusted_signers(&master).is_err());
+
+        // Dropping the list from the state file does not turn the check off.
+        std::fs::write(&state, format!("version = {STATE_VERSION}\n")).unwrap();
+        assert!(StateFileHandle::new(&dir).unwrap().get_trusted_signers(&master).is_err());
+
+        // Emptying it with the master key does.
+        std::fs::write(&state, contents).unwrap();
+        let mut handle = StateFileHandle::new(&dir).unwrap();
+        handle.set_trusted_signers(&TrustedSigners::default(), &master).unwrap();
+        handle.writeback().unwrap();
+        assert!(StateFileHandle::new(&dir).unwrap().get_trusted_signers(&master).unwrap().is_empty());
+    }
+}
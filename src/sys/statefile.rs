@@ -1,156 +1,702 @@
-use std::{collections::HashMap, hash::Hash, path::{Path, PathBuf}, str::FromStr};
+use std::{collections::HashMap, path::{Path, PathBuf}, str::FromStr};
 use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
 use strum::EnumString;
-use crate::sys::{mk::WrappedKey, procedure::actions::VaultState};
+use crate::{console_log, sys::{compression::CompressionPolicy, header::CompressionId, mk::{DEFAULT_SLOT, KdfParams, KeySlots, MasterVaultKey, WrappedKey}, procedure::actions::VaultState, recipient::{RecipientKey, Recipients}, signature::TrustedSigners}};
 
+/// The version of the state file that this build writes, files of
+/// a newer version are refused rather than misread.
+pub const STATE_VERSION: u32 = 2;
 
+/// The version of the device-local state file that this build writes.
+pub const LOCAL_STATE_VERSION: u32 = 1;
+
+const SLOT_PREFIX: &str = "slot.";
+
+/// The slots wrapping a new master key while it is being rotated in.
+const PENDING_SLOT_PREFIX: &str = "pending.";
+
+const RECIPIENT_PREFIX: &str = "recipient.";
+
+/// The recipients of a new master key while it is being rotated in.
+const PENDING_RECIPIENT_PREFIX: &str = "pending-recipient.";
+
+const SIGNER_PREFIX: &str = "signer.";
+
+
+/// Both halves of the vault state, the shared metadata in `.nov/.state`
+/// and the state of this device in `.nov/.local-state`.
 pub struct StateFileHandle {
     path: PathBuf,
-    state: HashMap<String, String>
+    state: StateDocument,
+    local: LocalDocument,
+    read_only: bool
 }
 
-#[derive(EnumString, strum::AsRefStr, PartialEq, Eq)]
+#[derive(EnumString, strum::AsRefStr, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
 pub enum SyncMethod {
     Git,
     TigrisS3
 
 }
 
+/// The contents of `.nov/.state`, written as TOML. This travels with the
+/// vault, so it only holds what every device needs to agree on.
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+struct StateDocument {
+    version: u32,
+    remote: Option<String>,
+    remote_backend: Option<SyncMethod>,
+    kdf: Option<KdfParams>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    slots: Vec<SlotEntry>,
+    /// The slots wrapping a new master key while it is being rotated in.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pending_slots: Vec<SlotEntry>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    recipients: Vec<RecipientEntry>,
+    /// The recipients of a new master key while it is being rotated in.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pending_recipients: Vec<RecipientEntry>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    signers: Vec<SignerEntry>,
+    /// Authenticates the signers under the master key, see [`TrustedSigners::tag`].
+    signers_tag: Option<String>,
+    /// The tag of the signers under a master key that is being rotated in.
+    pending_signers_tag: Option<String>,
+    /// The codec and level of the last seal, so that a rotation can
+    /// recompress without the config, which is sealed away.
+    compression: Option<CompressionId>,
+    compression_level: Option<i32>,
+}
+
+/// The contents of `.nov/.local-state`, written as TOML. This never
+/// leaves the device.
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+struct LocalDocument {
+    version: u32,
+    state: Option<VaultState>,
+    init: Option<bool>,
+    prev_stamp_t3: Option<String>,
+    /// The highest generation of the vault that this device has seen.
+    seen_generation: u64,
+    /// Whether this device has trusted any signer, after which the
+    /// signers going missing from the state file is refused.
+    signers_required: bool,
+}
+
+/// The device state that `.nov/.state` held before it was split.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct LegacyDeviceState {
+    state: Option<VaultState>,
+    init: Option<bool>,
+    prev_stamp_t3: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct SlotEntry {
+    label: String,
+    key: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct RecipientEntry {
+    public_key: String,
+    key: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct SignerEntry {
+    label: String,
+    public_key: String,
+}
+
 impl StateFileHandle {
     pub fn new(path: impl AsRef<Path>) -> Result<Self> {
         let mut obj = Self {
             path: path.as_ref().to_path_buf(),
-            state: HashMap::default()
+            state: StateDocument::default(),
+            local: LocalDocument::default(),
+            read_only: false
+        };
+        obj._load()?;
+        Ok(obj)
+    }
+    /// Opens the state without writing anything, not even a migration. Such
+    /// a handle cannot be written back.
+    pub fn open_read_only(path: impl AsRef<Path>) -> Result<Self> {
+        let mut obj = Self {
+            path: path.as_ref().to_path_buf(),
+            state: StateDocument::default(),
+            local: LocalDocument::default(),
+            read_only: true
         };
         obj._load()?;
         Ok(obj)
     }
     fn _load(&mut self)  -> Result<()> {
-        self.state = read_hashmap(&self.path)?;
+        let persist = !self.read_only;
+        let (state, legacy) = read_state(&self.path, persist)?;
+        self.local = read_local_state(&self.path, &state, legacy.as_ref(), persist)?;
+
+        // The shared file is only rewritten once the device state that it
+        // held is safely on the disk.
+        if legacy.is_some() && persist {
+            write_state(&self.path, &state)?;
+            console_log!(Info, "Migrated the state file to version {STATE_VERSION}.");
+        }
+        self.state = state;
         Ok(())
     }
     pub fn reload(&mut self) -> Result<()> {
         self._load()
     }
     pub fn set_state(&mut self, state: VaultState) {
-        self.state.insert("state".to_string(), format!("{state:?}"));
+        self.local.state = Some(state);
     }
     pub fn set_init(&mut self, init: bool) {
-        self.state.insert("init".to_string(), format!("{init:?}"));
+        self.local.init = Some(init);
 
     }
     pub fn get_init(&self) -> Result<bool> {
-        Ok(self.state.get("init").map(|f| bool::from_str(f)).ok_or_else(|| anyhow!("Could not find 'init' in state file."))??)
+        self.local.init.ok_or_else(|| anyhow!("Could not find 'init' in state file."))
     }
     pub fn set_remote(&mut self, url: &str) {
-        self.state.insert("remote".to_string(), url.to_string());
+        self.state.remote = Some(url.to_string());
     }
     pub fn set_remote_storage(&mut self, method: SyncMethod) {
-        self.state.insert("remote_backend".to_string(), method.as_ref().to_string());
+        self.state.remote_backend = Some(method);
     }
     pub fn previous_tigris_commit_stamp(&mut self, stamp: &str) {
         // println!("Stamp: {stamp}");
-        self.state.insert("prev_stamp_t3".to_string(), stamp.to_string());
+        self.local.prev_stamp_t3 = Some(stamp.to_string());
     }
     pub fn get_previous_tigris_commit_stamp(&self) -> Result<String> {
-        self.state.get("prev_stamp_t3").ok_or_else(|| anyhow!("Failed to get the Tigris commit stamp.")).cloned()
+        self.local.prev_stamp_t3.clone().ok_or_else(|| anyhow!("Failed to get the Tigris commit stamp."))
     }
     pub fn get_remote_storage(&self) -> Result<Option<SyncMethod>> {
-        match self.state.get("remote_backend") {
-            Some(v) => Ok(Some(SyncMethod::from_str(v)?)),
-            None => Ok(None)
-        }
+        Ok(self.state.remote_backend)
     }
     pub fn get_remote(&self) -> Option<String> {
-        self.state.get("remote").map(|f| f.to_string())
+        self.state.remote.clone()
     }
-    pub fn set_master_key(&mut self, key: &WrappedKey) {
-        self.state.insert("wrapped".to_string(), key.to_hex());
+    /// Sets the Argon2id parameters that key slots should be wrapped with.
+    pub fn set_kdf_params(&mut self, params: KdfParams) {
+        self.state.kdf = Some(params);
     }
-    pub fn get_wrapped_key(&self) -> Result<WrappedKey> {
-        WrappedKey::from_hex(self.state.get("wrapped").ok_or_else(|| anyhow!("Failed to lookup the wrapped key."))?)
+    /// The Argon2id parameters that key slots should be wrapped with, slots
+    /// made with anything else are rewrapped the next time they unlock.
+    pub fn get_kdf_params(&self) -> Result<KdfParams> {
+        match self.state.kdf {
+            Some(v) => KdfParams::new(v.memory_kib, v.iterations, v.parallelism),
+            None => Ok(KdfParams::default())
+        }
     }
-    pub fn get_state(&mut self) -> Result<VaultState> {
-        match self.state.get("state") {
-            Some(v) => Ok(VaultState::from_str(v)?),
-            None => Ok(VaultState::Uninit)
+    /// Replaces every key slot.
+    pub fn set_key_slots(&mut self, slots: &KeySlots) {
+        self.state.slots = slot_entries(slots);
+    }
+    pub fn get_key_slots(&self) -> Result<KeySlots> {
+        let slots = read_slots(&self.state.slots)?;
+        if slots.is_empty() {
+            return Err(anyhow!("Failed to lookup the wrapped key."));
         }
-        // Ok(VaultState::from_str(self.state.get("state").ok_or_else(|| anyhow!("Failed to get the state."))?)?)
+        Ok(slots)
+    }
+    /// Stores the key slots of a master key that is being rotated in.
+    pub fn set_pending_key_slots(&mut self, slots: &KeySlots) {
+        self.state.pending_slots = slot_entries(slots);
+    }
+    pub fn get_pending_key_slots(&self) -> Result<Option<KeySlots>> {
+        let slots = read_slots(&self.state.pending_slots)?;
+        Ok((!slots.is_empty()).then_some(slots))
+    }
+    /// Clears the pending key slots along with the pending recipients.
+    pub fn clear_pending_key_slots(&mut self) {
+        self.state.pending_slots.clear();
+        self.state.pending_recipients.clear();
+        self.state.pending_signers_tag = None;
+    }
+    /// Replaces every recipient.
+    pub fn set_recipients(&mut self, recipients: &Recipients) {
+        self.state.recipients = recipient_entries(recipients);
+    }
+    pub fn get_recipients(&self) -> Result<Recipients> {
+        read_recipients(&self.state.recipients)
+    }
+    /// The key slots and recipients as they are embedded in the vault binary.
+    pub fn key_block(&self) -> Result<String> {
+        Ok(encode_key_block(&self.get_key_slots()?, &self.get_recipients()?))
+    }
+    /// Stores the recipients of a master key that is being rotated in,
+    /// these are cleared and committed along with the pending key slots.
+    pub fn set_pending_recipients(&mut self, recipients: &Recipients) {
+        self.state.pending_recipients = recipient_entries(recipients);
+    }
+    pub fn get_pending_recipients(&self) -> Result<Recipients> {
+        read_recipients(&self.state.pending_recipients)
+    }
+    /// Replaces the trusted signers, authenticated under the master key.
+    pub fn set_trusted_signers(&mut self, signers: &TrustedSigners, master: &MasterVaultKey) -> Result<()> {
+        self.state.signers = signers
+            .iter()
+            .map(|(label, signer)| SignerEntry {
+                label: label.clone(),
+                public_key: signer.to_string(),
+            })
+            .collect();
+        self.state.signers_tag = Some(signers.tag(master)?);
+        self.local.signers_required = !signers.is_empty();
+        Ok(())
+    }
+    /// The trusted signers, once they are checked against their tag. An
+    /// empty list is only accepted if it was emptied with the master key
+    /// or this device never trusted anyone.
+    pub fn get_trusted_signers(&mut self, master: &MasterVaultKey) -> Result<TrustedSigners> {
+        let signers = self.get_unverified_trusted_signers()?;
+        match &self.state.signers_tag {
+            Some(tag) => signers.verify_tag(master, tag)?,
+            None if !signers.is_empty() => {
+                return Err(anyhow!("The trusted signers are not authenticated, so none of them can be relied on."));
+            }
+            None if self.local.signers_required => {
+                return Err(anyhow!("The trusted signers were removed from the state file without the master key, so the vault is refused."));
+            }
+            None => {}
+        }
+        self.local.signers_required = !signers.is_empty();
+        Ok(signers)
+    }
+    /// The trusted signers as the state file has them, for display only.
+    pub fn get_unverified_trusted_signers(&self) -> Result<TrustedSigners> {
+        let mut signers = TrustedSigners::default();
+        for entry in &self.state.signers {
+            signers.add(&entry.label, entry.public_key.parse()?)?;
+        }
+        Ok(signers)
+    }
+    /// Authenticates the trusted signers under a master key that is being
+    /// rotated in, the tag is committed along with the pending key slots.
+    pub fn set_pending_trusted_signers(&mut self, signers: &TrustedSigners, master: &MasterVaultKey) -> Result<()> {
+        self.state.pending_signers_tag = Some(signers.tag(master)?);
+        Ok(())
+    }
+    pub fn commit_pending_trusted_signers(&mut self) {
+        if let Some(tag) = self.state.pending_signers_tag.take() {
+            self.state.signers_tag = Some(tag);
+        }
+    }
+    /// Records the default codec and level that the vault was sealed with.
+    pub fn set_compression(&mut self, policy: &CompressionPolicy) {
+        self.state.compression = Some(policy.default_codec());
+        self.state.compression_level = policy.default_level();
+    }
+    /// The codec and level of the last seal, without the per extension
+    /// overrides since every entry already records its own codec.
+    pub fn get_compression(&self) -> Result<CompressionPolicy> {
+        match self.state.compression {
+            Some(codec) => CompressionPolicy::new(codec, self.state.compression_level, HashMap::new()),
+            None => Ok(CompressionPolicy::default())
+        }
+    }
+    pub fn set_seen_generation(&mut self, generation: u64) {
+        self.local.seen_generation = generation;
+    }
+    pub fn get_seen_generation(&self) -> u64 {
+        self.local.seen_generation
+    }
+    /// Where the state of this device is kept.
+    pub fn local_path(&self) -> PathBuf {
+        self.path.join(".nov").join(".local-state")
+    }
+    pub fn get_state(&mut self) -> Result<VaultState> {
+        Ok(self.local.state.unwrap_or(VaultState::Uninit))
     }
     pub fn writeback(&mut self) -> Result<()> {
-        write_meta_status(&self.path, &self.state)?;
+        if self.read_only {
+            return Err(anyhow!("The state file was opened read-only and cannot be written."));
+        }
+        write_local_state(&self.path, &self.local)?;
+        write_state(&self.path, &self.state)?;
 
         Ok(())
     }
 }
 
+fn slot_entries(slots: &KeySlots) -> Vec<SlotEntry> {
+    slots
+        .iter()
+        .map(|slot| SlotEntry {
+            label: slot.label.clone(),
+            key: slot.key.to_hex(),
+        })
+        .collect()
+}
+
+fn read_slots(entries: &[SlotEntry]) -> Result<KeySlots> {
+    let mut slots = KeySlots::default();
+    for entry in entries {
+        slots.insert(&entry.label, WrappedKey::from_hex(&entry.key)?)?;
+    }
+    Ok(slots)
+}
 
+fn recipient_entries(recipients: &Recipients) -> Vec<RecipientEntry> {
+    recipients
+        .iter()
+        .map(|(recipient, key)| RecipientEntry {
+            public_key: recipient.to_string(),
+            key: key.to_hex(),
+        })
+        .collect()
+}
 
+fn read_recipients(entries: &[RecipientEntry]) -> Result<Recipients> {
+    let mut recipients = Recipients::default();
+    for entry in entries {
+        recipients.insert(entry.public_key.parse()?, RecipientKey::from_hex(&entry.key)?);
+    }
+    Ok(recipients)
+}
 
-pub fn read_hashmap(root: impl AsRef<Path>) -> Result<HashMap<String, String>> {
+/// Writes the key slots and recipients in the `key=value` form that
+/// the state file used to have, this is what gets embedded in the vault binary.
+pub fn encode_key_block(slots: &KeySlots, recipients: &Recipients) -> String {
+    let mut block = String::new();
+    for slot in slots.iter() {
+        block.push_str(&format!("{SLOT_PREFIX}{}={}\n", slot.label, slot.key.to_hex()));
+    }
+    for (recipient, key) in recipients.iter() {
+        block.push_str(&format!("{RECIPIENT_PREFIX}{recipient}={}\n", key.to_hex()));
+    }
+    block
+}
+
+pub fn decode_key_block(block: &str) -> Result<(KeySlots, Recipients)> {
+    let state = string_to_hashmap(block);
+    let slots = read_prefixed_slots(&state, SLOT_PREFIX)?;
+    if slots.is_empty() {
+        return Err(anyhow!("The vault binary does not contain any key slots."));
+    }
+    Ok((slots, read_prefixed_recipients(&state, RECIPIENT_PREFIX)?))
+}
+
+fn read_prefixed_slots(state: &HashMap<String, String>, prefix: &str) -> Result<KeySlots> {
+    let mut labels = state.keys()
+        .filter_map(|k| k.strip_prefix(prefix))
+        .collect::<Vec<_>>();
+    labels.sort();
+
+    let mut slots = KeySlots::default();
+    for label in labels {
+        slots.insert(label, WrappedKey::from_hex(&state[&format!("{prefix}{label}")])?)?;
+    }
+    Ok(slots)
+}
+
+fn read_prefixed_recipients(state: &HashMap<String, String>, prefix: &str) -> Result<Recipients> {
+    let mut keys = state.keys()
+        .filter_map(|k| k.strip_prefix(prefix))
+        .collect::<Vec<_>>();
+    keys.sort();
+
+    let mut recipients = Recipients::default();
+    for key in keys {
+        recipients.insert(key.parse()?, RecipientKey::from_hex(&state[&format!("{prefix}{key}")])?);
+    }
+    Ok(recipients)
+}
+
+/// Reads the shared state file, creating an empty one if there is none and
+/// `persist` is set. Files from before the device state was split off give
+/// that state back as well, the caller writes them back once it has been moved.
+fn read_state(root: impl AsRef<Path>, persist: bool) -> Result<(StateDocument, Option<LegacyDeviceState>)> {
     let path = root.as_ref().join(".nov").join(".state");
 
     if !path.exists() {
-        if let Some(parent) = path.parent()  && !parent.exists() {
-           
-                std::fs::create_dir_all(parent)?;
-            
+        let state = StateDocument {
+            version: STATE_VERSION,
+            ..Default::default()
+        };
+        if persist {
+            write_state(root, &state)?;
         }
-        // Write an empty key file.
-        std::fs::write(path, [])?;
-        return Ok(HashMap::default());
+        return Ok((state, None));
     }
 
+    let src_str = std::fs::read_to_string(&path)?;
+    let Some(version) = state_version(&src_str)? else {
+        let (state, device) = migrate_legacy_state(&string_to_hashmap(&src_str))?;
+        return Ok((state, Some(device)));
+    };
+    if version > STATE_VERSION {
+        return Err(anyhow!(
+            "The state file is version {version}, but this version of novovault only understands up to version {STATE_VERSION}. Please upgrade novovault."
+        ));
+    }
 
-    let src_str = std::fs::read_to_string(path)?;
+    let mut state: StateDocument = toml::from_str(&src_str).map_err(|e| anyhow!("The state file is corrupted: {e}"))?;
+    if version == STATE_VERSION {
+        return Ok((state, None));
+    }
+    let device = toml::from_str(&src_str).map_err(|e| anyhow!("The state file is corrupted: {e}"))?;
+    state.version = STATE_VERSION;
+    Ok((state, Some(device)))
+}
 
+/// The version of a state file, or none for the old `key=value` form. Anything
+/// that is neither is refused, so a damaged file is never migrated over.
+fn state_version(src_str: &str) -> Result<Option<u32>> {
+    // The old form started out as an empty file, and checkouts still carry one.
+    if src_str.trim().is_empty() {
+        return Ok(None);
+    }
+    let table = match toml::from_str::<toml::Table>(src_str) {
+        Ok(table) => table,
+        Err(_) if is_legacy_state(src_str) => return Ok(None),
+        Err(e) => return Err(anyhow!("The state file is corrupted and was left as it is: {e}")),
+    };
+    let Some(version) = table.get("version") else {
+        if is_legacy_state(src_str) {
+            return Ok(None);
+        }
+        return Err(anyhow!("The state file is corrupted and was left as it is, it does not say which version it is."));
+    };
+    let version = version
+        .as_integer()
+        .and_then(|v| u32::try_from(v).ok())
+        .ok_or_else(|| anyhow!("The version of the state file is not a number."))?;
+    Ok(Some(version))
+}
 
-    let file = src_str
-        .split("\n")
-        .map(|f| f.trim())
-        .filter(|f| !f.is_empty())
-        .map(|f| f.split("="))
-        .flat_map(|mut f| Some((f.next()?.to_string(), f.next()?.to_string())))
-        .collect();
+/// Whether every line is a `key=value` entry that the old form knew about.
+fn is_legacy_state(src_str: &str) -> bool {
+    let mut lines = src_str.lines().map(str::trim).filter(|l| !l.is_empty()).peekable();
+    lines.peek().is_some()
+        && lines.all(|line| line.split_once('=').is_some_and(|(key, _)| is_legacy_key(key)))
+}
+
+fn is_legacy_key(key: &str) -> bool {
+    let prefixes = [SLOT_PREFIX, PENDING_SLOT_PREFIX, RECIPIENT_PREFIX, PENDING_RECIPIENT_PREFIX, SIGNER_PREFIX];
+    prefixes.iter().any(|prefix| key.strip_prefix(prefix).is_some_and(|rest| !rest.is_empty()))
+        || matches!(key, "state" | "init" | "remote" | "remote_backend" | "prev_stamp_t3" | "kdf" | "wrapped")
+}
+
+/// Reads the state of this device, creating it if there is none and `persist`
+/// is set.
+///
+/// It starts out from whatever the shared file used to hold. A vault that
+/// arrived with its metadata but no device state was cloned or pulled, so it
+/// is sealed and was initialized elsewhere.
+fn read_local_state(root: impl AsRef<Path>, state: &StateDocument, legacy: Option<&LegacyDeviceState>, persist: bool) -> Result<LocalDocument> {
+    let path = root.as_ref().join(".nov").join(".local-state");
+
+    if path.exists() {
+        let src_str = std::fs::read_to_string(&path)?;
+        let local: LocalDocument = toml::from_str(&src_str).map_err(|e| anyhow!("The local state file is corrupted: {e}"))?;
+        if local.version > LOCAL_STATE_VERSION {
+            return Err(anyhow!(
+                "The local state file is version {}, but this version of novovault only understands up to version {LOCAL_STATE_VERSION}. Please upgrade novovault.",
+                local.version
+            ));
+        }
+        return Ok(local);
+    }
+
+    let mut local = LocalDocument {
+        version: LOCAL_STATE_VERSION,
+        ..Default::default()
+    };
+    match legacy {
+        Some(legacy) => {
+            local.state = legacy.state;
+            local.init = legacy.init;
+            local.prev_stamp_t3 = legacy.prev_stamp_t3.clone();
+        }
+        None if !state.slots.is_empty() => {
+            local.state = Some(VaultState::Sealed);
+            local.init = Some(false);
+        }
+        None => {}
+    }
 
+    // The generation used to be remembered in a file of its own.
+    let generation = root.as_ref().join(".nov").join(".generation");
+    if generation.exists() {
+        local.seen_generation = std::fs::read_to_string(&generation)?
+            .trim()
+            .parse()
+            .map_err(|_| anyhow!("The generation record at {generation:?} is corrupted."))?;
+    }
 
-    Ok(file)
+    if persist {
+        write_local_state(&root, &local)?;
+        if generation.exists() {
+            std::fs::remove_file(generation)?;
+        }
+    }
+    Ok(local)
 }
 
+/// Brings a state file of the old `key=value` form over to the current
+/// version, along with the device state that it held.
+fn migrate_legacy_state(legacy: &HashMap<String, String>) -> Result<(StateDocument, LegacyDeviceState)> {
+    let mut state = StateDocument {
+        version: STATE_VERSION,
+        ..Default::default()
+    };
+    let mut device = LegacyDeviceState::default();
+    let mut keys = legacy.keys().collect::<Vec<_>>();
+    keys.sort();
+    for key in keys {
+        let value = &legacy[key];
+        if let Some(label) = key.strip_prefix(SLOT_PREFIX) {
+            state.slots.push(SlotEntry { label: label.to_string(), key: value.clone() });
+        } else if let Some(label) = key.strip_prefix(PENDING_SLOT_PREFIX) {
+            state.pending_slots.push(SlotEntry { label: label.to_string(), key: value.clone() });
+        } else if let Some(public_key) = key.strip_prefix(RECIPIENT_PREFIX) {
+            state.recipients.push(RecipientEntry { public_key: public_key.to_string(), key: value.clone() });
+        } else if let Some(public_key) = key.strip_prefix(PENDING_RECIPIENT_PREFIX) {
+            state.pending_recipients.push(RecipientEntry { public_key: public_key.to_string(), key: value.clone() });
+        } else if let Some(label) = key.strip_prefix(SIGNER_PREFIX) {
+            state.signers.push(SignerEntry { label: label.to_string(), public_key: value.clone() });
+        } else {
+            match key.as_str() {
+                "state" => device.state = Some(VaultState::from_str(value)?),
+                "init" => device.init = Some(bool::from_str(value)?),
+                "remote" => state.remote = Some(value.clone()),
+                "remote_backend" => state.remote_backend = Some(SyncMethod::from_str(value)?),
+                "prev_stamp_t3" => device.prev_stamp_t3 = Some(value.clone()),
+                "kdf" => state.kdf = Some(value.parse()?),
+                // Vaults from before key slots existed had a single wrapped key.
+                "wrapped" => {}
+                _ => {
+                    console_log!(Warn, "Dropping the unknown entry '{key}' from the state file.");
+                }
+            }
+        }
+    }
+
+    if state.slots.is_empty() && let Some(wrapped) = legacy.get("wrapped") {
+        state.slots.push(SlotEntry { label: DEFAULT_SLOT.to_string(), key: wrapped.clone() });
+    }
+    Ok((state, device))
+}
+
+/// Splits `key=value` lines, everything after the first `=` is the value.
 pub fn string_to_hashmap(src_str: &str) -> HashMap<String, String> {
     src_str
         .split("\n")
         .map(|f| f.trim())
         .filter(|f| !f.is_empty())
-        .map(|f| f.split("="))
-        .flat_map(|mut f| Some((f.next()?.to_string(), f.next()?.to_string())))
+        .flat_map(|f| f.split_once("="))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
         .collect()
-
-    
 }
 
 
-fn write_meta_status(root: impl AsRef<Path>, bytes: &HashMap<String, String>) -> Result<()> {
+fn write_state(root: impl AsRef<Path>, state: &StateDocument) -> Result<()> {
+    write_document(root, ".state", &toml::to_string(state)?)
+}
 
+fn write_local_state(root: impl AsRef<Path>, local: &LocalDocument) -> Result<()> {
+    write_document(root, ".local-state", &toml::to_string(local)?)
+}
 
-    // println!("WRITING....");
+fn write_document(root: impl AsRef<Path>, name: &str, data: &str) -> Result<()> {
     let root = root.as_ref().join(".nov");
 
-    let data = bytes.iter().map(|(key, value)| format!("{key}={value}"))
-        .collect::<Vec<_>>()
-        .join("\n");
-
     if !root.exists() {
         std::fs::create_dir_all(&root)?;
     }
 
-    let temp = root.join(".state.temp");
-    // println!("TEMP: {:?}", temp);
+    let temp = root.join(format!("{name}.temp"));
     std::fs::write(&temp, data.as_bytes())?;
 
-    // println!("WROTE 2");
-
-    atomicwrites::replace_atomic(temp.as_ref(), &root.join(".state"))?;
+    atomicwrites::replace_atomic(temp.as_ref(), &root.join(name))?;
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::sys::{
+        compression::CompressionPolicy,
+        header::CompressionId,
+        mk::{CachedPassword, KdfParams, MasterVaultKey, UserVaultKey, WrappedKey},
+        procedure::actions::VaultState,
+        statefile::{STATE_VERSION, StateFileHandle, SyncMethod, state_version},
+    };
+
+    #[test]
+    pub fn check_state_migration() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("state");
+        std::fs::create_dir_all(dir.join(".nov")).unwrap();
+
+        let params = KdfParams::new(8 * 1024, 1, 1).unwrap();
+        let user = UserVaultKey::init_fresh(&mut CachedPassword::from_string("pw".to_string()), params).unwrap();
+        let wrapped = WrappedKey::init(&user, &MasterVaultKey::generate()).unwrap().to_hex();
+
+        // Values containing '=' used to be cut short.
+        let legacy = format!(
+            "state=Sealed\ninit=true\nremote=https://example.com/vault?token=abc==\nremote_backend=TigrisS3\nkdf=8192,1,1\nwrapped={wrapped}\n"
+        );
+        std::fs::write(dir.join(".nov").join(".state"), legacy).unwrap();
+
+        let mut handle = StateFileHandle::new(&dir).unwrap();
+        assert!(handle.get_state().unwrap() == VaultState::Sealed);
+        assert!(handle.get_init().unwrap());
+        assert_eq!(handle.get_remote().unwrap(), "https://example.com/vault?token=abc==");
+        assert!(handle.get_remote_storage().unwrap() == Some(SyncMethod::TigrisS3));
+        assert_eq!(handle.get_kdf_params().unwrap(), params);
+        assert_eq!(handle.get_key_slots().unwrap().iter().next().unwrap().label, "default");
+
+        // The file was written back in the new form, with the state of
+        // this device split off into a file that is never synced.
+        let migrated = std::fs::read_to_string(dir.join(".nov").join(".state")).unwrap();
+        assert!(migrated.contains(&format!("version = {STATE_VERSION}")));
+        assert!(!migrated.contains("Sealed"));
+        let local = std::fs::read_to_string(handle.local_path()).unwrap();
+        assert!(local.contains("state = \"Sealed\""));
+        let mut handle = StateFileHandle::new(&dir).unwrap();
+        assert_eq!(handle.get_remote().unwrap(), "https://example.com/vault?token=abc==");
+
+        // The level of the last seal survives for a rotation to reuse.
+        assert!(handle.get_compression().unwrap().default_level().is_none());
+        handle.set_compression(&CompressionPolicy::new(CompressionId::Zstd, Some(19), HashMap::new()).unwrap());
+        handle.writeback().unwrap();
+        let policy = StateFileHandle::new(&dir).unwrap().get_compression().unwrap();
+        assert_eq!(policy.default_codec(), CompressionId::Zstd);
+        assert_eq!(policy.default_level(), Some(19));
+
+        // A newer version is refused instead of being misread.
+        std::fs::write(dir.join(".nov").join(".state"), format!("version = {}\n", STATE_VERSION + 1)).unwrap();
+        assert!(StateFileHandle::new(&dir).is_err());
+
+        // Anything that is neither form is refused and left alone, rather
+        // than being migrated over as an empty legacy file.
+        for damaged in [
+            format!("<<<<<<< HEAD\n{migrated}=======\n{migrated}>>>>>>> origin/main\n"),
+            migrated.replace("version", "verison"),
+            "state=Sealed\nunknown=1\n".to_string(),
+        ] {
+            std::fs::write(dir.join(".nov").join(".state"), &damaged).unwrap();
+            assert!(StateFileHandle::new(&dir).is_err());
+            assert_eq!(std::fs::read_to_string(dir.join(".nov").join(".state")).unwrap(), damaged);
+        }
+
+        // An empty file is what the old form created, so it is migrated.
+        for empty in ["", " \n"] {
+            std::fs::write(dir.join(".nov").join(".state"), empty).unwrap();
+            StateFileHandle::new(&dir).unwrap();
+            let src_str = std::fs::read_to_string(dir.join(".nov").join(".state")).unwrap();
+            assert_eq!(state_version(&src_str).unwrap(), Some(STATE_VERSION));
+        }
+    }
+}
@@ -0,0 +1,387 @@
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use anyhow::{Result, anyhow};
+use chacha20poly1305::{KeyInit, Key, XChaCha20Poly1305, XNonce, aead::AeadInPlace};
+use serde::{Deserialize, Serialize};
+
+/// The size of a plaintext chunk.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The size of the Poly1305 tag appended to every chunk.
+pub const TAG_SIZE: usize = 16;
+
+/// The random part of the nonce, the remaining five bytes
+/// are the chunk counter and the final chunk flag.
+pub const NONCE_PREFIX_SIZE: usize = 19;
+
+const SEALED_CHUNK_SIZE: u64 = (CHUNK_SIZE + TAG_SIZE) as u64;
+
+/// The padded plaintext ends with the length of the real contents.
+const TRAILER_SIZE: u64 = 8;
+
+/// How far the plaintext is padded before it is encrypted, so that the
+/// size of the ciphertext gives away less about the size of the contents.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Padding {
+    #[default]
+    None,
+    /// Pads up to the next power of two, at most doubling the size.
+    PowerOfTwo,
+    /// Padmé, which leaks O(log log n) bits of the size at a
+    /// cost of at most 12% of it.
+    Padme,
+    /// Pads up to at least this size, this keeps the size of a
+    /// binary when it is rewritten.
+    #[serde(skip)]
+    AtLeast(u64),
+}
+
+impl Padding {
+    /// The size that a plaintext of this length is padded up to.
+    pub fn padded_len(self, len: u64) -> u64 {
+        match self {
+            Self::None => len,
+            Self::PowerOfTwo => len.checked_next_power_of_two().unwrap_or(len),
+            Self::Padme => {
+                if len < 2 {
+                    return len;
+                }
+                let exponent = len.ilog2();
+                let bits = exponent.ilog2() + 1;
+                let mask = (1u64 << (exponent - bits)) - 1;
+                len.checked_add(mask).map_or(len, |l| l & !mask)
+            }
+            Self::AtLeast(size) => len.max(size),
+        }
+    }
+}
+
+/// Builds the nonce for a chunk, this follows the STREAM
+/// construction (prefix || counter (BE32) || last flag).
+fn chunk_nonce(prefix: &[u8; NONCE_PREFIX_SIZE], counter: u32, last: bool) -> XNonce {
+    let mut nonce = [0u8; 24];
+    nonce[..NONCE_PREFIX_SIZE].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_SIZE..NONCE_PREFIX_SIZE + 4].copy_from_slice(&counter.to_be_bytes());
+    nonce[23] = last as u8;
+    XNonce::clone_from_slice(&nonce)
+}
+
+/// Encrypts everything written to it in fixed-size chunks, so that
+/// only a single chunk is ever held in memory.
+///
+/// The associated data is bound into every chunk.
+pub struct StreamEncryptor<W: Write> {
+    inner: W,
+    cipher: XChaCha20Poly1305,
+    prefix: [u8; NONCE_PREFIX_SIZE],
+    associated: Vec<u8>,
+    counter: u32,
+    buffer: Vec<u8>,
+    written: u64,
+    padding: Option<Padding>,
+}
+
+impl<W: Write> StreamEncryptor<W> {
+    pub fn new(inner: W, key: &[u8; 32], prefix: [u8; NONCE_PREFIX_SIZE], associated: &[u8]) -> Self {
+        Self {
+            inner,
+            cipher: XChaCha20Poly1305::new(Key::from_slice(key)),
+            prefix,
+            associated: associated.to_vec(),
+            counter: 0,
+            buffer: Vec::with_capacity(CHUNK_SIZE + TAG_SIZE),
+            written: 0,
+            padding: None,
+        }
+    }
+    /// Pads the plaintext when the stream is finished, and ends it with the
+    /// length of the contents so that the padding can be stripped again.
+    pub fn with_padding(mut self, padding: Padding) -> Self {
+        self.padding = Some(padding);
+        self
+    }
+    fn seal_chunk(&mut self, last: bool) -> Result<()> {
+        let nonce = chunk_nonce(&self.prefix, self.counter, last);
+        self.cipher
+            .encrypt_in_place(&nonce, &self.associated, &mut self.buffer)
+            .map_err(|_| anyhow!("Failed to encrypt chunk {} of the vault.", self.counter))?;
+        self.inner.write_all(&self.buffer)?;
+        self.buffer.clear();
+        self.counter = self
+            .counter
+            .checked_add(1)
+            .ok_or_else(|| anyhow!("The vault is too large to be encrypted."))?;
+        Ok(())
+    }
+    /// Seals the final chunk and hands back the inner writer.
+    pub fn finish(mut self) -> Result<W> {
+        if let Some(padding) = self.padding.take() {
+            let contents = self.written;
+            let padded = padding.padded_len(contents + TRAILER_SIZE);
+            io::copy(&mut io::repeat(0).take(padded - contents - TRAILER_SIZE), &mut self)?;
+            self.write_all(&contents.to_le_bytes())?;
+        }
+        self.seal_chunk(true)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for StreamEncryptor<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        // We only seal a full chunk once we know there is more data
+        // after it, otherwise it has to be the final chunk.
+        if self.buffer.len() == CHUNK_SIZE {
+            self.seal_chunk(false).map_err(io::Error::other)?;
+        }
+        let take = buf.len().min(CHUNK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..take]);
+        self.written += take as u64;
+        Ok(take)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Decrypts a chunked stream with random access, only one chunk
+/// is decrypted and held at a time.
+pub struct StreamDecryptor<R: Read + Seek> {
+    inner: R,
+    cipher: XChaCha20Poly1305,
+    prefix: [u8; NONCE_PREFIX_SIZE],
+    associated: Vec<u8>,
+    data_start: u64,
+    chunks: u64,
+    sealed_len: u64,
+    padded_len: u64,
+    plain_len: u64,
+    position: u64,
+    current: Option<u64>,
+    buffer: Vec<u8>,
+}
+
+impl<R: Read + Seek> StreamDecryptor<R> {
+    /// Creates a new decryptor where the ciphertext begins at `data_start`
+    /// and runs until the end of the reader, every chunk must have been
+    /// sealed with the same associated data.
+    ///
+    /// The final chunk is authenticated immediately so that a truncated
+    /// vault is caught before anything is read from it.
+    pub fn new(
+        mut inner: R,
+        key: &[u8; 32],
+        prefix: [u8; NONCE_PREFIX_SIZE],
+        associated: &[u8],
+        data_start: u64,
+    ) -> Result<Self> {
+        let end = inner.seek(SeekFrom::End(0))?;
+        let sealed_len = end
+            .checked_sub(data_start)
+            .ok_or_else(|| anyhow!("The vault binary is shorter than its header."))?;
+
+        let chunks = sealed_len.div_ceil(SEALED_CHUNK_SIZE);
+        if chunks == 0 || sealed_len - (chunks - 1) * SEALED_CHUNK_SIZE < TAG_SIZE as u64 {
+            return Err(anyhow!("The vault binary is truncated."));
+        }
+        if chunks > u32::MAX as u64 + 1 {
+            return Err(anyhow!("The vault binary has too many chunks."));
+        }
+
+        let mut decryptor = Self {
+            inner,
+            cipher: XChaCha20Poly1305::new(Key::from_slice(key)),
+            prefix,
+            associated: associated.to_vec(),
+            data_start,
+            chunks,
+            sealed_len,
+            padded_len: sealed_len - chunks * TAG_SIZE as u64,
+            plain_len: sealed_len - chunks * TAG_SIZE as u64,
+            position: 0,
+            current: None,
+            buffer: Vec::with_capacity(CHUNK_SIZE + TAG_SIZE),
+        };
+        decryptor.load_chunk(chunks - 1)?;
+        Ok(decryptor)
+    }
+    fn load_chunk(&mut self, index: u64) -> Result<()> {
+        if self.current == Some(index) {
+            return Ok(());
+        }
+        let offset = index * SEALED_CHUNK_SIZE;
+        let length = (self.sealed_len - offset).min(SEALED_CHUNK_SIZE) as usize;
+
+        self.current = None;
+        self.buffer.resize(length, 0);
+        self.inner.seek(SeekFrom::Start(self.data_start + offset))?;
+        self.inner.read_exact(&mut self.buffer)?;
+
+        let nonce = chunk_nonce(&self.prefix, index as u32, index == self.chunks - 1);
+        self.cipher
+            .decrypt_in_place(&nonce, &self.associated, &mut self.buffer)
+            .map_err(|_| {
+                anyhow!("The vault failed its integrity check at chunk {index}, either the header or the contents were tampered with, or the wrong key was used.")
+            })?;
+        self.current = Some(index);
+        Ok(())
+    }
+    /// The size of the plaintext including any padding.
+    pub fn padded_len(&self) -> u64 {
+        self.padded_len
+    }
+    /// Hides the padding at the end of the plaintext, the length of
+    /// the contents is read from the trailer.
+    pub fn strip_padding(&mut self) -> Result<()> {
+        let mut trailer = [0u8; TRAILER_SIZE as usize];
+        let padded = self
+            .plain_len
+            .checked_sub(TRAILER_SIZE)
+            .ok_or_else(|| anyhow!("The vault binary is too short to contain its length."))?;
+        self.seek(SeekFrom::Start(padded))?;
+        self.read_exact(&mut trailer)?;
+
+        let contents = u64::from_le_bytes(trailer);
+        if contents > padded {
+            return Err(anyhow!("The length of the vault contents is corrupted."));
+        }
+        self.plain_len = contents;
+        self.position = 0;
+        Ok(())
+    }
+    /// Authenticates every chunk in the stream without keeping any of it.
+    pub fn verify(&mut self) -> Result<()> {
+        for index in 0..self.chunks {
+            self.load_chunk(index)?;
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> Read for StreamDecryptor<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.position >= self.plain_len {
+            return Ok(0);
+        }
+        let index = self.position / CHUNK_SIZE as u64;
+        let offset = (self.position % CHUNK_SIZE as u64) as usize;
+        self.load_chunk(index)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let take = buf
+            .len()
+            .min(self.buffer.len() - offset)
+            .min((self.plain_len - self.position) as usize);
+        buf[..take].copy_from_slice(&self.buffer[offset..offset + take]);
+        self.position += take as u64;
+        Ok(take)
+    }
+}
+
+impl<R: Read + Seek> Seek for StreamDecryptor<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::End(d) => self.plain_len.checked_add_signed(d),
+            SeekFrom::Current(d) => self.position.checked_add_signed(d),
+        };
+        match target {
+            Some(p) => {
+                self.position = p;
+                Ok(p)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid seek to a negative or overflowing position.",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+
+    use crate::sys::stream::{CHUNK_SIZE, Padding, StreamDecryptor, StreamEncryptor};
+
+    fn seal(data: &[u8], key: &[u8; 32]) -> Vec<u8> {
+        let mut enc = StreamEncryptor::new(vec![], key, [7u8; 19], b"header");
+        enc.write_all(data).unwrap();
+        enc.finish().unwrap()
+    }
+
+    #[test]
+    pub fn check_roundtrip_across_chunks() {
+        let key = [3u8; 32];
+        for size in [0, 1, CHUNK_SIZE - 1, CHUNK_SIZE, CHUNK_SIZE + 1, 3 * CHUNK_SIZE + 17] {
+            let data = (0..size).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+            let mut dec = StreamDecryptor::new(Cursor::new(seal(&data, &key)), &key, [7u8; 19], b"header", 0).unwrap();
+            dec.verify().unwrap();
+            assert_eq!(dec.seek(SeekFrom::End(0)).unwrap(), size as u64);
+            dec.seek(SeekFrom::Start(0)).unwrap();
+
+            let mut out = vec![];
+            dec.read_to_end(&mut out).unwrap();
+            assert_eq!(out, data);
+
+            if size > 10 {
+                dec.seek(SeekFrom::Start(size as u64 - 10)).unwrap();
+                let mut tail = vec![];
+                dec.read_to_end(&mut tail).unwrap();
+                assert_eq!(tail, data[size - 10..]);
+            }
+        }
+    }
+
+    #[test]
+    pub fn check_padding() {
+        assert_eq!(Padding::PowerOfTwo.padded_len(1000), 1024);
+        assert_eq!(Padding::Padme.padded_len(1100), 1152);
+        assert_eq!(Padding::Padme.padded_len(1_000_000), 1_015_808);
+        assert_eq!(Padding::AtLeast(4096).padded_len(1000), 4096);
+
+        let key = [5u8; 32];
+        for padding in [Padding::None, Padding::PowerOfTwo, Padding::Padme] {
+            for size in [0, 5, CHUNK_SIZE + 3, 3 * CHUNK_SIZE] {
+                let data = vec![4u8; size];
+                let mut enc = StreamEncryptor::new(vec![], &key, [7u8; 19], b"header").with_padding(padding);
+                enc.write_all(&data).unwrap();
+                let sealed = enc.finish().unwrap();
+
+                let mut dec = StreamDecryptor::new(Cursor::new(sealed), &key, [7u8; 19], b"header", 0).unwrap();
+                assert_eq!(dec.seek(SeekFrom::End(0)).unwrap(), padding.padded_len(size as u64 + 8));
+                dec.strip_padding().unwrap();
+                assert_eq!(dec.seek(SeekFrom::End(0)).unwrap(), size as u64);
+                dec.seek(SeekFrom::Start(0)).unwrap();
+
+                let mut out = vec![];
+                dec.read_to_end(&mut out).unwrap();
+                assert_eq!(out, data);
+            }
+        }
+    }
+
+    #[test]
+    pub fn check_tamper_and_truncation() {
+        let key = [9u8; 32];
+        let sealed = seal(&vec![1u8; 2 * CHUNK_SIZE + 5], &key);
+
+        let mut flipped = sealed.clone();
+        flipped[10] ^= 1;
+        let mut dec = StreamDecryptor::new(Cursor::new(flipped), &key, [7u8; 19], b"header", 0).unwrap();
+        assert!(dec.verify().is_err());
+
+        // Dropping the final chunk must not look like a valid, shorter vault.
+        let truncated = sealed[..2 * (CHUNK_SIZE + 16)].to_vec();
+        assert!(StreamDecryptor::new(Cursor::new(truncated), &key, [7u8; 19], b"header", 0).is_err());
+
+        assert!(StreamDecryptor::new(Cursor::new(sealed.clone()), &[0u8; 32], [7u8; 19], b"header", 0).is_err());
+
+        // A different header must not authenticate.
+        assert!(StreamDecryptor::new(Cursor::new(sealed), &key, [7u8; 19], b"headex", 0).is_err());
+    }
+}
@@ -1,117 +1,348 @@
-use std::{fs::File, io::Cursor, path::{Path, PathBuf}};
+use std::{fs::File, io::{BufWriter, Cursor, Read, Seek, SeekFrom}, path::Path};
 
 use aes_gcm::{KeyInit, aead::AeadMutInPlace};
 use anyhow::{Result, anyhow};
 use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
-use zip::{ZipWriter, write::FileOptions};
+use zip::{ZipWriter, write::{FullFileOptions, StreamWriter}};
 use std::io::Write;
 
+use crate::sys::{compression::CompressionPolicy, metadata::{FileMetadata, METADATA_FIELD}, header::{CompressionId, FormatVersion, HEADER_SIZE, VaultHeader}, stream::{NONCE_PREFIX_SIZE, Padding, StreamDecryptor, StreamEncryptor}};
 
-pub struct VaultWriter {
-    // encryptor: EncryptorBE32<XChaCha20Poly1305>,
-    file: Option<ZipWriter<Cursor<Vec<u8>>>>,
-    options: FileOptions<'static, ()>,
-    path: PathBuf,
-    key: [u8; 32]
-}
-
-impl VaultWriter {
-    pub fn new(target: impl AsRef<Path>, key: &[u8; 32])  -> Result<Self> {
+/// The header of the original format, where the whole archive was
+/// encrypted in one go.
+const LEGACY_HEADER_SIZE: usize = HEADER_SIZE + 24;
 
-        let path = target.as_ref();
-        let file = Cursor::new(vec![]);
+/// The key block is only ever a handful of wrapped keys, anything
+/// larger than this is a corrupted length.
+const MAX_KEY_BLOCK_SIZE: usize = 1024 * 1024;
 
+type VaultZip = ZipWriter<StreamWriter<StreamEncryptor<BufWriter<File>>>>;
 
+pub struct VaultWriter {
+    file: Option<VaultZip>,
+    compression: CompressionPolicy,
+}
 
-        let enc_options: FileOptions<'_, ()> = FileOptions::default()
-        .compression_method(zip::CompressionMethod::Deflated)
-        .unix_permissions(0o755);
-
-
-        let file = ZipWriter::new(file);
-
+impl VaultWriter {
+    /// Creates a new vault binary, the key block is stored in the clear
+    /// next to the header so the binary can be unlocked on its own. Each entry
+    /// records its own codec, the header only notes the default one.
+    pub fn new(target: impl AsRef<Path>, key: &[u8; 32], key_block: &str, generation: u64, compression: CompressionPolicy, padding: Padding)  -> Result<Self> {
 
+        // The archive is written straight into the encryptor, so it can never seek
+        // back, the entries will use data descriptors instead.
+        let file = ZipWriter::new_stream(create_stream(target.as_ref(), key, key_block, generation, compression.default_codec(), padding)?);
 
         Ok(Self {
-            options: enc_options,
+            compression,
             file: Some(file),
-            key: *key,
-            path: path.to_path_buf()
         })
     }
-    pub fn write_path(&mut self, path: &Path, name: &Path) -> Result<()> {
+    pub fn write_path(&mut self, path: &Path, name: &Path, metadata: &FileMetadata) -> Result<()> {
         if let Some(file) = &mut self.file {
-            if path.is_file() {
-            file.start_file(name.to_string_lossy(), self.options)?;
-            file.write_all(&std::fs::read(path)?)?;
+            if let Some(target) = &metadata.symlink {
+            file.add_symlink(name.to_string_lossy(), target, entry_options(&self.compression, CompressionId::Stored, metadata)?)?;
+        } else if path.is_file() {
+            let options = entry_options(&self.compression, self.compression.choose(path)?, metadata)?;
+            file.start_file(name.to_string_lossy(), options)?;
+            std::io::copy(&mut File::open(path)?, file)?;
         } else {
-            file.add_directory(name.to_string_lossy(), self.options)?;
+            file.add_directory(name.to_string_lossy(), entry_options(&self.compression, CompressionId::Stored, metadata)?)?;
         }
         } else {
             return Err(anyhow!("Failed to actually get the file innards."));
         }
-        
+
 
 
         Ok(())
     }
     pub fn finish(&mut self) -> Result<()> {
 
-        let file = self.file.take().unwrap();
+        let file = self.file.take()
+            .ok_or_else(|| anyhow!("The vault writer was already finished."))?;
+
+        let encryptor = file.finish()?.into_inner();
 
-        let mut inner = file.finish()?.into_inner();
+        encryptor.finish()?.flush()?;
 
-        let key = Key::from_slice(&self.key);
-        let mut cipher = XChaCha20Poly1305::new(key);
+        Ok(())
+    }
+}
 
+/// The options of an entry, with its metadata in an extra field.
+fn entry_options(compression: &CompressionPolicy, codec: CompressionId, metadata: &FileMetadata) -> Result<FullFileOptions<'static>> {
+    let mut options = compression.zip_options(codec);
+    if let Some(mode) = metadata.mode {
+        options = options.unix_permissions(mode);
+    }
+    options.add_extra_data(METADATA_FIELD, metadata.to_extra_field()?, false)?;
+    Ok(options)
+}
 
-        let mut nbytes = [0u8; 24];
-        rand::fill(&mut nbytes);
+/// Creates a new vault binary in the current format, returning
+/// the encryptor that the contents should be written to.
+fn create_stream(target: &Path, key: &[u8; 32], key_block: &str, generation: u64, compression: CompressionId, padding: Padding) -> Result<StreamEncryptor<BufWriter<File>>> {
+    let mut file = BufWriter::new(File::create(target)?);
+
+    let mut prefix = [0u8; NONCE_PREFIX_SIZE];
+    rand::fill(&mut prefix);
+
+    let header = VaultHeader::current().with_compression(compression).to_bytes();
+    file.write_all(&header)?;
+    write_key_block(&mut file, key_block)?;
+    file.write_all(&generation.to_le_bytes())?;
+    file.write_all(&prefix)?;
+
+    // The header, the generation and the nonce prefix are authenticated, the
+    // key block is left out so that it can be replaced without re-encrypting,
+    // every wrapped key in it is authenticated on its own. That leaves the
+    // wrapped keys readable from every pushed binary, so a weak password can
+    // be guessed offline, and a tampered block can only swap in keys that
+    // fail to unwrap or unwrap to a master key the contents do not open under.
+    let mut associated = header.to_vec();
+    associated.extend_from_slice(&generation.to_le_bytes());
+    associated.extend_from_slice(&prefix);
+    Ok(StreamEncryptor::new(file, key, prefix, &associated).with_padding(padding))
+}
 
+/// Encrypts everything from the reader into a new vault binary and
+/// syncs it to the disk, the contents are already compressed with the codec.
+pub fn write_stream(target: impl AsRef<Path>, key: &[u8; 32], key_block: &str, generation: u64, compression: CompressionId, padding: Padding, contents: &mut impl Read) -> Result<()> {
+    let mut stream = create_stream(target.as_ref(), key, key_block, generation, compression, padding)?;
+    std::io::copy(contents, &mut stream)?;
+    stream.finish()?.into_inner()?.sync_all()?;
+    Ok(())
+}
 
-        // Reserve some space.
-        inner.reserve(16);
+fn write_key_block(file: &mut impl Write, key_block: &str) -> Result<()> {
+    if key_block.len() > MAX_KEY_BLOCK_SIZE {
+        return Err(anyhow!("There are too many key slots to embed in the vault binary."));
+    }
+    file.write_all(&(key_block.len() as u32).to_le_bytes())?;
+    file.write_all(key_block.as_bytes())?;
+    Ok(())
+}
 
-        let nonce = XNonce::from_slice(&nbytes);
+/// Reads the key block that follows the header, the file is left
+/// positioned at the nonce prefix.
+fn read_key_block(file: &mut File) -> Result<String> {
+    let mut length = [0u8; 4];
+    file.read_exact(&mut length)
+        .map_err(|_| anyhow!("The vault binary is too short to contain its key block."))?;
+    let length = u32::from_le_bytes(length) as usize;
+    if length > MAX_KEY_BLOCK_SIZE {
+        return Err(anyhow!("The key block of the vault binary is corrupted."));
+    }
 
-        cipher.encrypt_in_place(nonce, &[], &mut inner)
-            .map_err(|_| anyhow!("Failed to encrypt the vault contents in place."))?;
+    let mut block = vec![0u8; length];
+    file.read_exact(&mut block)
+        .map_err(|_| anyhow!("The vault binary is too short to contain its key block."))?;
+    String::from_utf8(block).map_err(|_| anyhow!("The key block of the vault binary is corrupted."))
+}
 
-        let mut file = File::create(&self.path)?;
-        file.write_all(b"NOVO")?;
-        file.write_all(&[0u8, 0, 0, 0])?;
-        file.write_all(nonce)?;
-        file.write_all(&inner)?;
+/// Reads the generation that follows the key block, the file is left
+/// positioned at the nonce prefix.
+fn read_generation_bytes(file: &mut File) -> Result<[u8; 8]> {
+    let mut generation = [0u8; 8];
+    file.read_exact(&mut generation)
+        .map_err(|_| anyhow!("The vault binary is too short to contain its generation."))?;
+    Ok(generation)
+}
 
-        
-        // std::fs::write(&self.path, &inner)?;
+/// Reads the generation of a vault binary without decrypting anything,
+/// older formats did not record one and count as the very first. The value
+/// is only trustworthy once the vault was opened and verified under its key.
+pub fn read_generation(path: impl AsRef<Path>) -> Result<u64> {
+    let mut file = File::open(path.as_ref())?;
+    let mut bytes = [0u8; HEADER_SIZE];
+    file.read_exact(&mut bytes)
+        .map_err(|_| anyhow!("The vault binary is too short to contain a header."))?;
+
+    if VaultHeader::parse(&bytes)?.version < FormatVersion::Generation {
+        return Ok(0);
+    }
+    read_key_block(&mut file)?;
+    Ok(u64::from_le_bytes(read_generation_bytes(&mut file)?))
+}
 
+/// Reads the key slots embedded in a vault binary, older formats
+/// did not embed them.
+pub fn read_embedded_keys(path: impl AsRef<Path>) -> Result<Option<String>> {
+    let mut file = File::open(path.as_ref())?;
+    let mut bytes = [0u8; HEADER_SIZE];
+    file.read_exact(&mut bytes)
+        .map_err(|_| anyhow!("The vault binary is too short to contain a header."))?;
 
-        Ok(())
+    if VaultHeader::parse(&bytes)?.version < FormatVersion::EmbeddedKeys {
+        return Ok(None);
     }
+    Ok(Some(read_key_block(&mut file)?))
 }
 
+/// Swaps the key block of a vault binary, the ciphertext is copied over
+/// as it is. Binaries in older formats are left alone.
+pub fn replace_embedded_keys(path: impl AsRef<Path>, key_block: &str) -> Result<()> {
+    let path = path.as_ref();
+    let mut file = File::open(path)?;
+    let mut header = [0u8; HEADER_SIZE];
+    file.read_exact(&mut header)
+        .map_err(|_| anyhow!("The vault binary is too short to contain a header."))?;
+
+    if VaultHeader::parse(&header)?.version < FormatVersion::EmbeddedKeys {
+        return Ok(());
+    }
+    read_key_block(&mut file)?;
 
+    let temp = path.with_extension("rewrite");
+    let mut out = BufWriter::new(File::create(&temp)?);
+    out.write_all(&header)?;
+    write_key_block(&mut out, key_block)?;
+    std::io::copy(&mut file, &mut out)?;
+    out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
 
-pub fn decrypt(
-    header: &mut [u8],
-    vault: &mut Vec<u8>,
-    key: &[u8; 32]  
-) -> Result<()> {
+    atomicwrites::replace_atomic(&temp, path)?;
+    Ok(())
+}
 
-    // let split = bytes.spl
+/// Reads the header of a vault binary without decrypting anything.
+pub fn read_header(path: impl AsRef<Path>) -> Result<VaultHeader> {
+    let mut bytes = [0u8; HEADER_SIZE];
+    File::open(path.as_ref())?
+        .read_exact(&mut bytes)
+        .map_err(|_| anyhow!("The vault binary is too short to contain a header."))?;
+    VaultHeader::parse(&bytes)
+}
+
+/// Rewrites a vault binary into the current format under a (possibly
+/// different) key.
+///
+/// The plaintext is streamed from the old binary into a temporary
+/// file next to it, which then atomically replaces the original. The
+/// new binary is padded to at least the size of the old one and keeps
+/// its generation.
+pub fn reencrypt(path: impl AsRef<Path>, old_key: &[u8; 32], new_key: &[u8; 32], key_block: &str) -> Result<()> {
+    let path = path.as_ref();
+    let temp = path.with_extension("rewrite");
+
+    let compression = read_header(path)?.compression;
+    let generation = read_generation(path)?;
+    let mut reader = VaultReader::open(path, old_key)?;
+    reader.verify()?;
+    let padding = Padding::AtLeast(reader.padded_len());
+    reader.seek(SeekFrom::Start(0))?;
+
+    let mut stream = create_stream(&temp, new_key, key_block, generation, compression, padding)?;
+    std::io::copy(&mut reader, &mut stream)?;
+    stream.finish()?.into_inner()?.sync_all()?;
+
+    atomicwrites::replace_atomic(&temp, path)?;
+    Ok(())
+}
 
+/// A decrypted view over a vault binary.
+pub enum VaultReader {
+    /// The original format is decrypted fully into memory.
+    Legacy(Cursor<Vec<u8>>),
+    /// The chunked format is decrypted as it is read.
+    Stream(StreamDecryptor<File>),
+}
 
-    if header[..4] != *b"NOVO" {
-        return Err(anyhow!("Could not find the magic header at the top of the vault binary."));
+impl VaultReader {
+    /// Opens a vault binary, dispatching on the format byte in the header.
+    pub fn open(path: impl AsRef<Path>, key: &[u8; 32]) -> Result<Self> {
+        let mut file = File::open(path.as_ref())?;
+
+        let mut bytes = [0u8; HEADER_SIZE];
+        file.read_exact(&mut bytes)
+            .map_err(|_| anyhow!("The vault binary is too short to contain a header."))?;
+
+        let version = VaultHeader::parse(&bytes)?.version;
+        match version {
+            FormatVersion::Legacy => {
+                let mut vault = vec![];
+                file.seek(SeekFrom::Start(0))?;
+                file.read_to_end(&mut vault)?;
+                if vault.len() < LEGACY_HEADER_SIZE {
+                    return Err(anyhow!("The vault binary is too short to contain a header."));
+                }
+                let mut vault_body = vault.split_off(LEGACY_HEADER_SIZE);
+                decrypt(&mut vault, &mut vault_body, key)?;
+                Ok(Self::Legacy(Cursor::new(vault_body)))
+            }
+            FormatVersion::Stream | FormatVersion::AuthenticatedStream | FormatVersion::EmbeddedKeys | FormatVersion::Padded | FormatVersion::Generation => {
+                if version >= FormatVersion::EmbeddedKeys {
+                    read_key_block(&mut file)?;
+                }
+                let generation = if version >= FormatVersion::Generation {
+                    Some(read_generation_bytes(&mut file)?)
+                } else {
+                    None
+                };
+                let data_start = file.stream_position()? + NONCE_PREFIX_SIZE as u64;
+
+                let mut prefix = [0u8; NONCE_PREFIX_SIZE];
+                file.read_exact(&mut prefix)?;
+
+                let mut associated = vec![];
+                if version >= FormatVersion::AuthenticatedStream {
+                    associated.extend_from_slice(&bytes);
+                    if let Some(generation) = generation {
+                        associated.extend_from_slice(&generation);
+                    }
+                    associated.extend_from_slice(&prefix);
+                }
+                let mut stream = StreamDecryptor::new(file, key, prefix, &associated, data_start)?;
+                if version >= FormatVersion::Padded {
+                    stream.strip_padding()?;
+                }
+                Ok(Self::Stream(stream))
+            }
+        }
+    }
+    /// The size of the plaintext including any padding.
+    pub fn padded_len(&self) -> u64 {
+        match self {
+            Self::Legacy(cursor) => cursor.get_ref().len() as u64,
+            Self::Stream(stream) => stream.padded_len(),
+        }
     }
+    /// Checks the integrity of the entire vault before anything is extracted.
+    pub fn verify(&mut self) -> Result<()> {
+        match self {
+            // This was already authenticated when it was decrypted.
+            Self::Legacy(_) => Ok(()),
+            Self::Stream(stream) => stream.verify(),
+        }
+    }
+}
 
+impl Read for VaultReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Legacy(cursor) => cursor.read(buf),
+            Self::Stream(stream) => stream.read(buf),
+        }
+    }
+}
 
-    // let nonce_slice = 
+impl Seek for VaultReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        match self {
+            Self::Legacy(cursor) => cursor.seek(pos),
+            Self::Stream(stream) => stream.seek(pos),
+        }
+    }
+}
 
-    // let nonce_slice = bytes[8..8 + 24].to_vec();
+/// Decrypts a vault of the original format in place.
+pub fn decrypt(
+    header: &mut [u8],
+    vault: &mut Vec<u8>,
+    key: &[u8; 32]
+) -> Result<()> {
 
-    let nonce = XNonce::from_slice(&header[8..]);
+    let nonce = XNonce::from_slice(&header[HEADER_SIZE..]);
 
 
     let key= Key::from_slice(key);
@@ -119,10 +350,95 @@ pub fn decrypt(
     let mut cipher = XChaCha20Poly1305::new(key);
 
     cipher.decrypt_in_place(nonce, &[], vault)
-        .unwrap();
+        .map_err(|_| anyhow!("Failed to decrypt the vault, it is either corrupted or the wrong key was used."))?;
 
 
 
 
     Ok(())
-}
\ No newline at end of file
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use aes_gcm::{KeyInit, aead::AeadMutInPlace};
+    use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+    use zip::ZipArchive;
+
+    use crate::sys::{compression::CompressionPolicy, header::VaultHeader, metadata::FileMetadata, stream::Padding, writer::{VaultReader, VaultWriter, read_embedded_keys, read_generation, read_header, reencrypt, replace_embedded_keys}};
+
+    #[test]
+    pub fn check_vault_roundtrip() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("writer");
+        std::fs::create_dir_all(dir.join("notes")).unwrap();
+        std::fs::write(dir.join("notes").join("a.md"), b"hello vault").unwrap();
+
+        let key = [5u8; 32];
+        let mut writer = VaultWriter::new(dir.join("vault.bin"), &key, "slot.default=00\n", 7, CompressionPolicy::default(), Padding::Padme).unwrap();
+        writer.write_path(&dir.join("notes"), "notes".as_ref(), &FileMetadata::default()).unwrap();
+        writer.write_path(&dir.join("notes").join("a.md"), "notes/a.md".as_ref(), &FileMetadata::default()).unwrap();
+        writer.finish().unwrap();
+
+        let mut reader = VaultReader::open(dir.join("vault.bin"), &key).unwrap();
+        reader.verify().unwrap();
+        let mut archive = ZipArchive::new(reader).unwrap();
+        let mut contents = String::new();
+        archive.by_name("notes/a.md").unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "hello vault");
+
+        assert!(VaultReader::open(dir.join("vault.bin"), &[6u8; 32]).is_err());
+
+        // The key block can be swapped without touching the contents.
+        assert_eq!(read_embedded_keys(dir.join("vault.bin")).unwrap().unwrap(), "slot.default=00\n");
+        replace_embedded_keys(dir.join("vault.bin"), "slot.other=0102\n").unwrap();
+        assert_eq!(read_embedded_keys(dir.join("vault.bin")).unwrap().unwrap(), "slot.other=0102\n");
+        let mut reader = VaultReader::open(dir.join("vault.bin"), &key).unwrap();
+        reader.verify().unwrap();
+
+        // Downgrading the header is caught by the tag.
+        let mut tampered = std::fs::read(dir.join("vault.bin")).unwrap();
+        tampered[4] = 1;
+        std::fs::write(dir.join("tampered.bin"), tampered).unwrap();
+        assert!(VaultReader::open(dir.join("tampered.bin"), &key).is_err());
+
+        // So is winding back the generation.
+        assert_eq!(read_generation(dir.join("vault.bin")).unwrap(), 7);
+        let mut tampered = std::fs::read(dir.join("vault.bin")).unwrap();
+        let offset = 8 + 4 + "slot.other=0102\n".len();
+        tampered[offset] = 6;
+        std::fs::write(dir.join("tampered.bin"), tampered).unwrap();
+        assert_eq!(read_generation(dir.join("tampered.bin")).unwrap(), 6);
+        assert!(VaultReader::open(dir.join("tampered.bin"), &key).and_then(|mut reader| reader.verify()).is_err());
+    }
+
+    #[test]
+    pub fn check_legacy_migration() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("migrate");
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // Produce a vault the way the original format did.
+        let key = [8u8; 32];
+        let nonce = [4u8; 24];
+        let mut body = b"legacy contents".to_vec();
+        XChaCha20Poly1305::new(Key::from_slice(&key))
+            .encrypt_in_place(XNonce::from_slice(&nonce), &[], &mut body)
+            .unwrap();
+        let mut legacy = b"NOVO\0\0\0\0".to_vec();
+        legacy.extend_from_slice(&nonce);
+        legacy.extend_from_slice(&body);
+        std::fs::write(dir.join("vault.bin"), legacy).unwrap();
+
+        assert!(read_header(dir.join("vault.bin")).unwrap().is_outdated());
+        assert!(read_embedded_keys(dir.join("vault.bin")).unwrap().is_none());
+        reencrypt(dir.join("vault.bin"), &key, &key, "").unwrap();
+        assert_eq!(read_header(dir.join("vault.bin")).unwrap(), VaultHeader::current());
+
+        let mut contents = vec![];
+        VaultReader::open(dir.join("vault.bin"), &key).unwrap().read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, b"legacy contents");
+    }
+}